@@ -1,9 +1,50 @@
 //! actix-web 路由映射API
-use actix_web::{http, HttpRequest, HttpResponse, web};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use actix_web::{http, HttpRequest, HttpResponse, ResponseError, web};
+use actix_web_actors::ws;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
+use crate::auth::Auth;
+use crate::batch;
+use crate::chaos::ChaosConfig;
+use crate::databases::{Databases, Db};
 use crate::db;
+use crate::error::Error;
+use crate::etag::{self, Preconditions};
+use crate::format::Format;
+use crate::graphql;
+use crate::journal::Entry;
+use crate::monitor::{self, RequestLog};
+use crate::openapi;
+use crate::patch;
+use crate::query::ListQuery;
+use crate::relation::Relations;
+use crate::watch;
+
+/// 路由模式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteMode {
+    /// 请求路径直接作为json pointer，数组按下标访问
+    Pointer,
+    /// json-server风格：对象数组视为集合，按元素的id字段访问，POST到集合时追加并生成id
+    Collection,
+}
+
+impl FromStr for RouteMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pointer" => Ok(RouteMode::Pointer),
+            "collection" => Ok(RouteMode::Collection),
+            _ => Err(format!("unknown route mode: {}", s)),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct QueryKeys {
@@ -11,9 +52,9 @@ pub struct QueryKeys {
 }
 
 impl QueryKeys {
-    pub fn from_req(req: &HttpRequest) -> QueryKeys {
-        let keys = req
-            .path()
+    /// 由请求路径解析
+    pub fn from_path(path: &str) -> QueryKeys {
+        let keys = path
             .split("/")
             .skip(1)
             .map(|seg| seg.to_string())
@@ -25,6 +66,51 @@ impl QueryKeys {
         }
     }
 
+    /// 由json pointer还原，用于重放日志
+    pub fn from_ptr(ptr: &str) -> QueryKeys {
+        if ptr.is_empty() {
+            QueryKeys { keys: vec![] }
+        } else {
+            QueryKeys {
+                keys: ptr[1..].split('/').map(|seg| seg.to_string()).collect(),
+            }
+        }
+    }
+
+    /// 按路由模式解析路径：集合模式下将集合中的id替换为对应元素的下标
+    pub fn resolve(mut self, mode: RouteMode, json_obj: &Value) -> Result<QueryKeys, Error> {
+        if mode == RouteMode::Pointer {
+            return Ok(self);
+        }
+        let mut cur = Some(json_obj);
+        for seg in self.keys.iter_mut() {
+            cur = match cur {
+                Some(Value::Array(array)) if db::is_collection(array) => {
+                    match db::find_by_id(array, seg) {
+                        Some(idx) => {
+                            *seg = idx.to_string();
+                            array.get(idx)
+                        }
+                        None => return Err(Error::IdNotFound { id: seg.clone() }),
+                    }
+                }
+                Some(Value::Array(array)) => {
+                    seg.parse::<usize>().ok().and_then(|idx| array.get(idx))
+                }
+                Some(Value::Object(map)) => map.get(seg.as_str()),
+                _ => None,
+            };
+        }
+        Ok(self)
+    }
+
+    /// 上一级路径
+    pub fn parent(&self) -> QueryKeys {
+        let mut keys = self.keys.clone();
+        keys.pop();
+        QueryKeys { keys }
+    }
+
     pub fn json_ptr(&self) -> String {
         if self.len() == 0 {
             String::new()
@@ -35,6 +121,11 @@ impl QueryKeys {
         }
     }
 
+    /// 第index级key
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.keys.get(index).map(|key| key.as_str())
+    }
+
     pub fn len(&self) -> usize {
         self.keys.len()
     }
@@ -51,33 +142,330 @@ pub fn server_info() -> HttpResponse {
     }))
 }
 
-pub fn do_get(req: HttpRequest, data: web::Data<db::Database>) -> HttpResponse {
-    let mut database = data.data.lock().unwrap();
-    let mut keys = QueryKeys::from_req(&req);
-    match db::Database::get(&mut keys, &mut database) {
-        Ok(obj) => HttpResponse::Ok().content_type("application/json; charset=utf-8").json(obj),
-        Err(e) => HttpResponse::build(http::StatusCode::BAD_REQUEST).json(e),
+pub fn do_get(
+    req: HttpRequest,
+    data: Db,
+    mode: web::Data<RouteMode>,
+) -> HttpResponse {
+    let database = data.data.read();
+    let keys = match data.keys(&req).resolve(**mode, &database) {
+        Ok(keys) => keys,
+        Err(e) => return e.error_response(),
+    };
+    let query = match ListQuery::parse(req.query_string()) {
+        Ok(query) => query,
+        Err(e) => return e.error_response(),
+    };
+    let relations = match Relations::parse(req.query_string()) {
+        Ok(relations) => relations,
+        Err(e) => return e.error_response(),
+    };
+    let found = database
+        .pointer(&keys.json_ptr())
+        .ok_or_else(|| Error::PathNotFound {
+            pointer: keys.json_ptr(),
+        });
+    let (collection, mut target) = match found {
+        Ok(obj) => {
+            let obj = obj.clone();
+            (db::Database::collection_of(&keys, &database), obj)
+        }
+        // 集合模式下 /posts/1/comments 这类路径不存在时按嵌套路由查找关联元素
+        Err(e) => match db::Database::get_nested(&keys, &database) {
+            Some((child, children)) if **mode == RouteMode::Collection => {
+                (Some(child), Value::Array(children))
+            }
+            _ => return e.error_response(),
+        },
+    };
+    let mut resp = HttpResponse::Ok();
+    resp.content_type("application/json; charset=utf-8");
+    // 目标为数组且带有查询参数时执行过滤、排序与分页
+    if let Value::Array(array) = &target {
+        if !query.is_empty() {
+            let result = query.apply(array);
+            resp.header("X-Total-Count", result.total.to_string());
+            if let Some(link) = query.links(req.path(), result.total) {
+                resp.header(http::header::LINK, link);
+            }
+            target = Value::Array(result.items);
+        }
+    }
+    match collection {
+        Some(collection) if !relations.is_empty() => {
+            relations.apply(&database, &collection, &mut target)
+        }
+        _ => {}
     }
+    let etag = etag::etag(&target);
+    if let Some(not_modified) = etag::not_modified(&req, &etag) {
+        return not_modified;
+    }
+    resp.header(http::header::ETAG, etag);
+    resp.json(target)
 }
 
 pub fn do_post(
     req: HttpRequest,
-    data: web::Data<db::Database>,
+    data: Db,
+    mode: web::Data<RouteMode>,
+    preconditions: web::Data<Preconditions>,
     obj: web::Json<Value>,
 ) -> HttpResponse {
-    let mut database = data.data.lock().unwrap();
-    let mut keys = QueryKeys::from_req(&req);
-    match db::Database::insert(&mut keys, &mut database, obj.0) {
-        Ok(_) => HttpResponse::new(http::StatusCode::CREATED),
-        Err(e) => HttpResponse::build(http::StatusCode::BAD_REQUEST).json(e),
+    let mut database = data.data.write();
+    let mut keys = match data.keys(&req).resolve(**mode, &database) {
+        Ok(keys) => keys,
+        Err(e) => return e.error_response(),
+    };
+    let path = keys.json_ptr();
+    if req.method() == http::Method::PUT {
+        if let Err(e) = etag::check(&req, database.pointer(&path), **preconditions) {
+            return e.error_response();
+        }
+    }
+    if **mode == RouteMode::Collection {
+        let is_collection = |ptr: &str| match database.pointer(ptr) {
+            Some(Value::Array(array)) => db::is_collection(array),
+            _ => false,
+        };
+        // POST到集合：追加元素
+        if req.method() == http::Method::POST && is_collection(&path) {
+            let res = data.checked(&path, &mut database, |database| {
+                db::Database::append(&mut keys, database, obj.0)
+            });
+            return match res {
+                Ok((idx, item)) => {
+                    let entry = Entry::Insert {
+                        path: format!("{}/{}", path, idx),
+                        value: item.clone(),
+                    };
+                    data.record(&entry, &database, None);
+                    HttpResponse::Created().json(item)
+                }
+                Err(e) => e.error_response(),
+            };
+        }
+        // 写入集合中的元素：整体替换，保留原id
+        if keys.len() > 0 && is_collection(&keys.parent().json_ptr()) {
+            let mut value = obj.0;
+            if let (Some(item), Some(id)) = (
+                value.as_object_mut(),
+                database.pointer(&path).and_then(|old| old.get("id")),
+            ) {
+                item.insert("id".to_string(), id.clone());
+            }
+            let old = data.previous(&database, &path, false);
+            let res = data.checked(&path, &mut database, |database| {
+                db::Database::replace(&mut keys, database, value.clone())
+            });
+            return match res {
+                Ok(_) => {
+                    let entry = Entry::Replace {
+                        path,
+                        value: value.clone(),
+                    };
+                    data.record(&entry, &database, old);
+                    HttpResponse::Ok()
+                        .header(http::header::ETAG, etag::etag(&value))
+                        .json(value)
+                }
+                Err(e) => e.error_response(),
+            };
+        }
+    }
+    // 根只能整体修改，如 --api-prefix 下的 POST /api
+    if keys.len() == 0 {
+        return Error::RootNotAllowed.error_response();
+    }
+    let old = data.previous(&database, &path, true);
+    let res = data.checked(&path, &mut database, |database| {
+        db::Database::insert(&mut keys, database, obj.0.clone())
+    });
+    match res {
+        Ok(_) => {
+            let etag = etag::etag(&obj.0);
+            data.record(&Entry::Insert { path, value: obj.0 }, &database, old);
+            HttpResponse::Created()
+                .header(http::header::ETAG, etag)
+                .finish()
+        }
+        Err(e) => e.error_response(),
     }
 }
 
-pub fn do_delete(req: HttpRequest, data: web::Data<db::Database>) -> HttpResponse {
-    let mut database = data.data.lock().unwrap();
-    let mut keys = QueryKeys::from_req(&req);
-    match db::Database::delete(&mut keys, &mut database) {
-        Ok(_) => HttpResponse::new(http::StatusCode::NO_CONTENT),
+/// 局部更新，根据Content-Type区分JSON Patch与JSON Merge Patch
+pub fn do_patch(
+    req: HttpRequest,
+    data: Db,
+    mode: web::Data<RouteMode>,
+    preconditions: web::Data<Preconditions>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let content_type = req
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    let mut database = data.data.write();
+    let mut keys = match data.keys(&req).resolve(**mode, &database) {
+        Ok(keys) => keys,
+        Err(e) => return e.error_response(),
+    };
+    let path = keys.json_ptr();
+    if let Err(e) = etag::check(&req, database.pointer(&path), **preconditions) {
+        return e.error_response();
+    }
+    let old = data.previous(&database, &path, false);
+    let res = match content_type.as_str() {
+        patch::JSON_PATCH => match serde_json::from_slice::<Vec<patch::Operation>>(&body) {
+            Ok(ops) => data
+                .checked(&path, &mut database, |database| {
+                    db::Database::json_patch(&mut keys, database, ops.clone())
+                })
+                .map(|_| Entry::JsonPatch { path, ops }),
+            Err(e) => Err(Error::InvalidBody {
+                detail: format!("invalid json patch: {}", e),
+            }),
+        },
+        patch::MERGE_PATCH => match serde_json::from_slice::<Value>(&body) {
+            Ok(value) => data
+                .checked(&path, &mut database, |database| {
+                    db::Database::merge_patch(&mut keys, database, value.clone())
+                })
+                .map(|_| Entry::MergePatch { path, value }),
+            Err(e) => Err(Error::InvalidBody {
+                detail: format!("invalid merge patch: {}", e),
+            }),
+        },
+        _ => {
+            return Error::UnsupportedMediaType {
+                accept: vec![patch::JSON_PATCH, patch::MERGE_PATCH],
+            }
+            .error_response()
+        }
+    };
+    match res {
+        Ok(entry) => {
+            data.record(&entry, &database, old);
+            let mut resp = HttpResponse::NoContent();
+            if let Some(value) = database.pointer(entry.path()) {
+                resp.header(http::header::ETAG, etag::etag(value));
+            }
+            resp.finish()
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub fn do_delete(
+    req: HttpRequest,
+    data: Db,
+    mode: web::Data<RouteMode>,
+    preconditions: web::Data<Preconditions>,
+) -> HttpResponse {
+    let mut database = data.data.write();
+    let mut keys = match data.keys(&req).resolve(**mode, &database) {
+        Ok(keys) => keys,
+        Err(e) => return e.error_response(),
+    };
+    if keys.len() == 0 {
+        return Error::RootNotAllowed.error_response();
+    }
+    let path = keys.json_ptr();
+    if let Err(e) = etag::check(&req, database.pointer(&path), **preconditions) {
+        return e.error_response();
+    }
+    let old = data.previous(&database, &path, false);
+    let res = data.checked(&path, &mut database, |database| {
+        db::Database::delete(&mut keys, database)
+    });
+    match res {
+        Ok(_) => {
+            data.record(&Entry::Delete { path }, &database, old);
+            HttpResponse::new(http::StatusCode::NO_CONTENT)
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+/// 批量操作，全部成功才生效，否则全部回滚
+pub async fn batch(
+    req: HttpRequest,
+    data: Db,
+    mode: web::Data<RouteMode>,
+    auth: web::Data<Auth>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return Error::InvalidBody {
+                    detail: format!("read body failed: {}", e),
+                }
+                .error_response()
+            }
+        };
+        if body.len() + chunk.len() > batch::BODY_LIMIT {
+            return Error::PayloadTooLarge {
+                limit: batch::BODY_LIMIT,
+            }
+            .error_response();
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let ops = match serde_json::from_slice::<Vec<batch::Operation>>(&body) {
+        Ok(ops) => ops,
+        Err(e) => {
+            return Error::InvalidBody {
+                detail: format!("invalid batch: {}", e),
+            }
+            .error_response()
+        }
+    };
+    // 与单个请求一样受认证规则限制
+    if let Err(e) = batch::authorize(&auth, req.headers(), data.mount(), &ops) {
+        return e.error_response();
+    }
+    let mut database = data.data.write();
+    match batch::execute(&data, **mode, &mut database, ops) {
+        Ok(results) => HttpResponse::Ok().json(json!({ "results": results })),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// GraphQL接口，GET不带query时返回schema的SDL
+pub fn graphql(req: HttpRequest, data: Db, body: web::Bytes) -> HttpResponse {
+    let request = if req.method() == http::Method::GET {
+        match graphql::Request::from_query(req.query_string()) {
+            Ok(Some(request)) => Ok(request),
+            Ok(None) => {
+                let database = data.data.read();
+                return HttpResponse::Ok()
+                    .content_type("text/plain; charset=utf-8")
+                    .body(graphql::sdl(&database));
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        let content_type = req
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .unwrap_or("")
+            .trim();
+        graphql::Request::from_body(content_type, &body)
+    };
+    let allow_mutation = req.method() != http::Method::GET;
+    match request.and_then(|request| graphql::execute(&data, request, allow_mutation)) {
+        Ok(response) => HttpResponse::Ok().json(response),
         Err(e) => HttpResponse::build(http::StatusCode::BAD_REQUEST).json(e),
     }
 }
@@ -87,11 +475,187 @@ pub struct FlushConfig {
     file: String,
 }
 
-pub fn flush(data: web::Data<db::Database>, conf: web::Json<FlushConfig>) -> HttpResponse {
-    let json_obj = data.data.lock().unwrap();
+pub fn flush(data: Db, conf: web::Json<FlushConfig>) -> HttpResponse {
     let file = conf.0.file;
-    match db::Database::flush(&json_obj, file) {
+    // 按目标文件的扩展名选择格式，无法识别时保持db文件的格式
+    let format = Format::from_path(&file).unwrap_or_else(|| data.format());
+    match data.flush(&file, format) {
         Ok(_) => HttpResponse::new(http::StatusCode::NO_CONTENT),
-        Err(e) => HttpResponse::build(http::StatusCode::INTERNAL_SERVER_ERROR).json(e),
+        Err(e) => e.error_response(),
     }
 }
+
+/// 导出描述当前数据的OpenAPI文档
+pub fn openapi(data: Db, mode: web::Data<RouteMode>) -> HttpResponse {
+    let database = data.data.read();
+    let mut spec = openapi::export(&database, **mode);
+    // 数据库路由位于api前缀或 /db/<name> 下
+    if !data.mount().is_empty() {
+        spec["servers"] = json!([{ "url": data.mount() }]);
+    }
+    HttpResponse::Ok().json(spec)
+}
+
+/// 最近的请求，按查询参数过滤
+pub fn requests_list(req: HttpRequest, log: web::Data<RequestLog>) -> HttpResponse {
+    match monitor::Filter::parse(req.query_string()) {
+        Ok(filter) => {
+            let (requests, total) = log.query(&filter);
+            HttpResponse::Ok().json(json!({"total": total, "requests": requests}))
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub fn requests_clear(log: web::Data<RequestLog>) -> HttpResponse {
+    log.clear();
+    HttpResponse::new(http::StatusCode::NO_CONTENT)
+}
+
+/// Prometheus格式的指标
+pub fn metrics(log: web::Data<RequestLog>, databases: web::Data<Databases>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(log.metrics(&databases))
+}
+
+/// 订阅数据变更，?path= 指定只订阅的路径
+pub async fn watch(
+    req: HttpRequest,
+    data: Db,
+    stream: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let prefixes = match watch::parse_prefixes(req.query_string()) {
+        Ok(prefixes) => prefixes,
+        Err(e) => return Ok(e.error_response()),
+    };
+    let receiver = data.watchers.subscribe(prefixes);
+    if watch::is_websocket(&req) {
+        ws::start(watch::WatchSession::new(receiver), &req, stream)
+    } else {
+        Ok(watch::sse(receiver))
+    }
+}
+
+/// 列出所有命名数据库
+pub fn databases_list(databases: web::Data<Databases>) -> HttpResponse {
+    HttpResponse::Ok().json(databases.list())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateDatabase {
+    name: String,
+    /// 要克隆的数据库
+    from: Option<String>,
+    /// 初始数据，未给出时为空对象
+    data: Option<Value>,
+}
+
+/// 创建数据库
+pub fn databases_create(
+    databases: web::Data<Databases>,
+    conf: web::Json<CreateDatabase>,
+) -> HttpResponse {
+    let conf = conf.0;
+    match databases.create(&conf.name, conf.from.as_deref(), conf.data) {
+        Ok(_) => HttpResponse::Created().json(json!({
+            "name": conf.name,
+            "path": databases.path(&conf.name)
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// 恢复为加载或创建时的数据
+pub fn databases_reset(databases: web::Data<Databases>, name: web::Path<String>) -> HttpResponse {
+    match databases.reset(&name) {
+        Ok(_) => HttpResponse::new(http::StatusCode::NO_CONTENT),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub fn databases_drop(databases: web::Data<Databases>, name: web::Path<String>) -> HttpResponse {
+    match databases.remove(&name) {
+        Ok(_) => HttpResponse::new(http::StatusCode::NO_CONTENT),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// 列出当前数据库保存的快照
+pub fn fixture_list(data: Db) -> HttpResponse {
+    HttpResponse::Ok().json(data.fixtures.list())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaveFixture {
+    name: String,
+}
+
+/// 以给定名字保存当前数据，同名快照会被覆盖
+pub fn fixture_save(data: Db, conf: web::Json<SaveFixture>) -> HttpResponse {
+    let json_obj = data.data.read().clone();
+    match data.fixtures.save(&conf.name, json_obj) {
+        Ok(_) => HttpResponse::Created().json(json!({ "name": conf.name })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub fn fixture_remove(data: Db, name: web::Path<String>) -> HttpResponse {
+    match data.fixtures.remove(&name) {
+        Ok(_) => HttpResponse::new(http::StatusCode::NO_CONTENT),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// 恢复为快照的数据，与其它修改一样写入日志并通知订阅者
+pub fn fixture_restore(data: Db, name: web::Path<String>) -> HttpResponse {
+    match data.fixtures.get(&name) {
+        Ok(value) => {
+            data.reset(value);
+            HttpResponse::new(http::StatusCode::NO_CONTENT)
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+/// 恢复为启动时db文件的内容
+pub fn fixture_reset(data: Db) -> HttpResponse {
+    data.reset(data.fixtures.initial());
+    HttpResponse::new(http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Login {
+    username: String,
+    password: String,
+    /// 覆盖配置中的有效期(秒)，便于测试过期
+    expires_in: Option<u64>,
+}
+
+/// 用户名密码登录，签发JWT
+pub fn login(auth: web::Data<Auth>, conf: web::Json<Login>) -> HttpResponse {
+    match auth.login(&conf.username, &conf.password, conf.expires_in) {
+        Ok(token) => HttpResponse::Ok().json(token),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// 查看当前的故障注入配置
+pub fn chaos_info(chaos: web::Data<RwLock<ChaosConfig>>) -> HttpResponse {
+    HttpResponse::Ok().json(&*chaos.read().unwrap())
+}
+
+/// 替换故障注入配置，未给出的字段取默认值(不注入)
+pub fn chaos_update(
+    chaos: web::Data<RwLock<ChaosConfig>>,
+    conf: web::Json<ChaosConfig>,
+) -> HttpResponse {
+    *chaos.write().unwrap() = conf.0;
+    HttpResponse::Ok().json(&*chaos.read().unwrap())
+}
+
+/// 关闭所有故障注入
+pub fn chaos_reset(chaos: web::Data<RwLock<ChaosConfig>>) -> HttpResponse {
+    *chaos.write().unwrap() = ChaosConfig::default();
+    HttpResponse::new(http::StatusCode::NO_CONTENT)
+}
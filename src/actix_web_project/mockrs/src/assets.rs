@@ -0,0 +1,243 @@
+//! 静态文件模块
+//! 指定 --static-dir 时数据库路由移到 --api-prefix 下，其余路径的 GET/HEAD 请求返回目录中的文件，
+//! 便于前端构建产物与mock接口由同一个进程提供：
+//!     Content-Type 按扩展名判断，支持单个区间的 Range 请求
+//!     目录返回其中的 index.html，不返回以 . 开头的隐藏文件
+//!     文件不存在且请求接受html时返回根目录的 index.html，由前端路由处理 history 模式的路径
+//! 前端文件经常重新构建，响应均带有 Cache-Control: no-cache。
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use percent_encoding::percent_decode_str;
+
+use crate::error;
+
+/// 规范化 --api-prefix：补上开头的 /，去掉末尾的 /，"/" 视为空
+pub fn api_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim().trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", prefix)
+    }
+}
+
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+/// Range 请求头的解析结果
+#[derive(Debug, PartialEq)]
+enum Range {
+    /// 没有Range或格式不支持，返回整个文件
+    Full,
+    /// [start, end)
+    Partial(u64, u64),
+    /// 区间在文件之外，返回416
+    Unsatisfiable,
+}
+
+impl StaticFiles {
+    pub fn new(root: &str) -> std::io::Result<StaticFiles> {
+        let root = PathBuf::from(root);
+        if !root.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("static dir {:?} is not a directory", root),
+            ));
+        }
+        Ok(StaticFiles { root })
+    }
+
+    /// 请求路径对应的文件，不存在或试图访问目录之外、隐藏文件时返回None
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+        for seg in path.split('/').filter(|seg| !seg.is_empty()) {
+            let seg = percent_decode_str(seg).decode_utf8().ok()?;
+            // 同时排除了 . 和 ..
+            if seg.starts_with('.') || seg.contains('/') || seg.contains('\\') {
+                return None;
+            }
+            file.push(seg.as_ref());
+        }
+        if file.is_dir() {
+            file.push("index.html");
+        }
+        if file.is_file() {
+            Some(file)
+        } else {
+            None
+        }
+    }
+}
+
+/// 解析 Range 请求头，只支持单个区间，多个区间时返回整个文件
+fn parse_range(value: &str, len: u64) -> Range {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Range::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return Range::Full,
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-500 为最后500字节
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Range::Unsatisfiable;
+            }
+            (len.saturating_sub(suffix), len)
+        }
+        (Ok(start), Err(_)) if end.is_empty() => (start, len),
+        (Ok(start), Ok(end)) if end >= start => (start, end.saturating_add(1).min(len)),
+        _ => return Range::Full,
+    };
+    if start >= len {
+        Range::Unsatisfiable
+    } else {
+        Range::Partial(start, end)
+    }
+}
+
+fn accepts_html(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+fn respond(req: &HttpRequest, path: &Path) -> std::io::Result<HttpResponse> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(Range::Full, |value| parse_range(value, len));
+    let (mut builder, start, end) = match range {
+        Range::Full => (HttpResponse::Ok(), 0, len),
+        Range::Partial(start, end) => {
+            let mut builder = HttpResponse::build(StatusCode::PARTIAL_CONTENT);
+            builder.header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, len),
+            );
+            (builder, start, end)
+        }
+        Range::Unsatisfiable => {
+            return Ok(HttpResponse::build(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .finish())
+        }
+    };
+    let mut body = Vec::with_capacity((end - start) as usize);
+    file.seek(SeekFrom::Start(start))?;
+    file.take(end - start).read_to_end(&mut body)?;
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    Ok(builder
+        .content_type(mime.to_string())
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body))
+}
+
+/// 未匹配任何路由的请求
+pub fn serve(req: HttpRequest, files: web::Data<StaticFiles>) -> HttpResponse {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return HttpResponse::MethodNotAllowed()
+            .header(header::ALLOW, "GET, HEAD")
+            .finish();
+    }
+    let file = match files.resolve(req.path()) {
+        Some(file) => file,
+        None if accepts_html(&req) => match files.resolve("/index.html") {
+            Some(file) => file,
+            None => return HttpResponse::NotFound().finish(),
+        },
+        None => return HttpResponse::NotFound().finish(),
+    };
+    match respond(&req, &file) {
+        Ok(res) => res,
+        Err(e) => error::Error::Io {
+            detail: format!("can not read {:?}: {}", file, e),
+        }
+        .error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Range::Partial(0, 100));
+        assert_eq!(parse_range("bytes=900-", 1000), Range::Partial(900, 1000));
+        assert_eq!(parse_range("bytes=-100", 1000), Range::Partial(900, 1000));
+        assert_eq!(
+            parse_range("bytes=990-2000", 1000),
+            Range::Partial(990, 1000)
+        );
+        assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Range::Full);
+        assert_eq!(parse_range("items=0-1", 1000), Range::Full);
+        assert_eq!(parse_range("bytes=5-1", 1000), Range::Full);
+        assert_eq!(api_prefix("api/"), "/api");
+        assert_eq!(api_prefix("/"), "");
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = std::env::temp_dir().join(format!("mockrs-static-{}", std::process::id()));
+        fs::create_dir_all(dir.join("js")).unwrap();
+        fs::write(dir.join("index.html"), "<html></html>").unwrap();
+        fs::write(dir.join("js/app.js"), "console.log(1)").unwrap();
+        fs::write(dir.join(".env"), "SECRET=1").unwrap();
+        let files = web::Data::new(StaticFiles::new(dir.to_str().unwrap()).unwrap());
+        let get = |uri: &str, accept: &str| {
+            let req = TestRequest::with_uri(uri)
+                .header(header::ACCEPT, accept)
+                .to_http_request();
+            serve(req, files.clone())
+        };
+        let content_type = |res: &HttpResponse| {
+            res.headers()
+                .get(header::CONTENT_TYPE)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        let res = get("/js/app.js", "*/*");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(content_type(&res).unwrap(), "application/javascript");
+        assert_eq!(content_type(&get("/", "*/*")).unwrap(), "text/html");
+        // 前端路由的路径返回index.html，找不到的资源仍为404
+        let res = get("/users/1", "text/html,*/*");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(content_type(&res).unwrap(), "text/html");
+        assert_eq!(get("/js/missing.js", "*/*").status(), StatusCode::NOT_FOUND);
+        assert_eq!(get("/.env", "*/*").status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            get("/js/%2E%2E/.env", "*/*").status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let req = TestRequest::with_uri("/js/app.js")
+            .header(header::RANGE, "bytes=0-6")
+            .to_http_request();
+        let res = serve(req, files.clone());
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes 0-6/14"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,521 @@
+//! 认证模拟模块
+//! 通过 --auth 指定一个json文件，为通用的 /* 资源加上认证，支持三种凭证：
+//!     X-API-Key: <key>                    静态API key
+//!     Authorization: Basic <base64>       users中的用户名与密码
+//!     Authorization: Bearer <jwt>         POST /_actions/login 签发的HS256 JWT
+//! rules按顺序匹配路径(与 --routes 相同的 :name 与 * 模式)及method，第一条匹配的规则生效，都不匹配时使用default：
+//!     public          无需认证
+//!     read_only       GET HEAD OPTIONS 无需认证，修改需要认证
+//!     authenticated   需要认证
+//! 规则可以再要求一个role，凭证中没有该role时返回403。
+//! ```json
+//! {
+//!   "api_keys": [{"key": "k1", "roles": ["admin"]}],
+//!   "users": [{"username": "alice", "password": "secret", "roles": ["editor"], "claims": {"team": "a"}}],
+//!   "jwt": {"secret": "change-me", "expires_in": 3600, "claims": {"iss": "mockrs"}},
+//!   "rules": [
+//!     {"path": "/health", "access": "public"},
+//!     {"path": "/posts/*", "access": "read_only"},
+//!     {"path": "/admin/*", "methods": ["DELETE"], "role": "admin"}
+//!   ],
+//!   "default": "authenticated"
+//! }
+//! ```
+use std::fs;
+use std::io::ErrorKind;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::{Body, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, HeaderMap, Method};
+use actix_web::{web, ResponseError};
+use futures::future::{ok, Either, Ready};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+
+use crate::error::Error;
+use crate::routes::Pattern;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 访问级别
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Public,
+    ReadOnly,
+    #[default]
+    Authenticated,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ApiKey {
+    key: String,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct User {
+    username: String,
+    password: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    /// 签发JWT时加入的额外claims
+    #[serde(default)]
+    claims: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct JwtConfig {
+    /// 为空时不能登录，也不接受Bearer凭证
+    secret: String,
+    /// 有效期(秒)
+    expires_in: u64,
+    /// 所有JWT都带有的claims
+    claims: Map<String, Value>,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            secret: String::new(),
+            expires_in: 3600,
+            claims: Map::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RuleConfig {
+    path: String,
+    /// 为空时匹配所有method
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    access: Access,
+    role: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct AuthConfig {
+    api_keys: Vec<ApiKey>,
+    users: Vec<User>,
+    jwt: JwtConfig,
+    rules: Vec<RuleConfig>,
+    default: Access,
+}
+
+/// 通过认证的身份
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub subject: String,
+    pub roles: Vec<String>,
+}
+
+/// 认证配置，未指定 --auth 时所有请求都无需认证
+pub struct Auth {
+    config: AuthConfig,
+    rules: Vec<(Pattern, RuleConfig)>,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth::from_config(AuthConfig {
+            default: Access::Public,
+            ..AuthConfig::default()
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, String> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).map_err(|e| e.to_string())
+}
+
+fn mac(secret: &str, content: &str) -> HmacSha256 {
+    // HMAC接受任意长度的key
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).unwrap();
+    mac.input(content.as_bytes());
+    mac
+}
+
+/// 签发HS256 JWT
+pub fn sign(claims: &Map<String, Value>, secret: &str) -> String {
+    let header = encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = encode(serde_json::to_string(claims).unwrap().as_bytes());
+    let content = format!("{}.{}", header, payload);
+    let signature = encode(&mac(secret, &content).result().code());
+    format!("{}.{}", content, signature)
+}
+
+/// 校验HS256 JWT的签名与有效期，返回其中的claims
+pub fn verify(token: &str, secret: &str) -> Result<Map<String, Value>, String> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err("malformed token".to_string());
+    }
+    let header: Value = serde_json::from_slice(&decode(parts[0])?).map_err(|e| e.to_string())?;
+    if header["alg"] != "HS256" {
+        return Err("unsupported token algorithm".to_string());
+    }
+    let signature = decode(parts[2])?;
+    mac(secret, &format!("{}.{}", parts[0], parts[1]))
+        .verify(&signature)
+        .map_err(|_| "invalid token signature".to_string())?;
+    let claims: Map<String, Value> =
+        serde_json::from_slice(&decode(parts[1])?).map_err(|e| e.to_string())?;
+    match claims.get("exp").and_then(Value::as_u64) {
+        Some(exp) if exp <= now() => Err("token expired".to_string()),
+        _ => Ok(claims),
+    }
+}
+
+/// claims中的roles数组或role字符串
+fn roles_of(claims: &Map<String, Value>) -> Vec<String> {
+    match (claims.get("roles"), claims.get("role")) {
+        (Some(Value::Array(roles)), _) => roles
+            .iter()
+            .filter_map(|role| role.as_str().map(str::to_string))
+            .collect(),
+        (_, Some(Value::String(role))) => vec![role.clone()],
+        _ => vec![],
+    }
+}
+
+fn unauthorized(detail: &str) -> Error {
+    Error::Unauthorized {
+        detail: detail.to_string(),
+    }
+}
+
+impl Auth {
+    pub fn load(file: &str) -> std::io::Result<Auth> {
+        let content = fs::read_to_string(file)?;
+        let config: AuthConfig = serde_json::from_str(&content).map_err(|e| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid auth file {}: {}", file, e),
+            )
+        })?;
+        Ok(Auth::from_config(config))
+    }
+
+    fn from_config(config: AuthConfig) -> Auth {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| (Pattern::new(&rule.path), rule.clone()))
+            .collect();
+        Auth { config, rules }
+    }
+
+    /// 用户名密码登录，签发JWT，expires_in可以缩短或延长有效期
+    pub fn login(
+        &self,
+        username: &str,
+        password: &str,
+        expires_in: Option<u64>,
+    ) -> Result<Value, Error> {
+        if self.config.jwt.secret.is_empty() {
+            return Err(Error::LoginNotConfigured);
+        }
+        let user = self
+            .config
+            .users
+            .iter()
+            .find(|user| user.username == username && user.password == password)
+            .ok_or_else(|| unauthorized("invalid username or password"))?;
+        let expires_in = expires_in.unwrap_or(self.config.jwt.expires_in);
+        let iat = now();
+        let mut claims = self.config.jwt.claims.clone();
+        claims.extend(user.claims.clone());
+        claims.insert("sub".to_string(), json!(user.username));
+        claims.insert("roles".to_string(), json!(user.roles));
+        claims.insert("iat".to_string(), json!(iat));
+        // expires_in由客户端给出，过大时视为永不过期
+        claims.insert("exp".to_string(), json!(iat.saturating_add(expires_in)));
+        Ok(json!({
+            "access_token": sign(&claims, &self.config.jwt.secret),
+            "token_type": "Bearer",
+            "expires_in": expires_in
+        }))
+    }
+
+    /// 从请求头解析凭证，没有凭证时为None，凭证无效时返回原因
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Identity>, String> {
+        if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+            return match self
+                .config
+                .api_keys
+                .iter()
+                .find(|api_key| api_key.key == key)
+            {
+                Some(api_key) => Ok(Some(Identity {
+                    subject: "api_key".to_string(),
+                    roles: api_key.roles.clone(),
+                })),
+                None => Err("invalid api key".to_string()),
+            };
+        }
+        let authorization = match headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(authorization) => authorization,
+            None => return Ok(None),
+        };
+        if let Some(credentials) = authorization.strip_prefix("Basic ") {
+            let credentials = base64::decode(credentials.trim())
+                .ok()
+                .and_then(|credentials| String::from_utf8(credentials).ok())
+                .ok_or_else(|| "malformed basic credentials".to_string())?;
+            let mut parts = credentials.splitn(2, ':');
+            let (username, password) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            return match self
+                .config
+                .users
+                .iter()
+                .find(|user| user.username == username && user.password == password)
+            {
+                Some(user) => Ok(Some(Identity {
+                    subject: user.username.clone(),
+                    roles: user.roles.clone(),
+                })),
+                None => Err("invalid username or password".to_string()),
+            };
+        }
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            if self.config.jwt.secret.is_empty() {
+                return Err("bearer tokens are not accepted".to_string());
+            }
+            let claims = verify(token.trim(), &self.config.jwt.secret)?;
+            return Ok(Some(Identity {
+                subject: claims
+                    .get("sub")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                roles: roles_of(&claims),
+            }));
+        }
+        Err("unsupported authorization scheme".to_string())
+    }
+
+    /// 第一条匹配的规则，没有时为default
+    fn rule_for(&self, method: &Method, path: &str) -> (Access, Option<&str>) {
+        self.rules
+            .iter()
+            .find(|(pattern, rule)| {
+                pattern.matches(path).is_some()
+                    && (rule.methods.is_empty()
+                        || rule
+                            .methods
+                            .iter()
+                            .any(|m| m.eq_ignore_ascii_case(method.as_str())))
+            })
+            .map(|(_, rule)| (rule.access, rule.role.as_deref()))
+            .unwrap_or((self.config.default, None))
+    }
+
+    /// 按规则检查请求，不允许访问时返回401或403错误
+    pub fn check(&self, method: &Method, path: &str, headers: &HeaderMap) -> Result<(), Error> {
+        let (access, role) = self.rule_for(method, path);
+        let read = [Method::GET, Method::HEAD, Method::OPTIONS].contains(method);
+        match access {
+            Access::Public => return Ok(()),
+            Access::ReadOnly if read => return Ok(()),
+            _ => {}
+        }
+        let identity = match self.authenticate(headers) {
+            Ok(Some(identity)) => identity,
+            Ok(None) => return Err(unauthorized("authentication required")),
+            Err(reason) => return Err(unauthorized(&reason)),
+        };
+        match role {
+            Some(role) if !identity.roles.iter().any(|r| r == role) => Err(Error::Forbidden {
+                role: role.to_string(),
+                subject: identity.subject,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 认证中间件，拒绝的请求不会到达处理函数
+pub struct Authentication {
+    auth: web::Data<Auth>,
+}
+
+impl Authentication {
+    pub fn new(auth: web::Data<Auth>) -> Authentication {
+        Authentication { auth }
+    }
+}
+
+impl<S> Transform<S> for Authentication
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+    >,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = AuthenticationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticationMiddleware {
+            service,
+            auth: self.auth.clone(),
+        })
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: S,
+    auth: web::Data<Auth>,
+}
+
+impl<S> Service for AuthenticationMiddleware<S>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+    >,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = actix_web::Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match self.auth.check(req.method(), req.path(), req.headers()) {
+            Ok(_) => Either::Left(self.service.call(req)),
+            Err(e) => Either::Right(ok(req.into_response(e.error_response()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn auth() -> Auth {
+        let config = json!({
+            "api_keys": [{"key": "k1", "roles": ["admin"]}],
+            "users": [{"username": "alice", "password": "secret", "roles": ["editor"]}],
+            "jwt": {"secret": "s3cret", "claims": {"iss": "mockrs"}},
+            "rules": [
+                {"path": "/health", "access": "public"},
+                {"path": "/posts/*", "access": "read_only"},
+                {"path": "/admin/*", "methods": ["delete"], "role": "admin"}
+            ]
+        });
+        Auth::from_config(serde_json::from_value(config).unwrap())
+    }
+
+    fn status(auth: &Auth, req: TestRequest) -> u16 {
+        let req = req.to_http_request();
+        match auth.check(req.method(), req.path(), req.headers()) {
+            Ok(_) => 200,
+            Err(e) => e.status().as_u16(),
+        }
+    }
+
+    #[test]
+    fn test_jwt() {
+        let mut claims = Map::new();
+        claims.insert("sub".to_string(), json!("alice"));
+        claims.insert("exp".to_string(), json!(now() + 60));
+        let token = sign(&claims, "key");
+        assert_eq!(verify(&token, "key").unwrap(), claims);
+        assert!(verify(&token, "other").is_err());
+        let tampered = token.replacen(".", ".e30.", 1);
+        assert!(verify(&tampered, "key").is_err());
+
+        claims.insert("exp".to_string(), json!(now() - 1));
+        assert_eq!(
+            verify(&sign(&claims, "key"), "key").unwrap_err(),
+            "token expired"
+        );
+    }
+
+    #[test]
+    fn test_rules() {
+        let auth = auth();
+        assert_eq!(status(&auth, TestRequest::with_uri("/health")), 200);
+        assert_eq!(status(&auth, TestRequest::with_uri("/posts/1")), 200);
+        assert_eq!(status(&auth, TestRequest::delete().uri("/posts/1")), 401);
+        assert_eq!(status(&auth, TestRequest::with_uri("/users")), 401);
+
+        let basic = format!("Basic {}", base64::encode("alice:secret"));
+        let req = || TestRequest::delete().uri("/posts/1");
+        assert_eq!(
+            status(&auth, req().header("Authorization", basic.as_str())),
+            200
+        );
+        let wrong = format!("Basic {}", base64::encode("alice:nope"));
+        assert_eq!(
+            status(&auth, req().header("Authorization", wrong.as_str())),
+            401
+        );
+        assert_eq!(status(&auth, req().header("X-API-Key", "nope")), 401);
+
+        // 需要role的规则
+        let req = || TestRequest::delete().uri("/admin/users");
+        assert_eq!(
+            status(&auth, req().header("Authorization", basic.as_str())),
+            403
+        );
+        assert_eq!(status(&auth, req().header("X-API-Key", "k1")), 200);
+
+        let login = auth.login("alice", "secret", Some(60)).unwrap();
+        let bearer = format!("Bearer {}", login["access_token"].as_str().unwrap());
+        let claims = verify(login["access_token"].as_str().unwrap(), "s3cret").unwrap();
+        assert_eq!(claims["iss"], "mockrs");
+        assert_eq!(claims["roles"], json!(["editor"]));
+        assert_eq!(
+            status(
+                &auth,
+                TestRequest::with_uri("/users").header("Authorization", bearer.as_str())
+            ),
+            200
+        );
+        assert!(auth.login("alice", "nope", None).is_err());
+        let login = auth.login("alice", "secret", Some(u64::MAX)).unwrap();
+        let claims = verify(login["access_token"].as_str().unwrap(), "s3cret").unwrap();
+        assert_eq!(claims["exp"], json!(u64::MAX));
+        assert_eq!(
+            status(&Auth::default(), TestRequest::delete().uri("/users")),
+            200
+        );
+    }
+}
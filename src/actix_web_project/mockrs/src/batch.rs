@@ -0,0 +1,399 @@
+//! 批量操作模块
+//! POST /_actions/batch 按顺序执行一组操作，在一次加锁内完成，全部成功才生效，任一失败则全部回滚：
+//!     {"op": "get", "path": "/posts/0"}
+//!     {"op": "insert", "path": "/posts/-", "value": {...}}      路径以 /- 结尾时追加到数组末尾
+//!     {"op": "delete", "path": "/posts/1"}
+//!     {"op": "patch", "path": "/posts/0", "value": {...}}       JSON Merge Patch
+//!     {"op": "patch", "path": "/posts/0", "ops": [...]}         JSON Patch
+//! 路径为json pointer，集合模式下与请求路径一样按id定位，追加到集合时自动生成id。
+//! 响应中每个操作对应一个结果；回滚时失败操作之后的操作不再执行，状态为424。
+//! 执行前按每个操作对应的请求检查 --auth 的规则，任一操作被拒绝时不执行任何操作。
+use actix_web::http::{HeaderMap, Method};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::api::{QueryKeys, RouteMode};
+use crate::auth::Auth;
+use crate::db::{self, Database};
+use crate::error::Error;
+use crate::journal::Entry;
+use crate::patch;
+use crate::schema;
+use crate::watch;
+
+/// 批量操作中的一项
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Get {
+        path: String,
+    },
+    Insert {
+        path: String,
+        value: Value,
+    },
+    Delete {
+        path: String,
+    },
+    /// value为JSON Merge Patch，ops为JSON Patch，只能给出其中一个
+    Patch {
+        path: String,
+        value: Option<Value>,
+        ops: Option<Vec<patch::Operation>>,
+    },
+}
+
+impl Operation {
+    /// 与该操作等价的单个请求的method与路径(json pointer)
+    fn request(&self) -> (Method, &str) {
+        match self {
+            Operation::Get { path } => (Method::GET, path),
+            Operation::Insert { path, .. } => match path.strip_suffix("/-") {
+                Some(parent) => (Method::POST, parent),
+                None => (Method::PUT, path),
+            },
+            Operation::Delete { path } => (Method::DELETE, path),
+            Operation::Patch { path, .. } => (Method::PATCH, path),
+        }
+    }
+}
+
+/// 请求体大小上限，一次批量导入的数据通常超过 web::Json 默认的32KB
+pub const BODY_LIMIT: usize = 4 * 1024 * 1024;
+
+/// 单个操作执行后的结果
+struct Applied {
+    result: Value,
+    entry: Option<Entry>,
+    event: Option<watch::Event>,
+}
+
+/// 按路由模式解析json pointer
+fn resolve(path: &str, mode: RouteMode, json_obj: &Value) -> Result<QueryKeys, Error> {
+    if !path.is_empty() && !path.starts_with('/') {
+        return Err(Error::InvalidPointer {
+            pointer: path.to_string(),
+        });
+    }
+    QueryKeys::from_ptr(path).resolve(mode, json_obj)
+}
+
+/// 插入，路径以 /- 结尾时追加到数组末尾，返回实际写入的路径、内容及被覆盖的旧值
+fn insert(
+    data: &Database,
+    path: &str,
+    value: Value,
+    mode: RouteMode,
+    json_obj: &mut Value,
+) -> Result<(String, Value, Option<Value>), Error> {
+    let parent = match path.strip_suffix("/-") {
+        Some(parent) => parent,
+        None => {
+            let mut keys = resolve(path, mode, json_obj)?;
+            let path = keys.json_ptr();
+            let old = data.previous(json_obj, &path, true);
+            Database::insert(&mut keys, json_obj, value.clone())?;
+            return Ok((path, value, old));
+        }
+    };
+    let mut keys = resolve(parent, mode, json_obj)?;
+    let parent = keys.json_ptr();
+    match json_obj.pointer(&parent) {
+        Some(Value::Array(array)) if mode == RouteMode::Collection && db::is_collection(array) => {
+            let (idx, item) = Database::append(&mut keys, json_obj, value)?;
+            Ok((format!("{}/{}", parent, idx), item, None))
+        }
+        Some(Value::Array(array)) => {
+            let path = format!("{}/{}", parent, array.len());
+            Database::insert(&mut QueryKeys::from_ptr(&path), json_obj, value.clone())?;
+            Ok((path, value, None))
+        }
+        _ => Err(Error::TypeConflict {
+            detail: format!("{} is not an array", parent),
+            pointer: parent,
+        }),
+    }
+}
+
+fn apply(
+    data: &Database,
+    mode: RouteMode,
+    json_obj: &mut Value,
+    op: Operation,
+) -> Result<Applied, Error> {
+    let (status, entry, old) = match op {
+        // 根只能整体修改，不能插入或删除
+        Operation::Insert { path, .. } | Operation::Delete { path } if path.is_empty() => {
+            return Err(Error::RootNotAllowed);
+        }
+        Operation::Get { path } => {
+            let path = resolve(&path, mode, json_obj)?.json_ptr();
+            return match json_obj.pointer(&path) {
+                Some(value) => Ok(Applied {
+                    result: json!({"status": 200, "path": path, "value": value}),
+                    entry: None,
+                    event: None,
+                }),
+                None => Err(Error::PathNotFound { pointer: path }),
+            };
+        }
+        Operation::Insert { path, value } => {
+            let (path, value, old) = insert(data, &path, value, mode, json_obj)?;
+            (201, Entry::Insert { path, value }, old)
+        }
+        Operation::Delete { path } => {
+            let mut keys = resolve(&path, mode, json_obj)?;
+            let path = keys.json_ptr();
+            let old = data.previous(json_obj, &path, false);
+            Database::delete(&mut keys, json_obj)?;
+            (204, Entry::Delete { path }, old)
+        }
+        Operation::Patch { path, value, ops } => {
+            let mut keys = resolve(&path, mode, json_obj)?;
+            let path = keys.json_ptr();
+            let old = data.previous(json_obj, &path, false);
+            let entry = match (value, ops) {
+                (Some(value), None) => {
+                    Database::merge_patch(&mut keys, json_obj, value.clone())?;
+                    Entry::MergePatch { path, value }
+                }
+                (None, Some(ops)) => {
+                    Database::json_patch(&mut keys, json_obj, ops.clone())?;
+                    Entry::JsonPatch { path, ops }
+                }
+                _ => {
+                    return Err(Error::InvalidBody {
+                        detail: "patch requires either value (merge patch) or ops (json patch)"
+                            .to_string(),
+                    })
+                }
+            };
+            (204, entry, old)
+        }
+    };
+    let mut result = json!({"status": status, "path": entry.path()});
+    if let Entry::Insert { value, .. } = &entry {
+        result["value"] = value.clone();
+    }
+    let event = if data.watchers.is_empty() {
+        None
+    } else {
+        Some(db::event(&entry, json_obj, old))
+    };
+    Ok(Applied {
+        result,
+        entry: Some(entry),
+        event,
+    })
+}
+
+/// 按修改过的顶层key校验，与单个写入的校验范围一致
+fn validate(data: &Database, json_obj: &Value, entries: &[Entry]) -> Vec<schema::Violation> {
+    let mut keys: Vec<Option<String>> = vec![];
+    for entry in entries {
+        let key = QueryKeys::from_ptr(entry.path())
+            .get(0)
+            .map(|key| key.to_string());
+        if key.as_deref() != Some(schema::SCHEMA_KEY) && !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    if keys.contains(&None) {
+        return data.validate(json_obj, None);
+    }
+    keys.iter()
+        .flat_map(|key| data.validate(json_obj, key.as_deref()))
+        .collect()
+}
+
+/// 按认证规则检查每个操作，mount为数据库路由所在的路径前缀
+pub fn authorize(
+    auth: &Auth,
+    headers: &HeaderMap,
+    mount: &str,
+    ops: &[Operation],
+) -> Result<(), Error> {
+    ops.iter().try_for_each(|op| {
+        let (method, path) = op.request();
+        auth.check(&method, &format!("{}{}", mount, path), headers)
+    })
+}
+
+/// 在副本上依次执行所有操作，全部成功且通过schema校验后才替换json_obj并写入日志
+/// 调用时应持有data的写锁
+pub fn execute(
+    data: &Database,
+    mode: RouteMode,
+    json_obj: &mut Value,
+    ops: Vec<Operation>,
+) -> Result<Vec<Value>, Error> {
+    let total = ops.len();
+    let mut working = json_obj.clone();
+    let mut results = vec![];
+    let mut entries = vec![];
+    let mut events = vec![];
+    for (index, op) in ops.into_iter().enumerate() {
+        match apply(data, mode, &mut working, op) {
+            Ok(applied) => {
+                results.push(applied.result);
+                entries.extend(applied.entry);
+                events.extend(applied.event);
+            }
+            Err(e) => {
+                results.push(json!({"status": e.status().as_u16(), "error": e.problem()}));
+                results.resize(total, json!({"status": 424}));
+                return Err(Error::BatchRolledBack {
+                    index: Some(index),
+                    cause: Box::new(e),
+                    results,
+                });
+            }
+        }
+    }
+    let violations = validate(data, &working, &entries);
+    if !violations.is_empty() {
+        return Err(Error::BatchRolledBack {
+            index: None,
+            cause: Box::new(Error::SchemaViolation { errors: violations }),
+            results,
+        });
+    }
+    if !entries.is_empty() {
+        *json_obj = working;
+        data.record_batch(entries, events);
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+
+    fn ops(value: Value) -> Vec<Operation> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_commit() {
+        let data = Database::from_value(String::new(), json!({}));
+        let mut json_obj = json!({"posts": [{"id": 1, "title": "a"}], "profile": {}});
+        let mut receiver = data.watchers.subscribe(vec![]);
+        let results = execute(
+            &data,
+            RouteMode::Collection,
+            &mut json_obj,
+            ops(json!([
+                {"op": "insert", "path": "/posts/-", "value": {"title": "b"}},
+                {"op": "patch", "path": "/posts/1", "value": {"title": "c"}},
+                {"op": "insert", "path": "/profile/name", "value": "x"},
+                {"op": "get", "path": "/posts/2"},
+                {"op": "delete", "path": "/posts/1"}
+            ])),
+        )
+        .unwrap();
+        assert_eq!(
+            json_obj,
+            json!({"posts": [{"id": 2, "title": "b"}], "profile": {"name": "x"}})
+        );
+        assert_eq!(
+            results[0],
+            json!({"status": 201, "path": "/posts/1", "value": {"id": 2, "title": "b"}})
+        );
+        assert_eq!(results[3]["value"], json!({"id": 2, "title": "b"}));
+        assert_eq!(results[4], json!({"status": 204, "path": "/posts/0"}));
+
+        // 每个修改各有一个事件
+        let mut ops = vec![];
+        while let Some(Some(event)) = receiver.next().now_or_never() {
+            ops.push(event.op);
+        }
+        assert_eq!(ops, vec!["insert", "merge_patch", "insert", "delete"]);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let data = Database::from_value(String::new(), json!({}));
+        let mut json_obj = json!({"posts": [{"name": "a"}]});
+        let e = execute(
+            &data,
+            RouteMode::Pointer,
+            &mut json_obj,
+            ops(json!([
+                {"op": "insert", "path": "/posts/-", "value": {"name": "b"}},
+                {"op": "delete", "path": "/posts/5"},
+                {"op": "delete", "path": "/posts/0"}
+            ])),
+        )
+        .unwrap_err();
+        assert_eq!(e.status().as_u16(), 422);
+        let problem = e.problem();
+        assert_eq!(problem["code"], "batch_rolled_back");
+        assert_eq!(problem["index"], 1);
+        assert_eq!(problem["cause"]["code"], "index_out_of_bounds");
+        assert_eq!(problem["results"][0]["status"], 201);
+        assert_eq!(problem["results"][1]["status"], 422);
+        assert_eq!(problem["results"][2], json!({"status": 424}));
+        assert_eq!(json_obj, json!({"posts": [{"name": "a"}]}));
+
+        // 不符合db中的$schema时同样回滚
+        let mut json_obj = json!({"$schema": {"posts": {"maxItems": 1}}, "posts": []});
+        let e = execute(
+            &data,
+            RouteMode::Pointer,
+            &mut json_obj,
+            ops(json!([
+                {"op": "insert", "path": "/posts/-", "value": 1},
+                {"op": "insert", "path": "/posts/-", "value": 2}
+            ])),
+        )
+        .unwrap_err();
+        assert_eq!(e.problem()["cause"]["code"], "schema_violation");
+        assert_eq!(json_obj["posts"], json!([]));
+
+        for op in [
+            json!({"op": "delete", "path": ""}),
+            json!({"op": "insert", "path": "", "value": {}}),
+        ] {
+            let e =
+                execute(&data, RouteMode::Pointer, &mut json_obj, ops(json!([op]))).unwrap_err();
+            assert_eq!(e.problem()["cause"]["code"], "root_not_allowed");
+        }
+    }
+
+    #[test]
+    fn test_authorize() {
+        let file =
+            std::env::temp_dir().join(format!("mockrs-batch-auth-{}.json", std::process::id()));
+        let config = json!({
+            "api_keys": [{"key": "k1"}],
+            "rules": [
+                {"path": "/api/posts/*", "methods": ["DELETE"], "role": "admin"},
+                {"path": "/api/*", "access": "read_only"}
+            ]
+        });
+        std::fs::write(&file, config.to_string()).unwrap();
+        let auth = Auth::load(file.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        let mut headers = HeaderMap::new();
+        let code = |headers: &HeaderMap, value: Value| {
+            authorize(&auth, headers, "/api", &ops(value)).map_err(|e| e.code())
+        };
+
+        assert_eq!(
+            code(&headers, json!([{"op": "get", "path": "/posts/1"}])),
+            Ok(())
+        );
+        // 单个请求会被拒绝的写入，在批量操作中同样被拒绝
+        let write = json!([
+            {"op": "get", "path": "/posts/1"},
+            {"op": "insert", "path": "/posts/-", "value": {}}
+        ]);
+        assert_eq!(code(&headers, write.clone()), Err("unauthorized"));
+        headers.insert("x-api-key".parse().unwrap(), "k1".parse().unwrap());
+        assert_eq!(code(&headers, write), Ok(()));
+        let delete = json!([{"op": "delete", "path": "/posts/1"}]);
+        assert_eq!(code(&headers, delete), Err("forbidden"));
+    }
+}
@@ -0,0 +1,474 @@
+//! 录制与回放模块
+//! record 子命令以反向代理方式运行，将请求原样转发给目标服务，并把每一对请求/响应追加到cassette文件，
+//! 每条记录占一行，追加时只覆盖文件末尾的 ]}，不重写整个文件。
+//! replay 子命令加载cassette文件，按 method + path + query（可选请求体）匹配请求并返回录制的响应。
+//! 同一请求录制了多次时按录制顺序依次返回，全部返回过之后一直返回最后一次的响应。
+//! 请求体与响应体在content-type为json时按json保存，其余按字符串保存。
+//! ```json
+//! {
+//!   "interactions": [
+//!     {
+//!       "request": {"method": "GET", "path": "/posts", "query": "_page=1", "headers": {}, "body": null},
+//!       "response": {"status": 200, "headers": {"content-type": "application/json"}, "body": [{"id": 1}]}
+//!     }
+//!   ]
+//! }
+//! ```
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use actix_web::client::Client;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error;
+use crate::journal;
+
+/// 上游响应体的大小上限
+const BODY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// record 写出的cassette文件的开头与结尾，追加记录时覆盖结尾
+const CASSETTE_HEAD: &str = "{\n  \"interactions\": [";
+const CASSETTE_TAIL: &str = "\n  ]\n}\n";
+
+/// 逐跳头部及由代理重新计算的头部，不转发也不录制
+const SKIP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+    "content-encoding",
+    "host",
+];
+
+fn record_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter(|(name, _)| !SKIP_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+fn is_json(headers: &BTreeMap<String, String>) -> bool {
+    headers
+        .get("content-type")
+        .is_some_and(|ct| ct.contains("json"))
+}
+
+/// 按content-type将body转为json值保存，空body保存为null
+fn encode_body(headers: &BTreeMap<String, String>, body: &[u8]) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    if is_json(headers) {
+        if let Ok(value) = serde_json::from_slice(body) {
+            return value;
+        }
+    }
+    Value::String(String::from_utf8_lossy(body).into_owned())
+}
+
+fn decode_body(headers: &BTreeMap<String, String>, body: &Value) -> Vec<u8> {
+    match body {
+        Value::Null => vec![],
+        Value::String(text) if !is_json(headers) => text.clone().into_bytes(),
+        body => body.to_string().into_bytes(),
+    }
+}
+
+/// 查询参数排序后比较，与参数顺序无关
+fn same_query(a: &str, b: &str) -> bool {
+    let parse = |query: &str| {
+        web::Query::<Vec<(String, String)>>::from_query(query).map(|params| {
+            let mut params = params.into_inner();
+            params.sort();
+            params
+        })
+    };
+    match (parse(a), parse(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    method: String,
+    path: String,
+    #[serde(default)]
+    query: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Value,
+}
+
+impl RecordedRequest {
+    fn from_req(req: &HttpRequest, body: &[u8]) -> RecordedRequest {
+        let headers = record_headers(req.headers());
+        RecordedRequest {
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            query: req.query_string().to_string(),
+            body: encode_body(&headers, body),
+            headers,
+        }
+    }
+
+    fn matches(&self, other: &RecordedRequest, match_body: bool) -> bool {
+        self.method.eq_ignore_ascii_case(&other.method)
+            && self.path == other.path
+            && same_query(&self.query, &other.query)
+            && (!match_body || self.body == other.body)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Value,
+}
+
+impl RecordedResponse {
+    fn response(&self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let mut resp = HttpResponse::build(status);
+        for (name, value) in self.headers.iter() {
+            resp.header(name.as_str(), value.as_str());
+        }
+        resp.body(decode_body(&self.headers, &self.body))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    #[serde(default)]
+    interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(file: &str) -> std::io::Result<Cassette> {
+        let content = fs::read_to_string(file)?;
+        serde_json::from_str(&content).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid cassette file {}: {}", file, e),
+            )
+        })
+    }
+}
+
+/// 一条记录在cassette文件中的内容，first为false时以逗号开头
+fn cassette_entry(interaction: &str, first: bool) -> String {
+    format!("{}\n    {}", if first { "" } else { "," }, interaction)
+}
+
+/// record 模式的共享状态
+pub struct Recorder {
+    target: String,
+    file: String,
+    // 打开的cassette文件及其中的记录数
+    writer: Mutex<(File, usize)>,
+}
+
+impl Recorder {
+    /// cassette文件已存在时保留其中的记录，按追加所需的格式重写一次
+    pub fn new(target: &str, file: &str) -> std::io::Result<Recorder> {
+        let cassette = if Path::new(file).exists() {
+            Cassette::load(file)?
+        } else {
+            Cassette::default()
+        };
+        let mut content = CASSETTE_HEAD.to_string();
+        for (idx, interaction) in cassette.interactions.iter().enumerate() {
+            let interaction = serde_json::to_string(interaction).unwrap();
+            content.push_str(&cassette_entry(&interaction, idx == 0));
+        }
+        content.push_str(CASSETTE_TAIL);
+        journal::write_atomic(file, content.as_bytes())?;
+        let writer = OpenOptions::new().write(true).open(file)?;
+        Ok(Recorder {
+            target: target.trim_end_matches('/').to_string(),
+            file: file.to_string(),
+            writer: Mutex::new((writer, cassette.interactions.len())),
+        })
+    }
+
+    fn record(&self, interaction: &Interaction) -> Result<(), error::Error> {
+        let interaction = serde_json::to_string(interaction).unwrap();
+        let mut writer = self.writer.lock().unwrap();
+        let (file, count) = &mut *writer;
+        let content = format!(
+            "{}{}",
+            cassette_entry(&interaction, *count == 0),
+            CASSETTE_TAIL
+        );
+        file.seek(SeekFrom::End(-(CASSETTE_TAIL.len() as i64)))
+            .and_then(|_| file.write_all(content.as_bytes()))
+            .map_err(|e| error::Error::Io {
+                detail: format!("can not write cassette {}: {}", self.file, e),
+            })?;
+        *count += 1;
+        Ok(())
+    }
+}
+
+/// 转发请求到目标服务并录制
+pub async fn proxy(
+    req: HttpRequest,
+    body: web::Bytes,
+    client: web::Data<Client>,
+    recorder: web::Data<Recorder>,
+) -> HttpResponse {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let url = format!("{}{}", recorder.target, path);
+    let mut upstream = client.request_from(url.as_str(), req.head());
+    for name in SKIP_HEADERS {
+        upstream.headers_mut().remove(*name);
+    }
+    // 要求上游返回未压缩的内容，便于录制为可读的文本
+    upstream.headers_mut().remove(header::ACCEPT_ENCODING);
+
+    let mut res = match upstream.send_body(body.clone()).await {
+        Ok(res) => res,
+        Err(e) => {
+            return error::Error::ProxyFailed {
+                url,
+                detail: e.to_string(),
+            }
+            .error_response()
+        }
+    };
+    let res_body = match res.body().limit(BODY_LIMIT).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return error::Error::ProxyFailed {
+                url,
+                detail: e.to_string(),
+            }
+            .error_response()
+        }
+    };
+
+    let headers = record_headers(res.headers());
+    let interaction = Interaction {
+        request: RecordedRequest::from_req(&req, &body),
+        response: RecordedResponse {
+            status: res.status().as_u16(),
+            body: encode_body(&headers, &res_body),
+            headers,
+        },
+    };
+    info!("Record {} {}", req.method(), path);
+
+    let mut resp = HttpResponse::build(res.status());
+    for (name, value) in interaction.response.headers.iter() {
+        resp.header(name.as_str(), value.as_str());
+    }
+    if let Err(e) = recorder.record(&interaction) {
+        error!("{}", e);
+    }
+    resp.body(res_body)
+}
+
+/// replay 模式的共享状态
+pub struct Player {
+    cassette: Cassette,
+    served: Mutex<Vec<bool>>,
+    match_body: bool,
+}
+
+impl Player {
+    pub fn new(cassette: Cassette, match_body: bool) -> Player {
+        let served = vec![false; cassette.interactions.len()];
+        Player {
+            cassette,
+            served: Mutex::new(served),
+            match_body,
+        }
+    }
+
+    /// 返回第一条未返回过的匹配记录，均已返回过时返回最后一条匹配记录
+    fn find(&self, request: &RecordedRequest) -> Option<&RecordedResponse> {
+        let matched: Vec<usize> = self
+            .cassette
+            .interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, it)| it.request.matches(request, self.match_body))
+            .map(|(idx, _)| idx)
+            .collect();
+        let mut served = self.served.lock().unwrap();
+        let idx = matched
+            .iter()
+            .find(|idx| !served[**idx])
+            .or_else(|| matched.last())?;
+        served[*idx] = true;
+        Some(&self.cassette.interactions[*idx].response)
+    }
+}
+
+pub fn replay(req: HttpRequest, body: web::Bytes, player: web::Data<Player>) -> HttpResponse {
+    let request = RecordedRequest::from_req(&req, &body);
+    match player.find(&request) {
+        Some(response) => response.response(),
+        None => error::Error::NoRecordedInteraction {
+            method: request.method,
+            path: request.path,
+            query: request.query,
+        }
+        .error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(method: &str, path: &str, query: &str, body: Value) -> RecordedRequest {
+        RecordedRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            headers: BTreeMap::new(),
+            body,
+        }
+    }
+
+    fn interaction(request: RecordedRequest, status: u16) -> Interaction {
+        Interaction {
+            request,
+            response: RecordedResponse {
+                status,
+                headers: BTreeMap::new(),
+                body: Value::Null,
+            },
+        }
+    }
+
+    #[test]
+    fn test_match() {
+        let cassette = Cassette {
+            interactions: vec![
+                interaction(request("GET", "/posts", "a=1&b=2", Value::Null), 200),
+                interaction(request("GET", "/posts", "a=1&b=2", Value::Null), 201),
+                interaction(request("POST", "/posts", "", json!({"title": "a"})), 202),
+            ],
+        };
+        let player = Player::new(cassette.clone(), false);
+        let get = request("get", "/posts", "b=2&a=1", Value::Null);
+        assert_eq!(player.find(&get).unwrap().status, 200);
+        assert_eq!(player.find(&get).unwrap().status, 201);
+        assert_eq!(player.find(&get).unwrap().status, 201);
+        assert!(player
+            .find(&request("GET", "/posts", "a=1", Value::Null))
+            .is_none());
+
+        let post = request("POST", "/posts", "", json!({"title": "b"}));
+        assert_eq!(player.find(&post).unwrap().status, 202);
+        let player = Player::new(cassette, true);
+        assert!(player.find(&post).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_record_replay() {
+        let upstream = test::start(|| {
+            App::new().route(
+                "/posts",
+                web::post().to(|body: web::Json<Value>| {
+                    HttpResponse::Created().json(json!({"id": 1, "title": body["title"]}))
+                }),
+            )
+        });
+        let file =
+            std::env::temp_dir().join(format!("mockrs-cassette-{}.json", std::process::id()));
+        let file = file.to_str().unwrap();
+        let _ = fs::remove_file(file);
+
+        let recorder = web::Data::new(Recorder::new(&upstream.url(""), file).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .data(Client::default())
+                .app_data(recorder)
+                .default_service(web::route().to(proxy)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/posts?x=1")
+            .set_json(&json!({"title": "a"}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            test::read_body(resp).await,
+            web::Bytes::from(r#"{"id":1,"title":"a"}"#)
+        );
+
+        // 追加的记录与已有的记录都保留，文件仍是合法的json
+        let req = test::TestRequest::post()
+            .uri("/posts")
+            .set_json(&json!({"title": "b"}))
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let cassette = Cassette::load(file).unwrap();
+        assert_eq!(cassette.interactions.len(), 2);
+        Recorder::new(&upstream.url(""), file).unwrap();
+        let cassette = Cassette::load(file).unwrap();
+        let _ = fs::remove_file(file);
+        assert_eq!(cassette.interactions.len(), 2);
+        assert_eq!(cassette.interactions[1].request.body, json!({"title": "b"}));
+        assert_eq!(cassette.interactions[0].request.body, json!({"title": "a"}));
+        assert_eq!(
+            cassette.interactions[0].response.body,
+            json!({"id": 1, "title": "a"})
+        );
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Player::new(cassette, true)))
+                .default_service(web::route().to(replay)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/posts?x=1")
+            .set_json(&json!({"title": "a"}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body, json!({"id": 1, "title": "a"}));
+
+        let req = test::TestRequest::get().uri("/posts").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["code"], "no_recorded_interaction");
+    }
+}
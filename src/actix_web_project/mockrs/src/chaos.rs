@@ -0,0 +1,252 @@
+//! 故障注入中间件
+//! 包裹通用的 /* 资源，按配置对响应注入延迟、5xx错误、连接重置、截断响应体、慢速分块发送。
+//! 配置可在启动时通过 --chaos-* 参数给出，运行时通过 /_actions/chaos 查看和修改。
+use std::pin::Pin;
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_rt::time;
+use actix_web::dev::{
+    Body, ResponseBody, Service, ServiceRequest, ServiceResponse, SizedStream, Transform,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, ResponseError};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::stream::{self, Stream, StreamExt};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::error;
+
+/// 故障注入配置，比例均为百分比
+#[derive(StructOpt, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosConfig {
+    /// Only inject faults into requests whose path starts with this prefix
+    #[structopt(long = "chaos-prefix")]
+    pub prefix: Option<String>,
+
+    /// Fixed latency in milliseconds added to every response
+    #[structopt(long = "chaos-latency", default_value = "0")]
+    pub latency: u64,
+
+    /// Random extra latency in milliseconds, between 0 and this value
+    #[structopt(long = "chaos-jitter", default_value = "0")]
+    pub jitter: u64,
+
+    /// Percentage of requests answered with a 5xx error
+    #[structopt(long = "chaos-error-rate", default_value = "0")]
+    pub error_rate: f64,
+
+    /// Status code of injected errors, a random 5xx when not set
+    #[structopt(long = "chaos-error-status")]
+    pub error_status: Option<u16>,
+
+    /// Percentage of responses whose connection is reset before the body is sent
+    #[structopt(long = "chaos-reset-rate", default_value = "0")]
+    pub reset_rate: f64,
+
+    /// Percentage of responses whose body is cut in half
+    #[structopt(long = "chaos-truncate-rate", default_value = "0")]
+    pub truncate_rate: f64,
+
+    /// Send bodies in small chunks with this many milliseconds between them, 0 disables
+    #[structopt(long = "chaos-drip", default_value = "0")]
+    pub drip: u64,
+
+    /// Chunk size in bytes of slow-drip bodies
+    #[structopt(long = "chaos-drip-chunk", default_value = "16")]
+    pub drip_chunk: usize,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            prefix: None,
+            latency: 0,
+            jitter: 0,
+            error_rate: 0.0,
+            error_status: None,
+            reset_rate: 0.0,
+            truncate_rate: 0.0,
+            drip: 0,
+            drip_chunk: 16,
+        }
+    }
+}
+
+/// 单次请求要注入的故障
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fault {
+    Error(StatusCode),
+    Reset,
+    Truncate,
+    Drip(Duration, usize),
+    None,
+}
+
+const ERROR_STATUS: &[u16] = &[500, 502, 503, 504];
+
+fn hit(rate: f64) -> bool {
+    rate > 0.0 && rand::thread_rng().gen_range(0.0, 100.0) < rate
+}
+
+impl ChaosConfig {
+    fn applies(&self, path: &str) -> bool {
+        self.prefix
+            .as_ref()
+            .is_none_or(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    fn delay(&self) -> Duration {
+        let jitter = if self.jitter > 0 {
+            rand::thread_rng().gen_range(0, self.jitter + 1)
+        } else {
+            0
+        };
+        Duration::from_millis(self.latency + jitter)
+    }
+
+    fn roll(&self) -> Fault {
+        if hit(self.error_rate) {
+            let status = self.error_status.unwrap_or_else(|| {
+                ERROR_STATUS[rand::thread_rng().gen_range(0, ERROR_STATUS.len())]
+            });
+            Fault::Error(StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+        } else if hit(self.reset_rate) {
+            Fault::Reset
+        } else if hit(self.truncate_rate) {
+            Fault::Truncate
+        } else if self.drip > 0 {
+            Fault::Drip(Duration::from_millis(self.drip), self.drip_chunk.max(1))
+        } else {
+            Fault::None
+        }
+    }
+}
+
+type BodyChunks = Pin<Box<dyn Stream<Item = Result<web::Bytes, Error>>>>;
+
+fn reset_error() -> Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionReset,
+        "chaos: connection reset",
+    )
+    .into()
+}
+
+/// 按故障类型替换响应体，仍声明完整长度，使客户端能察觉到数据缺失
+fn inject(fault: Fault, res: ServiceResponse<Body>) -> ServiceResponse<Body> {
+    res.map_body(|_, body| {
+        let bytes = match body {
+            ResponseBody::Body(Body::Bytes(bytes)) | ResponseBody::Other(Body::Bytes(bytes)) => {
+                bytes
+            }
+            // 空响应体无需处理
+            other => return other,
+        };
+        let size = bytes.len() as u64;
+        let chunks: BodyChunks = match fault {
+            Fault::Reset => Box::pin(stream::once(async { Err(reset_error()) })),
+            Fault::Truncate => {
+                let half = bytes.slice(..bytes.len() / 2);
+                // 稍作等待再断开，确保前半部分已经发出
+                Box::pin(stream::once(async { Ok(half) }).chain(stream::once(async {
+                    time::delay_for(Duration::from_millis(50)).await;
+                    Err(reset_error())
+                })))
+            }
+            Fault::Drip(interval, chunk) => {
+                Box::pin(stream::unfold(bytes, move |mut rest| async move {
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    time::delay_for(interval).await;
+                    let next = rest.split_to(chunk.min(rest.len()));
+                    Some((Ok(next), rest))
+                }))
+            }
+            _ => return ResponseBody::Body(Body::Bytes(bytes)),
+        };
+        ResponseBody::Body(Body::from_message(SizedStream::new(size, chunks)))
+    })
+}
+
+/// 故障注入中间件，配置在所有worker间共享
+pub struct Chaos {
+    config: web::Data<RwLock<ChaosConfig>>,
+}
+
+impl Chaos {
+    pub fn new(config: web::Data<RwLock<ChaosConfig>>) -> Chaos {
+        Chaos { config }
+    }
+}
+
+impl<S> Transform<S> for Chaos
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ChaosMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ChaosMiddleware {
+            service,
+            config: self.config.clone(),
+        })
+    }
+}
+
+pub struct ChaosMiddleware<S> {
+    service: S,
+    config: web::Data<RwLock<ChaosConfig>>,
+}
+
+impl<S> Service for ChaosMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let config = self.config.read().unwrap().clone();
+        if !config.applies(req.path()) {
+            return Box::pin(self.service.call(req));
+        }
+        let delay = config.delay();
+        match config.roll() {
+            // 注入错误时不再调用实际的处理函数
+            Fault::Error(status) => Box::pin(async move {
+                time::delay_for(delay).await;
+                Ok(req.into_response(error::Error::ChaosInjected { status }.error_response()))
+            }),
+            fault => {
+                let fut = self.service.call(req);
+                Box::pin(async move {
+                    time::delay_for(delay).await;
+                    let res = fut.await?;
+                    Ok(match fault {
+                        Fault::None => res,
+                        fault => inject(fault, res),
+                    })
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! 多数据库模块
+//! db_file 为目录时，目录中每个 <name>.json(或yaml toml json5)文件都是一个独立的数据库，挂载在 /db/<name>/ 下，
+//! 例如 /db/users/posts/0 访问 users.json 中的 /posts/0。
+//! db_file 为文件时该文件是挂载在 / 的默认数据库，运行时创建的数据库同样挂载在 /db/<name>/ 下。
+//! 运行时通过 /_actions/databases 创建、克隆、重置、删除数据库，各数据库的数据互不影响：
+//!     GET     /_actions/databases                 列出所有数据库
+//!     POST    /_actions/databases                 创建 {"name": "suite1", "from": "users"}，from为要克隆的数据库，或用data给出初始数据
+//!     POST    /_actions/databases/<name>/reset    恢复为加载或创建时的数据
+//!     DELETE  /_actions/databases/<name>          删除数据库，不会删除磁盘上的文件
+//! /_actions 下的 flush openapi.json watch 用 ?db=<name> 指定数据库。
+//! 指定 --api-prefix 时以上数据库路径都位于该前缀下，如 /api/posts/0、/api/db/users/posts/0，/_actions 不变。
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures::future::{err, ok, Ready};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::api::QueryKeys;
+use crate::db::Database;
+use crate::error::Error;
+use crate::format::Format;
+use crate::schema::Schema;
+
+/// 命名数据库的路径前缀
+const PREFIX: &str = "/db/";
+
+/// 数据库列表中的一项
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseInfo {
+    name: String,
+    path: String,
+    persistent: bool,
+}
+
+pub struct Databases {
+    // 挂载在 / 的默认数据库，db_file为目录时没有
+    default: Option<Arc<Database>>,
+    named: RwLock<BTreeMap<String, Arc<Database>>>,
+    schema: Option<Schema>,
+    // 数据库路由的前缀，如 /api，为空时数据库挂载在 /
+    api_prefix: String,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Databases {
+    /// 加载db文件或db目录，指定journal时，db_file为目录则journal也应为目录，每个数据库使用 <name>.journal
+    /// format未指定时按扩展名判断，无法识别的按json解析；目录中只加载扩展名可识别的文件
+    pub fn load(
+        db_file: &str,
+        journal: Option<&str>,
+        schema: Option<Schema>,
+        format: Option<Format>,
+    ) -> std::io::Result<Databases> {
+        let open = |file: &String, journal: Option<String>, format: Format| {
+            let mut db = match &journal {
+                Some(journal) => Database::with_journal(file, journal, format)?,
+                None => Database::new(file, format)?,
+            };
+            if let Some(schema) = &schema {
+                db.set_schema(schema.clone());
+            }
+            // 已有数据不符合schema时只给出警告，之后的写入仍会被校验
+            for violation in db.validate(&db.data.read(), None) {
+                warn!("{} {}: {}", file, violation.pointer, violation.message);
+            }
+            Ok::<_, std::io::Error>(db)
+        };
+        let mut databases = Databases {
+            default: None,
+            named: RwLock::new(BTreeMap::new()),
+            schema: schema.clone(),
+            api_prefix: String::new(),
+        };
+        if !Path::new(db_file).is_dir() {
+            let format = format
+                .or_else(|| Format::from_path(db_file))
+                .unwrap_or(Format::Json);
+            let db = open(&db_file.to_string(), journal.map(str::to_string), format)?;
+            databases.default = Some(Arc::new(db));
+            return Ok(databases);
+        }
+        if let Some(journal) = journal {
+            fs::create_dir_all(journal)?;
+        }
+        for entry in fs::read_dir(db_file)? {
+            let path = entry?.path();
+            let file = path.to_string_lossy().to_string();
+            let detected = match Format::from_path(&file) {
+                Some(detected) => detected,
+                None => continue,
+            };
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) if valid_name(name) => name.to_string(),
+                _ => {
+                    warn!("Skip db file with invalid name: {:?}", path);
+                    continue;
+                }
+            };
+            if databases.get(&name).is_some() {
+                warn!("Skip {:?}, database {} is already mounted", path, name);
+                continue;
+            }
+            let journal = journal.map(|dir| format!("{}/{}.journal", dir, name));
+            let db = open(&file, journal, format.unwrap_or(detected))?;
+            info!("Mount {} at {}{}", file, PREFIX, name);
+            databases.mount(name, db);
+        }
+        Ok(databases)
+    }
+
+    /// 设置数据库路由的前缀，prefix应以 / 开头且不以 / 结尾
+    pub fn set_api_prefix(&mut self, prefix: String) {
+        self.api_prefix = prefix;
+    }
+
+    fn mount(&self, name: String, db: Database) {
+        self.named.write().unwrap().insert(name, Arc::new(db));
+    }
+
+    /// 所有数据库，用于定期快照
+    pub fn all(&self) -> Vec<Arc<Database>> {
+        self.default
+            .iter()
+            .cloned()
+            .chain(self.named.read().unwrap().values().cloned())
+            .collect()
+    }
+
+    /// 所有数据库及其挂载路径，用于统计数据量
+    pub fn mounted(&self) -> Vec<(String, Arc<Database>)> {
+        let root = if self.api_prefix.is_empty() {
+            "/"
+        } else {
+            &self.api_prefix
+        };
+        self.default
+            .iter()
+            .map(|db| (root.to_string(), db.clone()))
+            .chain(
+                self.named
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(name, db)| (self.path(name), db.clone())),
+            )
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Database>> {
+        self.named.read().unwrap().get(name).cloned()
+    }
+
+    /// 命名数据库的路由路径
+    pub fn path(&self, name: &str) -> String {
+        format!("{}{}{}", self.api_prefix, PREFIX, name)
+    }
+
+    pub fn list(&self) -> Vec<DatabaseInfo> {
+        self.named
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, db)| DatabaseInfo {
+                name: name.clone(),
+                path: self.path(name),
+                persistent: db.is_persistent(),
+            })
+            .collect()
+    }
+
+    /// 创建数据库，from给出时克隆该数据库的当前数据，否则使用data，都没有时为空对象
+    pub fn create(&self, name: &str, from: Option<&str>, data: Option<Value>) -> Result<(), Error> {
+        if !valid_name(name) {
+            return Err(Error::InvalidName {
+                name: name.to_string(),
+                detail: "database name may only contain letters, digits, - and _".to_string(),
+            });
+        }
+        let data = match from {
+            Some(from) => match self.get(from) {
+                Some(db) => db.data.read().clone(),
+                None => return Err(not_found(from)),
+            },
+            None => data.unwrap_or_else(|| json!({})),
+        };
+        let mut named = self.named.write().unwrap();
+        if named.contains_key(name) {
+            return Err(Error::DatabaseExists {
+                name: name.to_string(),
+            });
+        }
+        let mut db = Database::from_value(String::new(), data);
+        if let Some(schema) = &self.schema {
+            db.set_schema(schema.clone());
+        }
+        named.insert(name.to_string(), Arc::new(db));
+        Ok(())
+    }
+
+    /// 恢复为加载或创建时的数据
+    pub fn reset(&self, name: &str) -> Result<(), Error> {
+        match self.get(name) {
+            Some(db) => {
+                db.reset(db.fixtures.initial());
+                Ok(())
+            }
+            None => Err(not_found(name)),
+        }
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        match self.named.write().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(not_found(name)),
+        }
+    }
+
+    /// 数据库路由下的路径所在的数据库、其挂载路径及剩余的路径，数据库不存在或不在api前缀下时为None
+    pub fn route_of<'p>(&self, path: &'p str) -> Option<(String, Arc<Database>, &'p str)> {
+        let rest = match path.strip_prefix(self.api_prefix.as_str()) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => return None,
+        };
+        match rest.strip_prefix(PREFIX) {
+            Some(named) => {
+                let (name, rest) = named.split_at(named.find('/').unwrap_or(named.len()));
+                Some((self.path(name), self.get(name)?, rest))
+            }
+            None => Some((self.api_prefix.clone(), self.default.clone()?, rest)),
+        }
+    }
+
+    /// 按请求选择数据库：/db/<name>/... 选择命名数据库，/_actions 下按 ?db=<name> 选择，其余为默认数据库
+    fn select(&self, req: &HttpRequest) -> Result<Db, Error> {
+        // 去掉api前缀，前缀之外的路径(如 /graphql)同样使用默认数据库
+        let (api, path) = match req.path().strip_prefix(self.api_prefix.as_str()) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => (self.api_prefix.as_str(), rest),
+            _ => ("", req.path()),
+        };
+        if let Some(rest) = path.strip_prefix(PREFIX) {
+            // 数据库名有误或已被删除时报错，不落到默认数据库
+            let name = rest.split('/').next().unwrap_or("");
+            return match self.get(name) {
+                Some(db) => {
+                    let prefix = format!("{}{}{}", api, PREFIX, name);
+                    Ok(Db {
+                        db,
+                        mount: prefix.clone(),
+                        prefix,
+                    })
+                }
+                None => Err(not_found(name)),
+            };
+        }
+        if path.starts_with("/_actions/") {
+            let params = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+                .map(|params| params.into_inner())
+                .unwrap_or_default();
+            if let Some((_, name)) = params.iter().find(|(key, _)| key == "db") {
+                return match self.get(name) {
+                    Some(db) => Ok(Db {
+                        db,
+                        prefix: String::new(),
+                        mount: self.path(name),
+                    }),
+                    None => Err(not_found(name)),
+                };
+            }
+        }
+        match &self.default {
+            Some(db) => Ok(Db {
+                db: db.clone(),
+                prefix: api.to_string(),
+                // /_actions 下为默认数据库实际所在的api前缀
+                mount: if api.is_empty() {
+                    self.api_prefix.clone()
+                } else {
+                    api.to_string()
+                },
+            }),
+            None => Err(Error::NoDefaultDatabase {
+                databases: self.named.read().unwrap().keys().cloned().collect(),
+            }),
+        }
+    }
+}
+
+fn not_found(name: &str) -> Error {
+    Error::DatabaseNotFound {
+        name: name.to_string(),
+    }
+}
+
+/// 处理函数使用的数据库，由请求路径选择
+pub struct Db {
+    db: Arc<Database>,
+    prefix: String,
+    // 数据库路由的路径前缀，/_actions 下prefix为空，mount仍为数据库实际挂载的位置
+    mount: String,
+}
+
+impl Db {
+    /// 去掉 /db/<name> 前缀后的请求路径
+    pub fn keys(&self, req: &HttpRequest) -> QueryKeys {
+        QueryKeys::from_path(&req.path()[self.prefix.len()..])
+    }
+
+    /// 数据库路由实际所在的路径前缀
+    pub fn mount(&self) -> &str {
+        &self.mount
+    }
+}
+
+impl Deref for Db {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl FromRequest for Db {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Db, actix_web::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let databases = match req.app_data::<web::Data<Databases>>() {
+            Some(databases) => databases,
+            None => {
+                return err(actix_web::error::ErrorInternalServerError(
+                    "databases are not configured",
+                ))
+            }
+        };
+        match databases.select(req) {
+            Ok(db) => ok(db),
+            Err(e) => err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn databases() -> Databases {
+        let databases = Databases {
+            default: Some(Arc::new(Database::from_value(
+                String::new(),
+                json!({"posts": []}),
+            ))),
+            named: RwLock::new(BTreeMap::new()),
+            schema: None,
+            api_prefix: String::new(),
+        };
+        databases
+            .create("users", None, Some(json!({"users": [{"id": 1}]})))
+            .unwrap();
+        databases
+    }
+
+    fn select(databases: &Databases, uri: &str) -> Result<Db, Error> {
+        databases.select(&TestRequest::with_uri(uri).to_http_request())
+    }
+
+    #[test]
+    fn test_databases() {
+        let databases = databases();
+        let status = |res: Result<(), Error>| res.unwrap_err().status().as_u16();
+        assert_eq!(status(databases.create("users", None, None)), 409);
+        assert_eq!(status(databases.create("a/b", None, None)), 400);
+        assert_eq!(status(databases.create("copy", Some("nope"), None)), 404);
+        databases.create("copy", Some("users"), None).unwrap();
+
+        let req = TestRequest::with_uri("/db/copy/users/0").to_http_request();
+        let db = databases.select(&req).unwrap();
+        assert_eq!(db.keys(&req).json_ptr(), "/users/0");
+        *db.data.write() = json!({});
+        // 克隆得到的数据库互不影响
+        let users = select(&databases, "/db/users").unwrap();
+        assert_eq!(users.data.read()["users"][0]["id"], 1);
+
+        databases.reset("copy").unwrap();
+        let copy = select(&databases, "/_actions/flush?db=copy").unwrap();
+        assert_eq!(copy.data.read()["users"][0]["id"], 1);
+
+        let e = select(&databases, "/db/other/posts").err().unwrap();
+        assert_eq!(e.code(), "database_not_found");
+        assert!(select(&databases, "/_actions/flush?db=other").is_err());
+
+        databases.remove("copy").unwrap();
+        assert!(databases.remove("copy").is_err());
+        assert_eq!(databases.list().len(), 1);
+    }
+
+    #[test]
+    fn test_api_prefix() {
+        let mut databases = databases();
+        databases.set_api_prefix("/api".to_string());
+        let keys = |uri: &str| {
+            let req = TestRequest::with_uri(uri).to_http_request();
+            databases.select(&req).unwrap().keys(&req).json_ptr()
+        };
+        assert_eq!(keys("/api/posts/0"), "/posts/0");
+        assert_eq!(keys("/api"), "");
+        assert_eq!(keys("/api/db/users/users/0"), "/users/0");
+        // 不在前缀下的路径不去掉前缀
+        assert_eq!(keys("/apis/posts"), "/apis/posts");
+        assert_eq!(databases.list()[0].path, "/api/db/users");
+        assert_eq!(databases.path("copy"), "/api/db/copy");
+        let mount = |uri: &str| {
+            let req = TestRequest::with_uri(uri).to_http_request();
+            databases.select(&req).unwrap().mount().to_string()
+        };
+        assert_eq!(mount("/_actions/batch"), "/api");
+        assert_eq!(mount("/_actions/batch?db=users"), "/api/db/users");
+    }
+}
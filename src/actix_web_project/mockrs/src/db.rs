@@ -2,32 +2,304 @@
 use std::fs;
 use std::sync::Mutex;
 
-use log::debug;
+use log::{debug, error, info};
 use serde_json::{json, Value};
 
 use crate::api;
+use crate::error::Error;
+use crate::fixture::Fixtures;
+use crate::format::{self, Format};
+use crate::journal::{self, Entry, Journal};
+use crate::patch;
+use crate::relation;
+use crate::schema::{self, Schema, Violation};
+use crate::store::Store;
+use crate::watch;
 
 // 自定义数据结构：数据库
 pub struct Database {
-    // 互斥锁 阻塞
-    pub data: Mutex<Value>,
+    // 读写锁 读请求可并发
+    pub data: Store,
+    // db文件路径，快照会写回该文件
+    file: String,
+    // db文件格式，快照按该格式写回
+    format: Format,
+    // 预写日志，未开启持久化时为None
+    journal: Option<Mutex<Journal>>,
+    // --schema 给出的整个db的schema
+    schema: Option<Schema>,
+    // 数据变更的订阅者
+    pub watchers: watch::Hub,
+    // 初始数据及测试夹具快照
+    pub fixtures: Fixtures,
+    // 服务自身最近一次写回db文件的内容校验和，热加载时不把这次写入当作外部修改
+    written: Mutex<Option<String>>,
 }
 
 impl Database {
-    pub fn new(file: &String) -> Database {
-        let db = fs::read_to_string(&file).expect(&format!("Unable to read file: {}", file));
-        let data = Mutex::new(serde_json::from_str(&db).expect("Parse db file error"));
-        Database { data }
+    /// 读取db文件，文件不存在或无法解析时返回错误
+    pub fn new(file: &str, format: Format) -> std::io::Result<Database> {
+        let (_, data) = format::load(file, format)?;
+        let mut db = Database::from_value(file.to_string(), data);
+        db.format = format;
+        Ok(db)
+    }
+
+    /// 由已有数据创建，不开启持久化
+    pub fn from_value(file: String, data: Value) -> Database {
+        Database {
+            fixtures: Fixtures::new(data.clone()),
+            data: Store::new(data),
+            file,
+            format: Format::Json,
+            journal: None,
+            schema: None,
+            watchers: watch::Hub::default(),
+            written: Mutex::new(None),
+        }
+    }
+
+    /// 开启持久化：读取db文件后重放日志中的修改
+    pub fn with_journal(
+        file: &str,
+        journal_file: &str,
+        format: Format,
+    ) -> std::io::Result<Database> {
+        let (db, initial) = format::load(file, format)?;
+        let mut data = initial.clone();
+        let (journal, entries) = Journal::open(journal_file, db.as_bytes()).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Unable to open journal {}: {}", journal_file, e),
+            )
+        })?;
+        let count = entries.len();
+        for entry in entries {
+            if let Err(e) = entry.apply(&mut data) {
+                error!("Replay journal entry failed: {}", e);
+            }
+        }
+        info!("Replayed {} journal entries from {:?}", count, journal_file);
+        Ok(Database {
+            data: Store::new(data),
+            file: file.to_string(),
+            format,
+            journal: Some(Mutex::new(journal)),
+            schema: None,
+            watchers: watch::Hub::default(),
+            fixtures: Fixtures::new(initial),
+            written: Mutex::new(None),
+        })
+    }
+
+    /// db文件的格式，flush到扩展名无法识别的文件时也使用该格式
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// 设置db的schema，之后的写入都会先经过校验
+    pub fn set_schema(&mut self, schema: Schema) {
+        self.schema = Some(schema);
+    }
+
+    /// 按 --schema 及db中的 $schema 校验json，key为顶层key时只校验该key对应的部分
+    pub fn validate(&self, json_obj: &Value, key: Option<&str>) -> Vec<Violation> {
+        validate(self.schema.as_ref(), json_obj, key)
+    }
+
+    /// 执行一次写入并校验写入后的数据，校验失败时撤销写入
+    /// 只备份与校验path所在的顶层key，其余数据不受影响
+    pub fn checked<R>(
+        &self,
+        path: &str,
+        json_obj: &mut Value,
+        write: impl FnOnce(&mut Value) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        if self.schema.is_none() && json_obj.get(schema::SCHEMA_KEY).is_none() {
+            return write(json_obj);
+        }
+        // 修改schema本身时不做校验
+        let key = api::QueryKeys::from_ptr(path).get(0).map(|key| key.to_string());
+        if key.as_deref() == Some(schema::SCHEMA_KEY) {
+            return write(json_obj);
+        }
+        let backup = match &key {
+            Some(key) => json_obj.get(key).cloned(),
+            None => Some(json_obj.clone()),
+        };
+        let res = write(json_obj)?;
+        let violations = self.validate(json_obj, key.as_deref());
+        if violations.is_empty() {
+            return Ok(res);
+        }
+        match (key, backup) {
+            (Some(key), Some(value)) => {
+                json_obj[key.as_str()] = value;
+            }
+            (Some(key), None) => {
+                if let Value::Object(map) = json_obj {
+                    map.remove(&key);
+                }
+            }
+            (None, Some(value)) => *json_obj = value,
+            (None, None) => {}
+        }
+        Err(Error::SchemaViolation { errors: violations })
+    }
+
+    /// 写入前取得path处的旧值，用于变更事件；向数组插入元素不会覆盖原有元素，此时没有旧值
+    pub fn previous(&self, json_obj: &Value, path: &str, insert: bool) -> Option<Value> {
+        if self.watchers.is_empty() {
+            return None;
+        }
+        let parent = api::QueryKeys::from_ptr(path).parent().json_ptr();
+        if insert && json_obj.pointer(&parent).is_some_and(Value::is_array) {
+            return None;
+        }
+        json_obj.pointer(path).cloned()
+    }
+
+    /// 记录一次已生效的修改并通知订阅者，调用时应仍持有data的锁以保证日志顺序与修改顺序一致
+    /// json_obj为修改后的数据，old为修改前path处的值
+    pub fn record(&self, entry: &Entry, json_obj: &Value, old: Option<Value>) {
+        self.append_journal(entry);
+        if self.watchers.is_empty() {
+            return;
+        }
+        self.watchers.publish(event(entry, json_obj, old));
+    }
+
+    /// 记录一次已整体生效的批量修改：日志中只追加一条batch记录，订阅者依次收到各个操作的事件
+    pub fn record_batch(&self, entries: Vec<Entry>, events: Vec<watch::Event>) {
+        self.append_journal(&Entry::Batch { entries });
+        for event in events {
+            self.watchers.publish(event);
+        }
+    }
+
+    fn append_journal(&self, entry: &Entry) {
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.lock().unwrap().append(entry) {
+                error!("Append journal failed: {}", e);
+            }
+        }
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// 整体替换数据，同样写入日志并通知订阅者
+    pub fn reset(&self, value: Value) {
+        let mut json_obj = self.data.write();
+        let old = self.previous(&json_obj, "", false);
+        *json_obj = value.clone();
+        let entry = Entry::Replace {
+            path: String::new(),
+            value,
+        };
+        self.record(&entry, &json_obj, old);
+    }
+
+    /// db文件路径，运行时创建的数据库为空
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// 记录即将写回db文件的内容，须在写入之前调用，避免热加载先看到新文件
+    fn mark_written(&self, content: &[u8]) {
+        *self.written.lock().unwrap() = Some(journal::checksum(content));
+    }
+
+    /// file与db文件是否为同一个文件
+    fn is_own_file(&self, file: &str) -> bool {
+        match (fs::canonicalize(file), fs::canonicalize(&self.file)) {
+            (Ok(file), Ok(own)) => !self.file.is_empty() && file == own,
+            _ => false,
+        }
+    }
+
+    /// 重新读取被外部修改的db文件，解析失败或不符合schema时保留原数据并返回错误
+    /// 文件是服务自身写回的(flush或快照)或内容与当前数据相同时不做替换，返回false，
+    /// 否则写回之后接受的修改会被较旧的文件内容覆盖
+    pub fn reload(&self) -> Result<bool, String> {
+        let (content, value) = format::load(&self.file, self.format).map_err(|e| e.to_string())?;
+        if self.written.lock().unwrap().as_deref()
+            == Some(journal::checksum(content.as_bytes()).as_str())
+        {
+            return Ok(false);
+        }
+        let violations = self.validate(&value, None);
+        if let Some(violation) = violations.first() {
+            return Err(format!(
+                "{} schema violations in {}, first at {}: {}",
+                violations.len(),
+                self.file,
+                violation.pointer,
+                violation.message
+            ));
+        }
+        let mut json_obj = self.data.write();
+        if *json_obj == value {
+            return Ok(false);
+        }
+        // 日志中的修改都基于旧文件，以新文件内容重新开始
+        if let Some(journal) = &self.journal {
+            journal
+                .lock()
+                .unwrap()
+                .reset(content.as_bytes())
+                .map_err(|e| format!("reset journal failed: {}", e))?;
+        }
+        let old = std::mem::replace(&mut *json_obj, value.clone());
+        self.fixtures.set_initial(value.clone());
+        if !self.watchers.is_empty() {
+            self.watchers.publish(watch::Event {
+                op: "reload",
+                path: String::new(),
+                old: Some(old),
+                new: Some(value),
+            });
+        }
+        Ok(true)
+    }
+
+    /// 将当前数据作为快照写回db文件并清空日志，没有新修改时跳过
+    pub fn snapshot(&self) -> Result<(), Error> {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        let json_obj = self.data.read();
+        let mut journal = journal.lock().unwrap();
+        if journal.pending() == 0 {
+            return Ok(());
+        }
+        let content = self
+            .format
+            .serialize(&json_obj)
+            .map_err(|e| Error::Io {
+                detail: format!("snapshot failed due to {}", e),
+            })?;
+        debug!("Snapshot data to {:?} -- start", self.file);
+        self.mark_written(content.as_bytes());
+        journal::write_atomic(&self.file, content.as_bytes())
+            .and_then(|_| journal.reset(content.as_bytes()))
+            .map_err(|e| Error::Io {
+                detail: format!("snapshot failed due to {}", e),
+            })?;
+        debug!("Snapshot data to {:?} -- done", self.file);
+        Ok(())
     }
 
     pub fn get<'a>(
         keys: &mut api::QueryKeys,
         json_obj: &'a mut Value,
-    ) -> Result<&'a mut Value, Value> {
-        let json_ptr = &keys.json_ptr();
-        match json_obj.pointer_mut(json_ptr) {
+    ) -> Result<&'a mut Value, Error> {
+        let pointer = keys.json_ptr();
+        match json_obj.pointer_mut(&pointer) {
             Some(obj) => Ok(obj),
-            None => Err(json!({"reason": "key error"})),
+            None => Err(Error::PathNotFound { pointer }),
         }
     }
 
@@ -35,60 +307,283 @@ impl Database {
         keys: &mut api::QueryKeys,
         json_obj: &mut Value,
         value: Value,
-    ) -> Result<(), Value> {
+    ) -> Result<(), Error> {
+        if keys.len() == 0 {
+            return Err(Error::RootNotAllowed);
+        }
+        let pointer = keys.json_ptr();
         let target_key = keys.remove(keys.len() - 1);
-        match json_obj.pointer_mut(&keys.json_ptr()) {
-            Some(parent_obj) => match parent_obj {
-                Value::Object(obj) => {
-                    obj.insert(target_key, value);
+        match Self::get(keys, json_obj)? {
+            Value::Object(obj) => {
+                obj.insert(target_key, value);
+                Ok(())
+            }
+            Value::Array(array) => match target_key.parse::<usize>() {
+                Ok(idx) if idx <= array.len() => {
+                    array.insert(idx, value);
                     Ok(())
                 }
-                Value::Array(array) => match target_key.parse::<usize>() {
-                    Ok(idx) => {
-                        array.insert(idx, value);
-                        Ok(())
-                    }
-                    Err(_) => Err(json!({"reason": "Parse index as usize error"})),
-                },
-                _ => Err(json!({"reason": "Invalid json struct"})),
+                Ok(_) => Err(Error::IndexOutOfBounds { pointer }),
+                Err(_) => Err(Error::InvalidIndex { pointer }),
             },
-            None => Err(json!({"reason": "key error"})),
+            _ => Err(not_a_container(keys)),
         }
     }
 
-    pub fn delete(keys: &mut api::QueryKeys, json_obj: &mut Value) -> Result<(), Value> {
+    pub fn delete(keys: &mut api::QueryKeys, json_obj: &mut Value) -> Result<(), Error> {
+        if keys.len() == 0 {
+            return Err(Error::RootNotAllowed);
+        }
+        let pointer = keys.json_ptr();
         let target_key = keys.remove(keys.len() - 1);
-        match Self::get(keys, json_obj) {
-            Ok(parent_obj) => match parent_obj {
-                &mut Value::Object(ref mut map) => match map.remove(&target_key) {
-                    Some(_) => Ok(()),
-                    None => Err(json!({"reason": "key not found"})),
-                },
-                &mut Value::Array(ref mut array) => match target_key.parse::<usize>() {
-                    Ok(index) => {
-                        array.remove(index);
-                        Ok(())
-                    }
-                    Err(_) => Err(json!({"reason": "Parse int error"})),
-                },
-                _ => Err(json!({"reason": "Invalid Json Struct"})),
+        match Self::get(keys, json_obj)? {
+            Value::Object(map) => match map.remove(&target_key) {
+                Some(_) => Ok(()),
+                None => Err(Error::PathNotFound { pointer }),
+            },
+            Value::Array(array) => match target_key.parse::<usize>() {
+                Ok(index) if index < array.len() => {
+                    array.remove(index);
+                    Ok(())
+                }
+                Ok(_) => Err(Error::IndexOutOfBounds { pointer }),
+                Err(_) => Err(Error::InvalidIndex { pointer }),
             },
-            Err(e) => Err(e),
+            _ => Err(not_a_container(keys)),
+        }
+    }
+
+    /// keys指向顶层集合或其中的元素时，返回该集合名
+    pub fn collection_of(keys: &api::QueryKeys, json_obj: &Value) -> Option<String> {
+        let name = keys.get(0)?;
+        match (keys.len(), json_obj.get(name)) {
+            (1, Some(Value::Array(array))) | (2, Some(Value::Array(array)))
+                if is_collection(array) =>
+            {
+                Some(name.to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// 嵌套路由 /parent/index/child：返回child集合中外键指向该元素的所有元素
+    pub fn get_nested(keys: &api::QueryKeys, json_obj: &Value) -> Option<(String, Vec<Value>)> {
+        if keys.len() != 3 {
+            return None;
+        }
+        let (parent, child) = (keys.get(0)?, keys.get(2)?);
+        let parent_id = json_obj
+            .get(parent)?
+            .get(keys.get(1)?.parse::<usize>().ok()?)?
+            .get("id")?;
+        relation::children(json_obj, child, parent, parent_id).map(|items| (child.to_string(), items))
+    }
+
+    /// 整体替换keys指向的json
+    pub fn replace(
+        keys: &mut api::QueryKeys,
+        json_obj: &mut Value,
+        value: Value,
+    ) -> Result<(), Error> {
+        let target = Self::get(keys, json_obj)?;
+        *target = value;
+        Ok(())
+    }
+
+    /// 向keys指向的集合追加元素，未提供id时自动生成，返回新元素的下标及其内容
+    pub fn append(
+        keys: &mut api::QueryKeys,
+        json_obj: &mut Value,
+        mut value: Value,
+    ) -> Result<(usize, Value), Error> {
+        let pointer = keys.json_ptr();
+        let array = match Self::get(keys, json_obj)? {
+            Value::Array(array) if is_collection(array) => array,
+            _ => {
+                return Err(Error::TypeConflict {
+                    pointer,
+                    detail: "target is not a collection".to_string(),
+                })
+            }
+        };
+        let item = match value.as_object_mut() {
+            Some(item) => item,
+            None => {
+                return Err(Error::TypeConflict {
+                    pointer,
+                    detail: "collection item must be an object".to_string(),
+                })
+            }
+        };
+        match item.get("id") {
+            Some(id) => {
+                if find_by_id(array, &id_string(id)).is_some() {
+                    return Err(Error::DuplicateId { id: id.clone() });
+                }
+            }
+            None => {
+                item.insert("id".to_string(), json!(next_id(array)));
+            }
         }
+        array.push(value.clone());
+        Ok((array.len() - 1, value))
     }
 
-    pub fn flush(json_obj: &Value, file: String) -> Result<(), Value> {
-        let new_db = &serde_json::to_string(json_obj).unwrap();
+    /// 对keys指向的json执行JSON Patch，全部操作成功才会生效
+    pub fn json_patch(
+        keys: &mut api::QueryKeys,
+        json_obj: &mut Value,
+        ops: Vec<patch::Operation>,
+    ) -> Result<(), Error> {
+        let target = Self::get(keys, json_obj)?;
+        patch::json_patch(target, ops)
+    }
+
+    /// 对keys指向的json执行JSON Merge Patch
+    pub fn merge_patch(
+        keys: &mut api::QueryKeys,
+        json_obj: &mut Value,
+        value: Value,
+    ) -> Result<(), Error> {
+        let target = Self::get(keys, json_obj)?;
+        patch::merge_patch(target, value);
+        Ok(())
+    }
+
+    /// 将当前数据写到file，file为db文件本身时同样记录写入的内容
+    pub fn flush(&self, file: &str, format: Format) -> Result<(), Error> {
+        let new_db = &format.serialize(&self.data.read()).map_err(|e| Error::Io {
+            detail: format!("flush failed due to {}", e),
+        })?;
+        if self.is_own_file(file) {
+            self.mark_written(new_db.as_bytes());
+        }
         debug!("Flush data to {:?} -- start", file);
-        match fs::write(&file, new_db) {
+        match fs::write(file, new_db) {
             Ok(_) => {
                 debug!("Flush data to {:?} -- done", file);
                 Ok(())
             }
             Err(e) => {
                 debug!("Flush data to {:?} -- failed", file);
-                Err(json!({ "reason": format!("flush failed due to {:?}", e) }))
+                Err(Error::Io {
+                    detail: format!("flush failed due to {}", e),
+                })
+            }
+        }
+    }
+}
+
+/// keys指向的值不是对象或数组，无法在其中插入或删除
+fn not_a_container(keys: &api::QueryKeys) -> Error {
+    Error::TypeConflict {
+        pointer: keys.json_ptr(),
+        detail: format!("value at {:?} is not an object or array", keys.json_ptr()),
+    }
+}
+
+/// 一次修改对应的变更事件，json_obj为修改后的数据
+pub fn event(entry: &Entry, json_obj: &Value, old: Option<Value>) -> watch::Event {
+    let new = match entry {
+        Entry::Delete { .. } => None,
+        _ => json_obj.pointer(entry.path()).cloned(),
+    };
+    watch::Event {
+        op: entry.op(),
+        path: entry.path().to_string(),
+        old,
+        new,
+    }
+}
+
+/// 按schema文件及db中的 $schema 校验json
+pub fn validate(schema: Option<&Schema>, json_obj: &Value, key: Option<&str>) -> Vec<Violation> {
+    let mut violations = vec![];
+    if let Some(schema) = schema {
+        match key {
+            Some(key) => violations.extend(schema.validate_key(key, json_obj.get(key))),
+            None => violations.extend(schema.validate(json_obj, "")),
+        }
+    }
+    if let Some(Value::Object(schemas)) = json_obj.get(schema::SCHEMA_KEY) {
+        for (name, sub) in schemas {
+            if key.is_some_and(|key| key != name) {
+                continue;
+            }
+            if let Some(value) = json_obj.get(name) {
+                let pointer = format!("/{}", schema::escape(name));
+                violations.extend(schema::validate(sub, value, &pointer));
             }
         }
     }
+    violations
+}
+
+/// 元素全部为对象的数组视为集合
+pub fn is_collection(array: &[Value]) -> bool {
+    array.iter().all(Value::is_object)
+}
+
+/// id统一按字符串比较，使路径中的 "42" 能匹配数字 42
+pub fn id_string(id: &Value) -> String {
+    match id {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// 查找集合中id等于给定值的元素下标
+pub fn find_by_id(array: &[Value], id: &str) -> Option<usize> {
+    array
+        .iter()
+        .position(|item| item.get("id").map(id_string).as_deref() == Some(id))
+}
+
+/// 生成新id：已有数字id的最大值加一
+fn next_id(array: &[Value]) -> u64 {
+    array
+        .iter()
+        .filter_map(|item| item.get("id").and_then(Value::as_u64))
+        .max()
+        .map_or(1, |id| id + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{QueryKeys, RouteMode};
+
+    #[test]
+    fn test_collection() {
+        let mut data = json!({"posts": [{"id": 1}, {"id": "a"}], "tags": ["x"]});
+        let keys = QueryKeys::from_ptr("/posts/a").resolve(RouteMode::Collection, &data);
+        assert_eq!(keys.unwrap().json_ptr(), "/posts/1");
+        assert!(QueryKeys::from_ptr("/posts/3")
+            .resolve(RouteMode::Collection, &data)
+            .is_err());
+        let keys = QueryKeys::from_ptr("/tags/0").resolve(RouteMode::Collection, &data);
+        assert_eq!(keys.unwrap().json_ptr(), "/tags/0");
+
+        let (idx, item) =
+            Database::append(&mut QueryKeys::from_ptr("/posts"), &mut data, json!({})).unwrap();
+        assert_eq!((idx, item), (2, json!({"id": 2})));
+        assert!(Database::append(
+            &mut QueryKeys::from_ptr("/posts"),
+            &mut data,
+            json!({"id": 1})
+        )
+        .is_err());
+        assert!(Database::append(&mut QueryKeys::from_ptr("/tags"), &mut data, json!({})).is_err());
+
+        // 根不能插入或删除
+        let root = || QueryKeys::from_ptr("");
+        assert_eq!(
+            Database::delete(&mut root(), &mut data),
+            Err(Error::RootNotAllowed)
+        );
+        assert_eq!(
+            Database::insert(&mut root(), &mut data, json!({})),
+            Err(Error::RootNotAllowed)
+        );
+    }
 }
@@ -0,0 +1,497 @@
+//! 错误模块
+//! 数据库操作、认证、故障注入及录制回放返回的错误，每种错误有固定的code与HTTP状态码，
+//! 响应体为 RFC 7807 application/problem+json：
+//! ```json
+//! {
+//!   "type": "urn:mockrs:error:path_not_found",
+//!   "title": "Path not found",
+//!   "status": 404,
+//!   "detail": "no value at \"/posts/9\"",
+//!   "code": "path_not_found",
+//!   "pointer": "/posts/9"
+//! }
+//! ```
+//! 除上述字段外，各错误会带上相关的扩展字段(pointer、id、name、errors等)。
+use std::fmt;
+
+use actix_web::error::JsonPayloadError;
+use actix_web::http::{header, StatusCode};
+use actix_web::web::JsonConfig;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::{json, Map, Value};
+
+use crate::schema::Violation;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// json pointer指向的值不存在
+    PathNotFound {
+        pointer: String,
+    },
+    /// 集合中没有该id的元素
+    IdNotFound {
+        id: String,
+    },
+    /// 数组下标超出范围
+    IndexOutOfBounds {
+        pointer: String,
+    },
+    /// 数组下标不是合法的非负整数
+    InvalidIndex {
+        pointer: String,
+    },
+    /// 不以 / 开头的json pointer
+    InvalidPointer {
+        pointer: String,
+    },
+    /// 目标的类型不支持该操作，如向字符串中插入、向非集合追加元素
+    TypeConflict {
+        pointer: String,
+        detail: String,
+    },
+    /// 插入或删除db的根，根只能整体修改
+    RootNotAllowed,
+    /// 集合中已存在该id
+    DuplicateId {
+        id: Value,
+    },
+    /// JSON Patch 的test操作未通过
+    TestFailed {
+        pointer: String,
+        expected: Value,
+        actual: Value,
+    },
+    /// JSON Patch 的move操作将值移到自身的子节点下
+    InvalidMove {
+        from: String,
+        pointer: String,
+    },
+    /// 请求体无法解析
+    InvalidBody {
+        detail: String,
+    },
+    /// 请求体超过大小上限
+    PayloadTooLarge {
+        limit: usize,
+    },
+    /// 查询参数无法解析
+    InvalidQuery {
+        detail: String,
+    },
+    /// 不支持的Content-Type
+    UnsupportedMediaType {
+        accept: Vec<&'static str>,
+    },
+    /// 写入后的数据不符合schema
+    SchemaViolation {
+        errors: Vec<Violation>,
+    },
+    /// If-Match/If-None-Match 条件不满足
+    PreconditionFailed {
+        detail: String,
+        etag: Option<String>,
+    },
+    /// 要求If-Match但请求中没有
+    PreconditionRequired {
+        etag: Option<String>,
+    },
+    /// JSON Patch 中的一项操作失败，整个patch未生效
+    PatchFailed {
+        index: usize,
+        cause: Box<Error>,
+    },
+    /// 批量操作中的一项失败，所有操作已回滚
+    BatchRolledBack {
+        index: Option<usize>,
+        cause: Box<Error>,
+        results: Vec<Value>,
+    },
+    DatabaseNotFound {
+        name: String,
+    },
+    /// db_file为目录时没有挂载在 / 的数据库
+    NoDefaultDatabase {
+        databases: Vec<String>,
+    },
+    DatabaseExists {
+        name: String,
+    },
+    SnapshotNotFound {
+        name: String,
+    },
+    /// 数据库或快照的名字不合法
+    InvalidName {
+        name: String,
+        detail: String,
+    },
+    /// 写入db文件失败
+    Io {
+        detail: String,
+    },
+    /// 缺少凭证或凭证无效
+    Unauthorized {
+        detail: String,
+    },
+    /// 凭证中没有规则要求的role
+    Forbidden {
+        role: String,
+        subject: String,
+    },
+    /// 没有配置jwt，不能登录
+    LoginNotConfigured,
+    /// 故障注入返回的错误，状态码由配置决定
+    ChaosInjected {
+        status: StatusCode,
+    },
+    /// record模式下请求目标服务失败
+    ProxyFailed {
+        url: String,
+        detail: String,
+    },
+    /// replay模式下没有匹配的录制记录
+    NoRecordedInteraction {
+        method: String,
+        path: String,
+        query: String,
+    },
+}
+
+impl Error {
+    /// 稳定的机器可读错误码
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::PathNotFound { .. } => "path_not_found",
+            Error::IdNotFound { .. } => "id_not_found",
+            Error::IndexOutOfBounds { .. } => "index_out_of_bounds",
+            Error::InvalidIndex { .. } => "invalid_index",
+            Error::InvalidPointer { .. } => "invalid_pointer",
+            Error::TypeConflict { .. } => "type_conflict",
+            Error::RootNotAllowed => "root_not_allowed",
+            Error::DuplicateId { .. } => "duplicate_id",
+            Error::TestFailed { .. } => "test_failed",
+            Error::InvalidMove { .. } => "invalid_move",
+            Error::InvalidBody { .. } => "invalid_body",
+            Error::PayloadTooLarge { .. } => "payload_too_large",
+            Error::InvalidQuery { .. } => "invalid_query",
+            Error::UnsupportedMediaType { .. } => "unsupported_media_type",
+            Error::SchemaViolation { .. } => "schema_violation",
+            Error::PreconditionFailed { .. } => "precondition_failed",
+            Error::PreconditionRequired { .. } => "precondition_required",
+            Error::PatchFailed { .. } => "patch_failed",
+            Error::BatchRolledBack { .. } => "batch_rolled_back",
+            Error::DatabaseNotFound { .. } => "database_not_found",
+            Error::NoDefaultDatabase { .. } => "no_default_database",
+            Error::DatabaseExists { .. } => "database_exists",
+            Error::SnapshotNotFound { .. } => "snapshot_not_found",
+            Error::InvalidName { .. } => "invalid_name",
+            Error::Io { .. } => "io_error",
+            Error::Unauthorized { .. } => "unauthorized",
+            Error::Forbidden { .. } => "forbidden",
+            Error::LoginNotConfigured => "login_not_configured",
+            Error::ChaosInjected { .. } => "chaos_injected",
+            Error::ProxyFailed { .. } => "proxy_failed",
+            Error::NoRecordedInteraction { .. } => "no_recorded_interaction",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::PathNotFound { .. }
+            | Error::IdNotFound { .. }
+            | Error::DatabaseNotFound { .. }
+            | Error::NoDefaultDatabase { .. }
+            | Error::SnapshotNotFound { .. }
+            | Error::LoginNotConfigured
+            | Error::NoRecordedInteraction { .. } => StatusCode::NOT_FOUND,
+            Error::TypeConflict { .. }
+            | Error::RootNotAllowed
+            | Error::DuplicateId { .. }
+            | Error::TestFailed { .. }
+            | Error::DatabaseExists { .. } => StatusCode::CONFLICT,
+            Error::IndexOutOfBounds { .. }
+            | Error::InvalidIndex { .. }
+            | Error::InvalidMove { .. }
+            | Error::SchemaViolation { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::InvalidPointer { .. }
+            | Error::InvalidBody { .. }
+            | Error::InvalidQuery { .. }
+            | Error::InvalidName { .. } => StatusCode::BAD_REQUEST,
+            Error::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UnsupportedMediaType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::PreconditionFailed { .. } => StatusCode::PRECONDITION_FAILED,
+            Error::PreconditionRequired { .. } => StatusCode::PRECONDITION_REQUIRED,
+            // 使用失败操作的状态码
+            Error::PatchFailed { cause, .. } | Error::BatchRolledBack { cause, .. } => {
+                cause.status()
+            }
+            Error::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            Error::Forbidden { .. } => StatusCode::FORBIDDEN,
+            Error::ChaosInjected { status } => *status,
+            Error::ProxyFailed { .. } => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// 同一code的错误title相同
+    pub fn title(&self) -> &'static str {
+        match self {
+            Error::PathNotFound { .. } => "Path not found",
+            Error::IdNotFound { .. } => "Id not found",
+            Error::IndexOutOfBounds { .. } => "Index out of bounds",
+            Error::InvalidIndex { .. } => "Invalid array index",
+            Error::InvalidPointer { .. } => "Invalid json pointer",
+            Error::TypeConflict { .. } => "Type conflict",
+            Error::RootNotAllowed => "Root not allowed",
+            Error::DuplicateId { .. } => "Duplicate id",
+            Error::TestFailed { .. } => "Test operation failed",
+            Error::InvalidMove { .. } => "Invalid move",
+            Error::InvalidBody { .. } => "Invalid request body",
+            Error::PayloadTooLarge { .. } => "Payload too large",
+            Error::InvalidQuery { .. } => "Invalid query",
+            Error::UnsupportedMediaType { .. } => "Unsupported media type",
+            Error::SchemaViolation { .. } => "Schema validation failed",
+            Error::PreconditionFailed { .. } => "Precondition failed",
+            Error::PreconditionRequired { .. } => "Precondition required",
+            Error::PatchFailed { .. } => "Patch failed",
+            Error::BatchRolledBack { .. } => "Batch rolled back",
+            Error::DatabaseNotFound { .. } => "Database not found",
+            Error::NoDefaultDatabase { .. } => "No default database",
+            Error::DatabaseExists { .. } => "Database already exists",
+            Error::SnapshotNotFound { .. } => "Snapshot not found",
+            Error::InvalidName { .. } => "Invalid name",
+            Error::Io { .. } => "I/O error",
+            Error::Unauthorized { .. } => "Unauthorized",
+            Error::Forbidden { .. } => "Forbidden",
+            Error::LoginNotConfigured => "Login not configured",
+            Error::ChaosInjected { .. } => "Injected error",
+            Error::ProxyFailed { .. } => "Proxy failed",
+            Error::NoRecordedInteraction { .. } => "No recorded interaction",
+        }
+    }
+
+    /// 与本次错误相关的扩展字段
+    fn extensions(&self) -> Value {
+        match self {
+            Error::PathNotFound { pointer }
+            | Error::IndexOutOfBounds { pointer }
+            | Error::InvalidIndex { pointer }
+            | Error::InvalidPointer { pointer }
+            | Error::TypeConflict { pointer, .. } => json!({ "pointer": pointer }),
+            Error::IdNotFound { id } => json!({ "id": id }),
+            Error::DuplicateId { id } => json!({ "id": id }),
+            Error::TestFailed {
+                pointer,
+                expected,
+                actual,
+            } => json!({"pointer": pointer, "expected": expected, "actual": actual}),
+            Error::InvalidMove { from, pointer } => json!({"from": from, "pointer": pointer}),
+            Error::PayloadTooLarge { limit } => json!({ "limit": limit }),
+            Error::UnsupportedMediaType { accept } => json!({ "accept": accept }),
+            Error::SchemaViolation { errors } => json!({ "errors": errors }),
+            Error::PreconditionFailed { etag, .. } | Error::PreconditionRequired { etag } => {
+                json!({ "etag": etag })
+            }
+            Error::PatchFailed { index, cause } => {
+                json!({"index": index, "cause": cause.problem()})
+            }
+            Error::BatchRolledBack {
+                index,
+                cause,
+                results,
+            } => json!({"index": index, "cause": cause.problem(), "results": results}),
+            Error::DatabaseNotFound { name }
+            | Error::DatabaseExists { name }
+            | Error::SnapshotNotFound { name }
+            | Error::InvalidName { name, .. } => json!({ "name": name }),
+            Error::NoDefaultDatabase { databases } => json!({ "databases": databases }),
+            Error::Forbidden { role, subject } => json!({"role": role, "subject": subject}),
+            Error::ProxyFailed { url, .. } => json!({ "url": url }),
+            Error::NoRecordedInteraction {
+                method,
+                path,
+                query,
+            } => json!({"method": method, "path": path, "query": query}),
+            Error::InvalidBody { .. }
+            | Error::InvalidQuery { .. }
+            | Error::Io { .. }
+            | Error::Unauthorized { .. }
+            | Error::RootNotAllowed
+            | Error::LoginNotConfigured
+            | Error::ChaosInjected { .. } => json!({}),
+        }
+    }
+
+    /// RFC 7807 problem details
+    pub fn problem(&self) -> Value {
+        let mut problem = Map::new();
+        problem.insert(
+            "type".to_string(),
+            json!(format!("urn:mockrs:error:{}", self.code())),
+        );
+        problem.insert("title".to_string(), json!(self.title()));
+        problem.insert("status".to_string(), json!(self.status().as_u16()));
+        problem.insert("detail".to_string(), json!(self.to_string()));
+        problem.insert("code".to_string(), json!(self.code()));
+        if let Value::Object(extensions) = self.extensions() {
+            problem.extend(extensions);
+        }
+        Value::Object(problem)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::PathNotFound { pointer } => write!(f, "no value at {:?}", pointer),
+            Error::IdNotFound { id } => write!(f, "no item with id {:?}", id),
+            Error::IndexOutOfBounds { pointer } => {
+                write!(f, "array index of {:?} is out of bounds", pointer)
+            }
+            Error::InvalidIndex { pointer } => {
+                write!(f, "last token of {:?} is not an array index", pointer)
+            }
+            Error::InvalidPointer { pointer } => {
+                write!(
+                    f,
+                    "{:?} is not a json pointer, it must start with /",
+                    pointer
+                )
+            }
+            Error::TypeConflict { detail, .. }
+            | Error::InvalidBody { detail }
+            | Error::InvalidQuery { detail }
+            | Error::PreconditionFailed { detail, .. }
+            | Error::InvalidName { detail, .. }
+            | Error::Io { detail }
+            | Error::Unauthorized { detail } => write!(f, "{}", detail),
+            Error::RootNotAllowed => write!(f, "can not insert or delete the root of the db"),
+            Error::DuplicateId { id } => write!(f, "an item with id {} already exists", id),
+            Error::TestFailed { pointer, .. } => {
+                write!(f, "value at {:?} is not the expected one", pointer)
+            }
+            Error::InvalidMove { from, pointer } => {
+                write!(f, "can not move {:?} into its child {:?}", from, pointer)
+            }
+            Error::PayloadTooLarge { limit } => {
+                write!(f, "request body is larger than {} bytes", limit)
+            }
+            Error::UnsupportedMediaType { accept } => {
+                write!(f, "content type must be one of {}", accept.join(", "))
+            }
+            Error::SchemaViolation { errors } => {
+                write!(f, "{} schema violation(s)", errors.len())?;
+                if let Some(first) = errors.first() {
+                    write!(f, ", {} at {:?}", first.message, first.pointer)?;
+                }
+                Ok(())
+            }
+            Error::PreconditionRequired { .. } => {
+                write!(f, "send If-Match with the current etag")
+            }
+            Error::PatchFailed { index, cause } => {
+                write!(f, "operation {} failed: {}", index, cause)
+            }
+            Error::BatchRolledBack { index, cause, .. } => match index {
+                Some(index) => write!(f, "operation {} failed: {}", index, cause),
+                None => write!(f, "{}", cause),
+            },
+            Error::DatabaseNotFound { name } => write!(f, "no database named {:?}", name),
+            Error::NoDefaultDatabase { .. } => {
+                write!(f, "no database is mounted at /, use /db/<name>/")
+            }
+            Error::DatabaseExists { name } => write!(f, "database {:?} already exists", name),
+            Error::SnapshotNotFound { name } => write!(f, "no snapshot named {:?}", name),
+            Error::Forbidden { role, .. } => write!(f, "role {} required", role),
+            Error::LoginNotConfigured => write!(f, "jwt login is not configured"),
+            Error::ChaosInjected { .. } => write!(f, "chaos: injected error"),
+            Error::ProxyFailed { url, detail } => {
+                write!(f, "proxy error for {}: {}", url, detail)
+            }
+            Error::NoRecordedInteraction { method, path, .. } => {
+                write!(f, "no recorded interaction matches {} {}", method, path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status());
+        if let Error::Unauthorized { .. } = self {
+            resp.header(header::WWW_AUTHENTICATE, "Basic realm=\"mockrs\", Bearer");
+        }
+        resp.content_type(PROBLEM_JSON).json(self.problem())
+    }
+}
+
+/// web::Json 请求体的大小上限，与actix的默认值相同
+const JSON_LIMIT: usize = 32 * 1024;
+
+/// web::Json 的配置，请求体无法提取时同样返回problem+json
+pub fn json_config() -> JsonConfig {
+    JsonConfig::default()
+        .limit(JSON_LIMIT)
+        .error_handler(|e, _| {
+            match e {
+                JsonPayloadError::Overflow => Error::PayloadTooLarge { limit: JSON_LIMIT },
+                JsonPayloadError::ContentType => Error::UnsupportedMediaType {
+                    accept: vec!["application/json"],
+                },
+                e => Error::InvalidBody {
+                    detail: e.to_string(),
+                },
+            }
+            .into()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problem() {
+        let e = Error::PathNotFound {
+            pointer: "/posts/9".to_string(),
+        };
+        assert_eq!(
+            e.problem(),
+            json!({
+                "type": "urn:mockrs:error:path_not_found",
+                "title": "Path not found",
+                "status": 404,
+                "detail": "no value at \"/posts/9\"",
+                "code": "path_not_found",
+                "pointer": "/posts/9"
+            })
+        );
+        let resp = e.error_response();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("content-type").unwrap(), PROBLEM_JSON);
+
+        let e = Error::BatchRolledBack {
+            index: Some(1),
+            cause: Box::new(Error::IndexOutOfBounds {
+                pointer: "/posts/5".to_string(),
+            }),
+            results: vec![],
+        };
+        assert_eq!(e.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(e.problem()["cause"]["code"], "index_out_of_bounds");
+
+        let resp = Error::Unauthorized {
+            detail: "authentication required".to_string(),
+        }
+        .error_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+}
@@ -0,0 +1,148 @@
+//! 条件请求模块
+//! GET 的响应带有按内容计算的ETag，请求带 If-None-Match 且ETag未变化时返回304。
+//! PUT PATCH DELETE 带 If-Match 时，目标的当前ETag不匹配则返回412，不做修改；
+//! 开启 --require-if-match 后，修改已存在的数据必须带 If-Match，否则返回428。
+//! PUT 带 If-None-Match: * 时只在目标不存在时写入。
+use actix_web::{http, HttpRequest, HttpResponse};
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::journal;
+
+/// 条件请求的配置
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Preconditions {
+    pub require_if_match: bool,
+}
+
+/// 按序列化后的内容计算强ETag，对象的key有序，相同内容得到相同的ETag
+pub fn etag(value: &Value) -> String {
+    let content = serde_json::to_string(value).unwrap();
+    format!("\"{}\"", journal::checksum(content.as_bytes()))
+}
+
+fn header(req: &HttpRequest, name: http::header::HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+fn tags(header: &str) -> impl Iterator<Item = &str> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+}
+
+/// If-None-Match 使用弱比较，命中时应返回304
+pub fn none_match_hit(header: &str, etag: &str) -> bool {
+    let weak = |tag: &str| tag.trim_start_matches("W/").to_string();
+    tags(header).any(|tag| tag == "*" || weak(tag) == weak(etag))
+}
+
+/// If-Match 使用强比较，etag为None表示目标不存在
+pub fn if_match_hit(header: &str, etag: Option<&str>) -> bool {
+    match etag {
+        Some(etag) => tags(header).any(|tag| tag == "*" || tag == etag),
+        None => false,
+    }
+}
+
+/// GET 时检查 If-None-Match，命中时返回304响应
+pub fn not_modified(req: &HttpRequest, etag: &str) -> Option<HttpResponse> {
+    let header = header(req, http::header::IF_NONE_MATCH)?;
+    if none_match_hit(header, etag) {
+        Some(
+            HttpResponse::build(http::StatusCode::NOT_MODIFIED)
+                .header(http::header::ETAG, etag)
+                .finish(),
+        )
+    } else {
+        None
+    }
+}
+
+/// 修改前检查前置条件，current为目标的当前值，不满足时返回对应的错误响应
+pub fn check(
+    req: &HttpRequest,
+    current: Option<&Value>,
+    preconditions: Preconditions,
+) -> Result<(), Error> {
+    let current_etag = current.map(etag);
+    match header(req, http::header::IF_MATCH) {
+        Some(header) if !if_match_hit(header, current_etag.as_deref()) => {
+            return Err(Error::PreconditionFailed {
+                detail: "etag does not match".to_string(),
+                etag: current_etag,
+            });
+        }
+        Some(_) => {}
+        None if preconditions.require_if_match && current.is_some() => {
+            return Err(Error::PreconditionRequired { etag: current_etag });
+        }
+        None => {}
+    }
+    if req.method() == http::Method::PUT && current.is_some() {
+        if let Some(header) = header(req, http::header::IF_NONE_MATCH) {
+            if tags(header).any(|tag| tag == "*") {
+                return Err(Error::PreconditionFailed {
+                    detail: "target already exists".to_string(),
+                    etag: current_etag,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_etag() {
+        let a = json!({"b": 1, "a": [1, 2]});
+        let tag = etag(&a);
+        assert_eq!(tag, etag(&json!({"a": [1, 2], "b": 1})));
+        assert_ne!(tag, etag(&json!({"a": [2, 1], "b": 1})));
+
+        assert!(none_match_hit(&format!("\"x\", W/{}", tag), &tag));
+        assert!(none_match_hit("*", &tag));
+        assert!(!none_match_hit("\"x\"", &tag));
+        assert!(if_match_hit(&tag, Some(&tag)));
+        assert!(!if_match_hit(&format!("W/{}", tag), Some(&tag)));
+        assert!(!if_match_hit("*", None));
+    }
+
+    #[test]
+    fn test_check() {
+        let current = json!({"name": "a"});
+        let tag = etag(&current);
+        let strict = Preconditions {
+            require_if_match: true,
+        };
+        let req = TestRequest::put().to_http_request();
+        assert!(check(&req, Some(&current), Preconditions::default()).is_ok());
+        let status = |res: Result<(), Error>| res.unwrap_err().status().as_u16();
+        assert_eq!(status(check(&req, Some(&current), strict)), 428);
+        assert!(check(&req, None, strict).is_ok());
+
+        let req = TestRequest::put()
+            .header("If-Match", "\"stale\"")
+            .to_http_request();
+        assert_eq!(status(check(&req, Some(&current), strict)), 412);
+        let req = TestRequest::delete()
+            .header("If-Match", tag.as_str())
+            .to_http_request();
+        assert!(check(&req, Some(&current), strict).is_ok());
+        let req = TestRequest::put()
+            .header("If-None-Match", "*")
+            .to_http_request();
+        assert_eq!(
+            status(check(&req, Some(&current), Preconditions::default())),
+            412
+        );
+        assert!(check(&req, None, strict).is_ok());
+    }
+}
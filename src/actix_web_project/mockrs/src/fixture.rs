@@ -0,0 +1,126 @@
+//! 测试夹具模块
+//! 在测试用例之间保存与恢复数据，无需重启mockrs：
+//!     GET     /_actions/snapshots             列出已保存的快照
+//!     POST    /_actions/snapshot              以 {"name": "before"} 保存当前数据，同名时覆盖
+//!     POST    /_actions/restore/<name>        恢复为该快照的数据
+//!     DELETE  /_actions/snapshots/<name>      删除快照
+//!     POST    /_actions/reset                 恢复为启动时(或 --watch 最近一次重新加载的)db文件的内容
+//! 快照只保存在内存中，每个数据库各自独立，用 ?db=<name> 指定数据库。
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::Error;
+
+struct Saved {
+    data: Value,
+    created_at: u64,
+}
+
+/// 快照列表中的一项
+#[derive(Debug, Clone, Serialize)]
+pub struct FixtureInfo {
+    pub name: String,
+    /// 保存时间，unix时间戳(秒)
+    pub created_at: u64,
+}
+
+pub struct Fixtures {
+    // 启动时db文件的内容(重放日志之前)，或运行时创建数据库时的数据，--watch 重新加载后为新的文件内容
+    initial: Mutex<Value>,
+    saved: Mutex<BTreeMap<String, Saved>>,
+}
+
+impl Fixtures {
+    pub fn new(initial: Value) -> Fixtures {
+        Fixtures {
+            initial: Mutex::new(initial),
+            saved: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn initial(&self) -> Value {
+        self.initial.lock().unwrap().clone()
+    }
+
+    pub fn set_initial(&self, initial: Value) {
+        *self.initial.lock().unwrap() = initial;
+    }
+
+    pub fn save(&self, name: &str, data: Value) -> Result<(), Error> {
+        if name.is_empty() {
+            return Err(Error::InvalidName {
+                name: String::new(),
+                detail: "snapshot name is required".to_string(),
+            });
+        }
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.saved
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), Saved { data, created_at });
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Value, Error> {
+        match self.saved.lock().unwrap().get(name) {
+            Some(saved) => Ok(saved.data.clone()),
+            None => Err(not_found(name)),
+        }
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        match self.saved.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(not_found(name)),
+        }
+    }
+
+    pub fn list(&self) -> Vec<FixtureInfo> {
+        self.saved
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, saved)| FixtureInfo {
+                name: name.clone(),
+                created_at: saved.created_at,
+            })
+            .collect()
+    }
+}
+
+fn not_found(name: &str) -> Error {
+    Error::SnapshotNotFound {
+        name: name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_fixtures() {
+        let fixtures = Fixtures::new(json!({"posts": []}));
+        assert!(fixtures.save("", json!({})).is_err());
+        fixtures.save("a", json!({"posts": [1]})).unwrap();
+        fixtures.save("a", json!({"posts": [2]})).unwrap();
+        fixtures.save("b", json!({})).unwrap();
+        assert_eq!(fixtures.get("a").unwrap(), json!({"posts": [2]}));
+        let names: Vec<String> = fixtures.list().into_iter().map(|info| info.name).collect();
+        assert_eq!(names, vec!["a", "b"]);
+
+        fixtures.remove("a").unwrap();
+        assert!(fixtures.get("a").is_err());
+        assert!(fixtures.remove("a").is_err());
+        assert_eq!(fixtures.initial(), json!({"posts": []}));
+    }
+}
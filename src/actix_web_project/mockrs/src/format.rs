@@ -0,0 +1,146 @@
+//! db文件格式模块
+//! 除json外还支持 .yaml/.yml .toml .json5 文件，可以在手写的数据中使用注释。
+//! 格式按扩展名判断，也可以用 --format 指定；flush与快照按原格式写回，注释不会保留。
+//! 解析失败时给出行号与列号。
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+/// db文件格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Json5,
+    Yaml,
+    Toml,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "json5" => Ok(Format::Json5),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            _ => Err(format!("unknown db format: {}", s)),
+        }
+    }
+}
+
+impl Format {
+    /// 按扩展名判断格式，无法识别时为None
+    pub fn from_path(path: &str) -> Option<Format> {
+        Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.to_lowercase().parse().ok())
+    }
+
+    /// 解析文件内容，错误信息包含行号与列号
+    pub fn parse(self, content: &str) -> Result<Value, String> {
+        match self {
+            // serde_json serde_yaml toml 的错误信息已带有 "at line x column y"
+            Format::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            Format::Json5 => json5::from_str(content).map_err(|e| match e {
+                json5::Error::Message {
+                    msg,
+                    location: Some(location),
+                } => {
+                    // pest的错误信息为多行的示意图，只保留最后的说明
+                    let msg = msg
+                        .lines()
+                        .last()
+                        .map(|line| line.trim_start_matches([' ', '=']))
+                        .unwrap_or_default();
+                    format!(
+                        "{} at line {} column {}",
+                        msg, location.line, location.column
+                    )
+                }
+                json5::Error::Message { msg, .. } => msg,
+            }),
+        }
+    }
+
+    /// 序列化为该格式，toml不能表示null及顶层以外的混合类型数组
+    pub fn serialize(self, value: &Value) -> Result<String, String> {
+        match self {
+            Format::Json => serde_json::to_string(value).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+            Format::Toml => toml::Value::try_from(value)
+                .and_then(|value| toml::to_string_pretty(&value))
+                .map_err(|e| e.to_string()),
+            Format::Json5 => json5::to_string(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// 读取并解析db文件，返回原始内容及解析后的数据
+pub fn load(file: &str, format: Format) -> std::io::Result<(String, Value)> {
+    let content = fs::read_to_string(file)
+        .map_err(|e| Error::new(e.kind(), format!("unable to read db file {}: {}", file, e)))?;
+    let value = format.parse(&content).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid db file {}: {}", file, e),
+        )
+    })?;
+    Ok((content, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(Format::from_path("db.YML"), Some(Format::Yaml));
+        assert_eq!(Format::from_path("dbs/a.json5"), Some(Format::Json5));
+        assert_eq!(Format::from_path("db"), None);
+
+        let data = json!({"posts": [{"id": 1, "title": "a"}], "profile": {"name": "x"}});
+        let sources = [
+            (
+                Format::Yaml,
+                "# fixture\nposts:\n  - id: 1\n    title: a\nprofile:\n  name: x\n",
+            ),
+            (
+                Format::Toml,
+                "# fixture\n[[posts]]\nid = 1\ntitle = \"a\"\n\n[profile]\nname = \"x\"\n",
+            ),
+            (
+                Format::Json5,
+                "// fixture\n{posts: [{id: 1, title: 'a',}], profile: {name: 'x'}}",
+            ),
+        ];
+        for (format, source) in sources.iter() {
+            assert_eq!(format.parse(source).unwrap(), data);
+            let written = format.serialize(&data).unwrap();
+            assert_eq!(format.parse(&written).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let cases = [
+            (Format::Json, "{\n  \"a\": 1,\n  b\n}", "line 3 column 3"),
+            (Format::Yaml, "a: 1\nb: [1\n", "line 3 column 1"),
+            (Format::Toml, "a = 1\nb = \n", "line 2 column 5"),
+            (Format::Json5, "{\n  a: 1,\n  b: #\n}", "line 3 column 6"),
+        ];
+        for (format, source, location) in cases.iter() {
+            let e = format.parse(source).unwrap_err();
+            assert!(e.contains(location), "{:?}: {}", format, e);
+        }
+        assert!(Format::Toml.serialize(&json!({"a": null})).is_err());
+    }
+}
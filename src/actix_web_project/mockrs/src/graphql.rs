@@ -0,0 +1,1542 @@
+//! GraphQL模块
+//! /graphql 由db中的顶层集合(对象数组)推断出schema，与REST接口共用同一份数据：
+//!     type Post { id: ID! title: String ... }     字段类型由各元素的值推断，无法统一的为 JSON 标量
+//!     posts(filter: PostFilter, q: String, page: Int, perPage: Int, sortField: String, sortOrder: String): [Post!]!
+//!     post(id: ID!): Post
+//!     _postsMeta(filter: PostFilter, q: String): ListMetadata!    过滤后的总数，用于分页
+//!     createPost(...): Post   updatePost(id: ID!, ...): Post   deletePost(id: ID!): Post
+//! filter 的字段与REST的查询参数一致：title title_ne title_gte title_lte title_like。
+//! 外键(如 comments[].postId)生成关联字段 Comment.post 与 Post.comments，后者同样支持列表参数。
+//! POST 或 GET ?query= 执行，GET 只能执行查询；GET 不带query时返回schema的SDL。
+//! 支持变量、别名、片段、@skip/@include 及内省，不支持订阅(数据变更可用 /_actions/watch)。
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use graphql_parser::query::{
+    self as ast, Definition, Document, FragmentDefinition, OperationDefinition, Selection,
+    SelectionSet, TypeCondition,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::api::QueryKeys;
+use crate::db::{self, Database};
+use crate::error::Error;
+use crate::journal::Entry;
+use crate::query::ListQuery;
+use crate::relation;
+
+type Field<'a> = ast::Field<'a, String>;
+
+/// 内置及固定的类型名，集合推断出的类型名与之冲突时跳过该集合
+const RESERVED: &[&str] = &[
+    "ID",
+    "Int",
+    "Float",
+    "String",
+    "Boolean",
+    "JSON",
+    "ListMetadata",
+    "Query",
+    "Mutation",
+];
+
+/// 展开所有片段后一个操作最多选择的字段数
+const MAX_FIELDS: usize = 10_000;
+
+/// 列表查询参数与REST查询参数的对应关系
+const LIST_ARGS: &[(&str, &str)] = &[
+    ("q", "q"),
+    ("page", "_page"),
+    ("perPage", "_limit"),
+    ("sortField", "_sort"),
+    ("sortOrder", "_order"),
+];
+
+/// 一次GraphQL请求
+#[derive(Debug, Clone, Deserialize)]
+pub struct Request {
+    pub query: String,
+    #[serde(default)]
+    pub variables: Option<Value>,
+    #[serde(default, rename = "operationName")]
+    pub operation_name: Option<String>,
+}
+
+/// 请求级别的错误，响应400且没有data
+pub fn request_error(message: &str) -> Value {
+    json!({"errors": [{"message": message}]})
+}
+
+impl Request {
+    /// 由GET的查询字符串解析，没有query参数时为None
+    pub fn from_query(query_string: &str) -> Result<Option<Request>, Value> {
+        let params = actix_web::web::Query::<HashMap<String, String>>::from_query(query_string)
+            .map_err(|e| request_error(&format!("invalid query string: {}", e)))?
+            .into_inner();
+        let query = match params.get("query") {
+            Some(query) => query.clone(),
+            None => return Ok(None),
+        };
+        let variables = match params.get("variables") {
+            Some(variables) => Some(
+                serde_json::from_str(variables)
+                    .map_err(|e| request_error(&format!("invalid variables: {}", e)))?,
+            ),
+            None => None,
+        };
+        Ok(Some(Request {
+            query,
+            variables,
+            operation_name: params.get("operationName").cloned(),
+        }))
+    }
+
+    /// 由POST的请求体解析，application/graphql 时请求体即为查询
+    pub fn from_body(content_type: &str, body: &[u8]) -> Result<Request, Value> {
+        if content_type == "application/graphql" {
+            let query = String::from_utf8(body.to_vec())
+                .map_err(|_| request_error("query is not valid utf-8"))?;
+            return Ok(Request {
+                query,
+                variables: None,
+                operation_name: None,
+            });
+        }
+        serde_json::from_slice(body).map_err(|e| request_error(&format!("invalid request: {}", e)))
+    }
+}
+
+/// 由元素的值推断的标量类型
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scalar {
+    Id,
+    Int,
+    Float,
+    String,
+    Boolean,
+    Json,
+}
+
+impl Scalar {
+    fn name(self) -> &'static str {
+        match self {
+            Scalar::Id => "ID",
+            Scalar::Int => "Int",
+            Scalar::Float => "Float",
+            Scalar::String => "String",
+            Scalar::Boolean => "Boolean",
+            Scalar::Json => "JSON",
+        }
+    }
+}
+
+/// 字段值的形状，Unknown表示只出现过null
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Unknown,
+    Scalar(Scalar),
+    List(Scalar),
+}
+
+fn shape_of(value: &Value) -> Shape {
+    match value {
+        Value::Null => Shape::Unknown,
+        Value::Bool(_) => Shape::Scalar(Scalar::Boolean),
+        // GraphQL的Int为32位，超出的(如毫秒时间戳)按Float处理
+        Value::Number(n) => match n.as_i64() {
+            Some(i) if i32::try_from(i).is_ok() => Shape::Scalar(Scalar::Int),
+            _ => Shape::Scalar(Scalar::Float),
+        },
+        Value::String(_) => Shape::Scalar(Scalar::String),
+        Value::Object(_) => Shape::Scalar(Scalar::Json),
+        Value::Array(items) => match items.iter().map(shape_of).fold(Shape::Unknown, merge) {
+            Shape::Scalar(scalar) => Shape::List(scalar),
+            _ => Shape::List(Scalar::Json),
+        },
+    }
+}
+
+fn merge(a: Shape, b: Shape) -> Shape {
+    match (a, b) {
+        (Shape::Unknown, shape) | (shape, Shape::Unknown) => shape,
+        (a, b) if a == b => a,
+        (Shape::Scalar(Scalar::Int), Shape::Scalar(Scalar::Float))
+        | (Shape::Scalar(Scalar::Float), Shape::Scalar(Scalar::Int)) => {
+            Shape::Scalar(Scalar::Float)
+        }
+        (Shape::List(a), Shape::List(b)) => match merge(Shape::Scalar(a), Shape::Scalar(b)) {
+            Shape::Scalar(scalar) => Shape::List(scalar),
+            _ => Shape::List(Scalar::Json),
+        },
+        _ => Shape::Scalar(Scalar::Json),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    /// 元素自身的字段
+    Value(Shape),
+    /// 按外键key查找collection中的父元素
+    Parent { collection: String, key: String },
+    /// collection中外键指向本元素的所有元素
+    Children { collection: String },
+}
+
+#[derive(Debug, Clone)]
+struct Property {
+    name: String,
+    kind: Kind,
+}
+
+/// 由一个顶层集合推断出的类型
+#[derive(Debug, Clone)]
+struct Collection {
+    name: String,
+    type_name: String,
+    // 按id查询单个元素的字段名
+    single: String,
+    properties: Vec<Property>,
+}
+
+impl Collection {
+    fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|p| p.name == name)
+    }
+
+    fn filter_name(&self) -> String {
+        format!("{}Filter", self.type_name)
+    }
+
+    fn meta_name(&self) -> String {
+        format!("_{}Meta", self.name)
+    }
+}
+
+/// 类型引用，与内省中的 __Type 对应
+#[derive(Debug, Clone)]
+enum TypeRef {
+    Named(String),
+    List(Box<TypeRef>),
+    NonNull(Box<TypeRef>),
+}
+
+fn named(name: &str) -> TypeRef {
+    TypeRef::Named(name.to_string())
+}
+
+fn list_of(ty: TypeRef) -> TypeRef {
+    TypeRef::List(Box::new(ty))
+}
+
+fn non_null(ty: TypeRef) -> TypeRef {
+    TypeRef::NonNull(Box::new(ty))
+}
+
+impl std::fmt::Display for TypeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TypeRef::Named(name) => write!(f, "{}", name),
+            TypeRef::List(ty) => write!(f, "[{}]", ty),
+            TypeRef::NonNull(ty) => write!(f, "{}!", ty),
+        }
+    }
+}
+
+struct InputDef {
+    name: String,
+    ty: TypeRef,
+}
+
+fn input(name: &str, ty: TypeRef) -> InputDef {
+    InputDef {
+        name: name.to_string(),
+        ty,
+    }
+}
+
+struct FieldDef {
+    name: String,
+    args: Vec<InputDef>,
+    ty: TypeRef,
+}
+
+enum TypeKind {
+    Scalar,
+    Object(Vec<FieldDef>),
+    Input(Vec<InputDef>),
+}
+
+struct TypeDef {
+    name: String,
+    kind: TypeKind,
+}
+
+/// GraphQL名称：[_A-Za-z][_0-9A-Za-z]*，且不能以 __ 开头
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        && !name.starts_with("__")
+}
+
+/// blog_post -> BlogPost
+fn pascal_case(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// 由当前数据推断的schema
+pub struct Schema {
+    collections: Vec<Collection>,
+}
+
+impl Schema {
+    pub fn infer(root: &Value) -> Schema {
+        let mut collections: Vec<Collection> = vec![];
+        let mut type_names: Vec<String> = RESERVED.iter().map(|name| name.to_string()).collect();
+        if let Value::Object(map) = root {
+            for (name, value) in map {
+                let items = match value {
+                    Value::Array(items) if is_name(name) && db::is_collection(items) => items,
+                    _ => continue,
+                };
+                let type_name = pascal_case(&relation::singular(name));
+                let filter_name = format!("{}Filter", type_name);
+                if !is_name(&type_name)
+                    || type_names.contains(&type_name)
+                    || type_names.contains(&filter_name)
+                {
+                    continue;
+                }
+                type_names.push(type_name.clone());
+                type_names.push(filter_name);
+                let mut shapes: Vec<(String, Shape)> = vec![];
+                for (key, value) in items.iter().filter_map(Value::as_object).flatten() {
+                    if key == "id" || !is_name(key) {
+                        continue;
+                    }
+                    match shapes.iter_mut().find(|(name, _)| name == key) {
+                        Some((_, shape)) => *shape = merge(*shape, shape_of(value)),
+                        None => shapes.push((key.clone(), shape_of(value))),
+                    }
+                }
+                let mut properties = vec![Property {
+                    name: "id".to_string(),
+                    kind: Kind::Value(Shape::Scalar(Scalar::Id)),
+                }];
+                properties.extend(shapes.into_iter().map(|(name, shape)| Property {
+                    name,
+                    kind: Kind::Value(shape),
+                }));
+                let mut single = relation::singular(name);
+                if single == *name {
+                    single = format!("{}ById", name);
+                }
+                collections.push(Collection {
+                    name: name.clone(),
+                    type_name,
+                    single,
+                    properties,
+                });
+            }
+        }
+        // 外键生成双向的关联字段，与已有字段重名时跳过
+        let mut relations = vec![];
+        for (child, c) in collections.iter().enumerate() {
+            for (parent, p) in collections.iter().enumerate() {
+                let key = relation::foreign_key(&p.name);
+                if c.property(&key).is_none() {
+                    continue;
+                }
+                let parent_field = relation::singular(&p.name);
+                relations.push((
+                    child,
+                    Property {
+                        name: parent_field,
+                        kind: Kind::Parent {
+                            collection: p.name.clone(),
+                            key,
+                        },
+                    },
+                ));
+                relations.push((
+                    parent,
+                    Property {
+                        name: c.name.clone(),
+                        kind: Kind::Children {
+                            collection: c.name.clone(),
+                        },
+                    },
+                ));
+            }
+        }
+        for (idx, property) in relations {
+            if collections[idx].property(&property.name).is_none() {
+                collections[idx].properties.push(property);
+            }
+        }
+        Schema { collections }
+    }
+
+    fn collection(&self, name: &str) -> Option<&Collection> {
+        self.collections.iter().find(|c| c.name == name)
+    }
+
+    fn value_type(shape: Shape) -> TypeRef {
+        match shape {
+            Shape::Scalar(Scalar::Id) => non_null(named("ID")),
+            Shape::Scalar(scalar) => named(scalar.name()),
+            Shape::List(scalar) => list_of(named(scalar.name())),
+            Shape::Unknown => named("JSON"),
+        }
+    }
+
+    fn list_args(collection: &Collection) -> Vec<InputDef> {
+        let mut args = vec![input("filter", named(&collection.filter_name()))];
+        for (arg, _) in LIST_ARGS {
+            let ty = if arg.starts_with("page") || *arg == "perPage" {
+                "Int"
+            } else {
+                "String"
+            };
+            args.push(input(arg, named(ty)));
+        }
+        args
+    }
+
+    /// 写入的参数：元素自身的所有字段，均可为空
+    fn value_args(collection: &Collection) -> Vec<InputDef> {
+        collection
+            .properties
+            .iter()
+            .filter_map(|p| match p.kind {
+                Kind::Value(Shape::Scalar(Scalar::Id)) => Some(input(&p.name, named("ID"))),
+                Kind::Value(shape) => Some(input(&p.name, Schema::value_type(shape))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn types(&self) -> Vec<TypeDef> {
+        let mut types: Vec<TypeDef> = ["ID", "Int", "Float", "String", "Boolean", "JSON"]
+            .iter()
+            .map(|name| TypeDef {
+                name: name.to_string(),
+                kind: TypeKind::Scalar,
+            })
+            .collect();
+        types.push(TypeDef {
+            name: "ListMetadata".to_string(),
+            kind: TypeKind::Object(vec![FieldDef {
+                name: "count".to_string(),
+                args: vec![],
+                ty: non_null(named("Int")),
+            }]),
+        });
+        let mut queries = vec![];
+        let mut mutations = vec![];
+        for c in self.collections.iter() {
+            let mut fields = vec![];
+            let mut filters = vec![];
+            for p in c.properties.iter() {
+                let (args, ty) = match &p.kind {
+                    Kind::Value(shape) => (vec![], Schema::value_type(*shape)),
+                    Kind::Parent { collection, .. } => {
+                        let parent = self.collection(collection).unwrap();
+                        (vec![], named(&parent.type_name))
+                    }
+                    Kind::Children { collection } => {
+                        let child = self.collection(collection).unwrap();
+                        (
+                            Schema::list_args(child),
+                            non_null(list_of(non_null(named(&child.type_name)))),
+                        )
+                    }
+                };
+                fields.push(FieldDef {
+                    name: p.name.clone(),
+                    args,
+                    ty,
+                });
+                if let Kind::Value(Shape::Scalar(scalar)) = p.kind {
+                    if scalar == Scalar::Json {
+                        continue;
+                    }
+                    for suffix in ["", "_ne", "_gte", "_lte"].iter() {
+                        filters.push(input(
+                            &format!("{}{}", p.name, suffix),
+                            named(scalar.name()),
+                        ));
+                    }
+                    filters.push(input(&format!("{}_like", p.name), named("String")));
+                }
+            }
+            let ty = named(&c.type_name);
+            types.push(TypeDef {
+                name: c.type_name.clone(),
+                kind: TypeKind::Object(fields),
+            });
+            types.push(TypeDef {
+                name: c.filter_name(),
+                kind: TypeKind::Input(filters),
+            });
+            let id_arg = || vec![input("id", non_null(named("ID")))];
+            queries.push(FieldDef {
+                name: c.name.clone(),
+                args: Schema::list_args(c),
+                ty: non_null(list_of(non_null(ty.clone()))),
+            });
+            queries.push(FieldDef {
+                name: c.single.clone(),
+                args: id_arg(),
+                ty: ty.clone(),
+            });
+            queries.push(FieldDef {
+                name: c.meta_name(),
+                args: Schema::list_args(c).into_iter().take(2).collect(),
+                ty: non_null(named("ListMetadata")),
+            });
+            mutations.push(FieldDef {
+                name: format!("create{}", c.type_name),
+                args: Schema::value_args(c),
+                ty: ty.clone(),
+            });
+            let mut update_args = id_arg();
+            update_args.extend(
+                Schema::value_args(c)
+                    .into_iter()
+                    .filter(|arg| arg.name != "id"),
+            );
+            mutations.push(FieldDef {
+                name: format!("update{}", c.type_name),
+                args: update_args,
+                ty: ty.clone(),
+            });
+            mutations.push(FieldDef {
+                name: format!("delete{}", c.type_name),
+                args: id_arg(),
+                ty,
+            });
+        }
+        types.push(TypeDef {
+            name: "Query".to_string(),
+            kind: TypeKind::Object(queries),
+        });
+        if !mutations.is_empty() {
+            types.push(TypeDef {
+                name: "Mutation".to_string(),
+                kind: TypeKind::Object(mutations),
+            });
+        }
+        types
+    }
+
+    /// schema的SDL，内置标量不输出
+    pub fn sdl(&self) -> String {
+        let args = |args: &[InputDef]| {
+            if args.is_empty() {
+                return String::new();
+            }
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| format!("{}: {}", arg.name, arg.ty))
+                .collect();
+            format!("({})", args.join(", "))
+        };
+        let mut blocks = vec![];
+        for ty in self.types() {
+            match ty.kind {
+                TypeKind::Scalar if ty.name == "JSON" => blocks.push("scalar JSON".to_string()),
+                TypeKind::Scalar => {}
+                TypeKind::Object(fields) => {
+                    let fields: Vec<String> = fields
+                        .iter()
+                        .map(|f| format!("  {}{}: {}", f.name, args(&f.args), f.ty))
+                        .collect();
+                    blocks.push(format!("type {} {{\n{}\n}}", ty.name, fields.join("\n")));
+                }
+                TypeKind::Input(fields) => {
+                    if fields.is_empty() {
+                        // 没有可过滤的字段时仍需声明，GraphQL不允许空的input
+                        blocks.push(format!("input {} {{\n  id: ID\n}}", ty.name));
+                        continue;
+                    }
+                    let fields: Vec<String> = fields
+                        .iter()
+                        .map(|f| format!("  {}: {}", f.name, f.ty))
+                        .collect();
+                    blocks.push(format!("input {} {{\n{}\n}}", ty.name, fields.join("\n")));
+                }
+            }
+        }
+        blocks.join("\n\n") + "\n"
+    }
+
+    /// 内省结果(__schema)，各对象带有 __typename 以支持片段的类型条件
+    fn introspection(&self) -> Value {
+        let types = self.types();
+        let kinds: HashMap<String, &'static str> = types
+            .iter()
+            .map(|ty| {
+                let kind = match ty.kind {
+                    TypeKind::Scalar => "SCALAR",
+                    TypeKind::Object(_) => "OBJECT",
+                    TypeKind::Input(_) => "INPUT_OBJECT",
+                };
+                (ty.name.clone(), kind)
+            })
+            .collect();
+        fn type_ref(ty: &TypeRef, kinds: &HashMap<String, &'static str>) -> Value {
+            match ty {
+                TypeRef::Named(name) => json!({
+                    "__typename": "__Type",
+                    "kind": kinds.get(name).copied().unwrap_or("SCALAR"),
+                    "name": name,
+                    "ofType": null
+                }),
+                TypeRef::List(ty) => json!({
+                    "__typename": "__Type",
+                    "kind": "LIST",
+                    "name": null,
+                    "ofType": type_ref(ty, kinds)
+                }),
+                TypeRef::NonNull(ty) => json!({
+                    "__typename": "__Type",
+                    "kind": "NON_NULL",
+                    "name": null,
+                    "ofType": type_ref(ty, kinds)
+                }),
+            }
+        }
+        let input_value = |arg: &InputDef| {
+            json!({
+                "__typename": "__InputValue",
+                "name": arg.name,
+                "description": null,
+                "type": type_ref(&arg.ty, &kinds),
+                "defaultValue": null,
+                "isDeprecated": false,
+                "deprecationReason": null
+            })
+        };
+        let types: Vec<Value> = types
+            .iter()
+            .map(|ty| {
+                let mut value = json!({
+                    "__typename": "__Type",
+                    "kind": kinds[&ty.name],
+                    "name": ty.name,
+                    "description": null,
+                    "fields": null,
+                    "inputFields": null,
+                    "interfaces": null,
+                    "enumValues": null,
+                    "possibleTypes": null,
+                    "ofType": null,
+                    "specifiedByURL": null
+                });
+                match &ty.kind {
+                    TypeKind::Scalar => {}
+                    TypeKind::Object(fields) => {
+                        let fields: Vec<Value> = fields
+                            .iter()
+                            .map(|f| {
+                                json!({
+                                    "__typename": "__Field",
+                                    "name": f.name,
+                                    "description": null,
+                                    "args": f.args.iter().map(input_value).collect::<Vec<Value>>(),
+                                    "type": type_ref(&f.ty, &kinds),
+                                    "isDeprecated": false,
+                                    "deprecationReason": null
+                                })
+                            })
+                            .collect();
+                        value["fields"] = json!(fields);
+                        value["interfaces"] = json!([]);
+                    }
+                    TypeKind::Input(fields) => {
+                        value["inputFields"] =
+                            json!(fields.iter().map(input_value).collect::<Vec<Value>>());
+                    }
+                }
+                value
+            })
+            .collect();
+        let directive = |name: &str| {
+            json!({
+                "__typename": "__Directive",
+                "name": name,
+                "description": null,
+                "isRepeatable": false,
+                "locations": ["FIELD", "FRAGMENT_SPREAD", "INLINE_FRAGMENT"],
+                "args": [input_value(&input("if", non_null(named("Boolean"))))]
+            })
+        };
+        let mutation_type = if self.collections.is_empty() {
+            Value::Null
+        } else {
+            json!({"__typename": "__Type", "kind": "OBJECT", "name": "Mutation"})
+        };
+        json!({
+            "__typename": "__Schema",
+            "description": null,
+            "queryType": {"__typename": "__Type", "kind": "OBJECT", "name": "Query"},
+            "mutationType": mutation_type,
+            "subscriptionType": null,
+            "types": types,
+            "directives": [directive("include"), directive("skip")]
+        })
+    }
+}
+
+/// 按顺序收集的字段：响应中的key及同名的所有字段(子选择集合并)
+type Collected<'a> = Vec<(String, Vec<&'a Field<'a>>)>;
+
+struct Executor<'a> {
+    schema: &'a Schema,
+    fragments: HashMap<&'a str, &'a FragmentDefinition<'a, String>>,
+    variables: Map<String, Value>,
+    errors: Vec<Value>,
+    introspection: Option<Value>,
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// 合并同名字段的子选择集
+fn sub_selections<'a>(fields: &[&'a Field<'a>]) -> Vec<&'a Selection<'a, String>> {
+    fields
+        .iter()
+        .flat_map(|field| field.selection_set.items.iter())
+        .collect()
+}
+
+impl<'a> Executor<'a> {
+    fn error(&mut self, field: &Field, message: String, extensions: Option<Value>) {
+        let mut error = json!({
+            "message": message,
+            "locations": [{"line": field.position.line, "column": field.position.column}]
+        });
+        if let Some(extensions) = extensions {
+            error["extensions"] = extensions;
+        }
+        // 列表中每个元素都会报告同一字段的错误，只保留一次
+        if !self.errors.contains(&error) {
+            self.errors.push(error);
+        }
+    }
+
+    /// 写入失败等由db返回的错误
+    fn db_error(&mut self, field: &Field, e: Error) {
+        self.error(field, e.to_string(), Some(e.problem()));
+    }
+
+    fn to_json(&self, value: &ast::Value<String>) -> Value {
+        match value {
+            ast::Value::Variable(name) => self.variables.get(name).cloned().unwrap_or(Value::Null),
+            ast::Value::Int(n) => json!(n.as_i64()),
+            ast::Value::Float(f) => json!(f),
+            ast::Value::String(s) => json!(s),
+            ast::Value::Boolean(b) => json!(b),
+            ast::Value::Null => Value::Null,
+            ast::Value::Enum(e) => json!(e),
+            ast::Value::List(items) => {
+                Value::Array(items.iter().map(|v| self.to_json(v)).collect())
+            }
+            ast::Value::Object(map) => Value::Object(
+                map.iter()
+                    .map(|(key, v)| (key.clone(), self.to_json(v)))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn args(&self, field: &Field) -> Map<String, Value> {
+        field
+            .arguments
+            .iter()
+            .map(|(name, value)| (name.clone(), self.to_json(value)))
+            .collect()
+    }
+
+    /// @skip(if:) 与 @include(if:)
+    fn included(&self, directives: &[ast::Directive<String>]) -> bool {
+        directives.iter().all(|directive| {
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name == "if")
+                .map(|(_, value)| self.to_json(value).as_bool() == Some(true));
+            !matches!(
+                (directive.name.as_str(), condition),
+                ("skip", Some(true)) | ("include", Some(false))
+            )
+        })
+    }
+
+    /// 展开片段并按响应key合并字段，type_name为空时不检查类型条件
+    /// 片段已在执行前检查过，没有循环引用
+    fn collect(
+        &self,
+        selections: &[&'a Selection<'a, String>],
+        type_name: &str,
+        collected: &mut Collected<'a>,
+    ) {
+        let matches = |condition: &Option<TypeCondition<'a, String>>| match condition {
+            Some(TypeCondition::On(on)) => type_name.is_empty() || on == type_name,
+            None => true,
+        };
+        for selection in selections {
+            match selection {
+                Selection::Field(field) => {
+                    if !self.included(&field.directives) {
+                        continue;
+                    }
+                    let key = field.alias.as_ref().unwrap_or(&field.name);
+                    match collected.iter_mut().find(|(k, _)| k == key) {
+                        Some((_, fields)) => fields.push(field),
+                        None => collected.push((key.clone(), vec![field])),
+                    }
+                }
+                Selection::InlineFragment(fragment) => {
+                    if self.included(&fragment.directives) && matches(&fragment.type_condition) {
+                        let items: Vec<_> = fragment.selection_set.items.iter().collect();
+                        self.collect(&items, type_name, collected);
+                    }
+                }
+                Selection::FragmentSpread(spread) => {
+                    if !self.included(&spread.directives) {
+                        continue;
+                    }
+                    if let Some(fragment) = self.fragments.get(spread.fragment_name.as_str()) {
+                        if matches(&Some(fragment.type_condition.clone())) {
+                            let items: Vec<_> = fragment.selection_set.items.iter().collect();
+                            self.collect(&items, type_name, collected);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn collect_fields(
+        &self,
+        selections: &[&'a Selection<'a, String>],
+        type_name: &str,
+    ) -> Collected<'a> {
+        let mut collected = vec![];
+        self.collect(selections, type_name, &mut collected);
+        collected
+    }
+
+    /// 按选择集裁剪普通json(内省结果、ListMetadata)
+    fn project(&self, value: &Value, selections: &[&'a Selection<'a, String>]) -> Value {
+        match value {
+            Value::Array(items) => Value::Array(
+                items
+                    .iter()
+                    .map(|item| self.project(item, selections))
+                    .collect(),
+            ),
+            Value::Object(map) if !selections.is_empty() => {
+                let type_name = map.get("__typename").and_then(Value::as_str).unwrap_or("");
+                let mut res = Map::new();
+                for (key, fields) in self.collect_fields(selections, type_name) {
+                    let sub = sub_selections(&fields);
+                    let value = match map.get(fields[0].name.as_str()) {
+                        Some(value) => self.project(value, &sub),
+                        None => Value::Null,
+                    };
+                    res.insert(key, value);
+                }
+                Value::Object(res)
+            }
+            other => other.clone(),
+        }
+    }
+
+    /// 集合中的元素，关联字段按需查找
+    fn item(
+        &mut self,
+        root: &Value,
+        collection: &'a Collection,
+        item: &Value,
+        selections: &[&'a Selection<'a, String>],
+    ) -> Value {
+        let mut res = Map::new();
+        for (key, fields) in self.collect_fields(selections, &collection.type_name) {
+            let field = fields[0];
+            let sub = sub_selections(&fields);
+            let value = if field.name == "__typename" {
+                json!(collection.type_name)
+            } else {
+                match collection.property(&field.name).map(|p| &p.kind) {
+                    Some(Kind::Value(_)) => item.get(&field.name).cloned().unwrap_or(Value::Null),
+                    Some(Kind::Parent {
+                        collection: name,
+                        key,
+                    }) => {
+                        let parent = self.schema.collection(name).unwrap();
+                        let found = match (root.get(name), item.get(key)) {
+                            (Some(Value::Array(items)), Some(id)) => {
+                                db::find_by_id(items, &db::id_string(id)).map(|idx| &items[idx])
+                            }
+                            _ => None,
+                        };
+                        match found {
+                            Some(found) => self.item(root, parent, found, &sub),
+                            None => Value::Null,
+                        }
+                    }
+                    Some(Kind::Children { collection: name }) => {
+                        let child = self.schema.collection(name).unwrap();
+                        let id = item.get("id").cloned().unwrap_or(Value::Null);
+                        let items = relation::children(root, name, &collection.name, &id)
+                            .unwrap_or_default();
+                        let args = self.args(field);
+                        self.list(root, child, &items, &args, &sub, field)
+                    }
+                    None => {
+                        let message = format!(
+                            "Cannot query field \"{}\" on type \"{}\"",
+                            field.name, collection.type_name
+                        );
+                        self.error(field, message, None);
+                        Value::Null
+                    }
+                }
+            };
+            res.insert(key, value);
+        }
+        Value::Object(res)
+    }
+
+    /// 列表参数转为REST的查询参数，filter中的列表表示"或"
+    fn list_query(args: &Map<String, Value>, paginate: bool) -> Result<ListQuery, Error> {
+        let mut params = vec![];
+        if let Some(Value::Object(filter)) = args.get("filter") {
+            for (key, value) in filter {
+                match value {
+                    Value::Null => {}
+                    Value::Array(values) => {
+                        params.extend(values.iter().map(|v| (key.clone(), text(v))));
+                    }
+                    value => params.push((key.clone(), text(value))),
+                }
+            }
+        }
+        for (arg, param) in LIST_ARGS
+            .iter()
+            .take(if paginate { LIST_ARGS.len() } else { 1 })
+        {
+            match args.get(*arg) {
+                Some(Value::Null) | None => {}
+                Some(value) => params.push((param.to_string(), text(value))),
+            }
+        }
+        ListQuery::from_params(&params)
+    }
+
+    fn list(
+        &mut self,
+        root: &Value,
+        collection: &'a Collection,
+        items: &[Value],
+        args: &Map<String, Value>,
+        selections: &[&'a Selection<'a, String>],
+        field: &Field,
+    ) -> Value {
+        match Executor::list_query(args, true).map(|query| query.apply(items)) {
+            Ok(result) => Value::Array(
+                result
+                    .items
+                    .iter()
+                    .map(|item| self.item(root, collection, item, selections))
+                    .collect(),
+            ),
+            Err(e) => {
+                self.db_error(field, e);
+                Value::Null
+            }
+        }
+    }
+
+    fn query_field(
+        &mut self,
+        root: &Value,
+        field: &'a Field<'a>,
+        sub: &[&'a Selection<'a, String>],
+    ) -> Value {
+        let schema = self.schema;
+        let name = field.name.as_str();
+        if name == "__typename" {
+            return json!("Query");
+        }
+        if name == "__schema" || name == "__type" {
+            let introspection = self
+                .introspection
+                .get_or_insert_with(|| schema.introspection())
+                .clone();
+            if name == "__schema" {
+                return self.project(&introspection, sub);
+            }
+            let type_name = self.args(field).get("name").map(text).unwrap_or_default();
+            return match introspection["types"]
+                .as_array()
+                .and_then(|types| types.iter().find(|ty| ty["name"] == type_name.as_str()))
+            {
+                Some(ty) => self.project(ty, sub),
+                None => Value::Null,
+            };
+        }
+        let args = self.args(field);
+        let items = |collection: &Collection| match root.get(&collection.name) {
+            Some(Value::Array(items)) => items.clone(),
+            _ => vec![],
+        };
+        if let Some(collection) = schema.collections.iter().find(|c| c.name == name) {
+            return self.list(root, collection, &items(collection), &args, sub, field);
+        }
+        if let Some(collection) = schema.collections.iter().find(|c| c.single == name) {
+            let id = args.get("id").map(db::id_string).unwrap_or_default();
+            let items = items(collection);
+            return match db::find_by_id(&items, &id) {
+                Some(idx) => self.item(root, collection, &items[idx], sub),
+                None => Value::Null,
+            };
+        }
+        if let Some(collection) = schema.collections.iter().find(|c| c.meta_name() == name) {
+            return match Executor::list_query(&args, false)
+                .map(|query| query.apply(&items(collection)))
+            {
+                Ok(result) => self.project(
+                    &json!({"__typename": "ListMetadata", "count": result.total}),
+                    sub,
+                ),
+                Err(e) => {
+                    self.db_error(field, e);
+                    Value::Null
+                }
+            };
+        }
+        self.error(
+            field,
+            format!("Cannot query field \"{}\" on type \"Query\"", name),
+            None,
+        );
+        Value::Null
+    }
+
+    /// 写入的参数只能是元素自身的字段
+    fn values(&mut self, collection: &Collection, field: &Field) -> Option<Map<String, Value>> {
+        let args = self.args(field);
+        for name in args.keys() {
+            if !matches!(
+                collection.property(name),
+                Some(Property {
+                    kind: Kind::Value(_),
+                    ..
+                })
+            ) {
+                let message = format!("Unknown argument \"{}\" on field \"{}\"", name, field.name);
+                self.error(field, message, None);
+                return None;
+            }
+        }
+        Some(args)
+    }
+
+    fn mutation_field(
+        &mut self,
+        data: &Database,
+        root: &mut Value,
+        field: &'a Field<'a>,
+        sub: &[&'a Selection<'a, String>],
+    ) -> Value {
+        let schema = self.schema;
+        let name = field.name.as_str();
+        if name == "__typename" {
+            return json!("Mutation");
+        }
+        let found = ["create", "update", "delete"].iter().find_map(|op| {
+            let type_name = name.strip_prefix(op)?;
+            let collection = schema
+                .collections
+                .iter()
+                .find(|c| c.type_name == type_name)?;
+            Some((*op, collection))
+        });
+        let (op, collection) = match found {
+            Some(found) => found,
+            None => {
+                self.error(
+                    field,
+                    format!("Cannot query field \"{}\" on type \"Mutation\"", name),
+                    None,
+                );
+                return Value::Null;
+            }
+        };
+        let mut args = match self.values(collection, field) {
+            Some(args) => args,
+            None => return Value::Null,
+        };
+        let res = match op {
+            "create" => create(data, root, collection, args),
+            _ => {
+                let id = args
+                    .remove("id")
+                    .map(|id| db::id_string(&id))
+                    .unwrap_or_default();
+                if op == "update" {
+                    update(data, root, collection, &id, args)
+                } else {
+                    delete(data, root, collection, &id)
+                }
+            }
+        };
+        match res {
+            Ok(item) => self.item(root, collection, &item, sub),
+            Err(e) => {
+                self.db_error(field, e);
+                Value::Null
+            }
+        }
+    }
+}
+
+fn index_of(root: &Value, collection: &Collection, id: &str) -> Result<usize, Error> {
+    match root.get(&collection.name) {
+        Some(Value::Array(items)) => db::find_by_id(items, id),
+        _ => None,
+    }
+    .ok_or_else(|| Error::IdNotFound { id: id.to_string() })
+}
+
+/// 新增元素，集合中的id为数字时将字符串形式的id转为数字
+fn create(
+    data: &Database,
+    root: &mut Value,
+    collection: &Collection,
+    mut args: Map<String, Value>,
+) -> Result<Value, Error> {
+    let path = format!("/{}", collection.name);
+    let numeric = root
+        .pointer(&format!("{}/0/id", path))
+        .is_some_and(Value::is_number);
+    if let Some(Value::String(id)) = args.get("id") {
+        if let (true, Ok(id)) = (numeric, id.parse::<u64>()) {
+            args.insert("id".to_string(), json!(id));
+        }
+    }
+    let (idx, item) = data.checked(&path, root, |root| {
+        Database::append(&mut QueryKeys::from_ptr(&path), root, Value::Object(args))
+    })?;
+    let entry = Entry::Insert {
+        path: format!("{}/{}", path, idx),
+        value: item.clone(),
+    };
+    data.record(&entry, root, None);
+    Ok(item)
+}
+
+/// 按id合并更新，参数为null的字段会被删除
+fn update(
+    data: &Database,
+    root: &mut Value,
+    collection: &Collection,
+    id: &str,
+    args: Map<String, Value>,
+) -> Result<Value, Error> {
+    let path = format!("/{}/{}", collection.name, index_of(root, collection, id)?);
+    let value = Value::Object(args);
+    let old = data.previous(root, &path, false);
+    data.checked(&path, root, |root| {
+        Database::merge_patch(&mut QueryKeys::from_ptr(&path), root, value.clone())
+    })?;
+    let entry = Entry::MergePatch {
+        path: path.clone(),
+        value,
+    };
+    data.record(&entry, root, old);
+    Ok(root.pointer(&path).cloned().unwrap_or(Value::Null))
+}
+
+/// 按id删除，返回被删除的元素
+fn delete(
+    data: &Database,
+    root: &mut Value,
+    collection: &Collection,
+    id: &str,
+) -> Result<Value, Error> {
+    let path = format!("/{}/{}", collection.name, index_of(root, collection, id)?);
+    let item = root.pointer(&path).cloned().unwrap_or(Value::Null);
+    let old = data.previous(root, &path, false);
+    data.checked(&path, root, |root| {
+        Database::delete(&mut QueryKeys::from_ptr(&path), root)
+    })?;
+    data.record(&Entry::Delete { path }, root, old);
+    Ok(item)
+}
+
+/// 展开所有片段后选择集中的字段数，是执行时每一层收集到的字段数的上限
+/// 片段循环引用或字段数超过MAX_FIELDS时返回请求错误，
+/// 否则 ...A ...A 这类重复展开会使字段数按指数增长
+fn expanded_size<'a>(
+    selection_set: &'a SelectionSet<'a, String>,
+    fragments: &HashMap<&'a str, &'a FragmentDefinition<'a, String>>,
+    path: &mut Vec<&'a str>,
+    sizes: &mut HashMap<&'a str, usize>,
+) -> Result<usize, Value> {
+    let mut size = 0usize;
+    for selection in selection_set.items.iter() {
+        let selected = match selection {
+            Selection::Field(field) => {
+                1 + expanded_size(&field.selection_set, fragments, path, sizes)?
+            }
+            Selection::InlineFragment(fragment) => {
+                expanded_size(&fragment.selection_set, fragments, path, sizes)?
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.fragment_name.as_str();
+                if path.contains(&name) {
+                    return Err(request_error(&format!(
+                        "cannot spread fragment \"{}\" within itself",
+                        name
+                    )));
+                }
+                match (sizes.get(name), fragments.get(name)) {
+                    (Some(size), _) => *size,
+                    (None, Some(fragment)) => {
+                        path.push(name);
+                        let size = expanded_size(&fragment.selection_set, fragments, path, sizes)?;
+                        path.pop();
+                        sizes.insert(name, size);
+                        size
+                    }
+                    // 未定义的片段在执行时忽略
+                    (None, None) => 0,
+                }
+            }
+        };
+        size += selected;
+        if size > MAX_FIELDS {
+            return Err(request_error(&format!(
+                "the query selects more than {} fields after expanding fragments",
+                MAX_FIELDS
+            )));
+        }
+    }
+    Ok(size)
+}
+
+/// 执行一次请求，请求本身有误(无法解析、找不到操作等)时返回Err
+/// allow_mutation为false(GET请求)时拒绝执行mutation
+pub fn execute(data: &Database, request: Request, allow_mutation: bool) -> Result<Value, Value> {
+    let document: Document<String> =
+        graphql_parser::parse_query(&request.query).map_err(|e| request_error(&e.to_string()))?;
+    let mut operations = vec![];
+    let mut fragments = HashMap::new();
+    for definition in document.definitions.iter() {
+        match definition {
+            Definition::Operation(operation) => operations.push(operation),
+            Definition::Fragment(fragment) => {
+                fragments.insert(fragment.name.as_str(), fragment);
+            }
+        }
+    }
+    // 与GraphQL规范一致，所有片段都不能循环引用，不只是被操作用到的
+    let mut sizes = HashMap::new();
+    for definition in document.definitions.iter() {
+        if let Definition::Fragment(fragment) = definition {
+            let path = &mut vec![fragment.name.as_str()];
+            expanded_size(&fragment.selection_set, &fragments, path, &mut sizes)?;
+        }
+    }
+    let name_of = |operation: &OperationDefinition<String>| match operation {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(q) => q.name.clone(),
+        OperationDefinition::Mutation(m) => m.name.clone(),
+        OperationDefinition::Subscription(s) => s.name.clone(),
+    };
+    let operation = match (&request.operation_name, operations.len()) {
+        (Some(name), _) => operations
+            .into_iter()
+            .find(|operation| name_of(operation).as_ref() == Some(name))
+            .ok_or_else(|| request_error(&format!("unknown operation named \"{}\"", name)))?,
+        (None, 1) => operations[0],
+        (None, 0) => return Err(request_error("no operation in the document")),
+        (None, _) => {
+            return Err(request_error(
+                "operationName is required when the document contains multiple operations",
+            ))
+        }
+    };
+    let mut variables = match request.variables {
+        Some(Value::Object(variables)) => variables,
+        _ => Map::new(),
+    };
+    let (definitions, selection_set, is_mutation) = match operation {
+        OperationDefinition::SelectionSet(set) => (&[][..], set, false),
+        OperationDefinition::Query(q) => (&q.variable_definitions[..], &q.selection_set, false),
+        OperationDefinition::Mutation(m) => (&m.variable_definitions[..], &m.selection_set, true),
+        OperationDefinition::Subscription(_) => {
+            return Err(request_error(
+                "subscriptions are not supported, use /_actions/watch to follow changes",
+            ))
+        }
+    };
+    if is_mutation && !allow_mutation {
+        return Err(request_error("mutations must be sent with POST"));
+    }
+    expanded_size(selection_set, &fragments, &mut vec![], &mut sizes)?;
+    // 默认值中不能引用变量
+    let constants = Executor {
+        schema: &Schema {
+            collections: vec![],
+        },
+        fragments: HashMap::new(),
+        variables: Map::new(),
+        errors: vec![],
+        introspection: None,
+    };
+    for definition in definitions {
+        if let (false, Some(default)) = (
+            variables.contains_key(&definition.name),
+            &definition.default_value,
+        ) {
+            variables.insert(definition.name.clone(), constants.to_json(default));
+        }
+    }
+    let selections: Vec<_> = selection_set.items.iter().collect();
+    let mut res = Map::new();
+    let errors = if is_mutation {
+        // mutation的字段依次执行，整个操作期间持有写锁
+        let mut root = data.data.write();
+        let schema = Schema::infer(&root);
+        let mut executor = Executor {
+            schema: &schema,
+            fragments,
+            variables,
+            errors: vec![],
+            introspection: None,
+        };
+        for (key, fields) in executor.collect_fields(&selections, "Mutation") {
+            let sub = sub_selections(&fields);
+            let value = executor.mutation_field(data, &mut root, fields[0], &sub);
+            res.insert(key, value);
+        }
+        executor.errors
+    } else {
+        let root = data.data.read();
+        let schema = Schema::infer(&root);
+        let mut executor = Executor {
+            schema: &schema,
+            fragments,
+            variables,
+            errors: vec![],
+            introspection: None,
+        };
+        for (key, fields) in executor.collect_fields(&selections, "Query") {
+            let sub = sub_selections(&fields);
+            let value = executor.query_field(&root, fields[0], &sub);
+            res.insert(key, value);
+        }
+        executor.errors
+    };
+    let mut response = json!({ "data": res });
+    if !errors.is_empty() {
+        response["errors"] = json!(errors);
+    }
+    Ok(response)
+}
+
+/// 当前数据推断出的schema的SDL
+pub fn sdl(root: &Value) -> String {
+    Schema::infer(root).sdl()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+
+    fn db() -> Database {
+        Database::from_value(
+            String::new(),
+            json!({
+                "posts": [
+                    {"id": 1, "title": "hello", "views": 10},
+                    {"id": 2, "title": "world", "views": 2.5, "meta": {"a": 1}}
+                ],
+                "comments": [{"id": 1, "body": "c1", "postId": 1}],
+                "profile": {"name": "x"}
+            }),
+        )
+    }
+
+    fn run(data: &Database, query: &str, variables: Value) -> Value {
+        let request = Request {
+            query: query.to_string(),
+            variables: Some(variables),
+            operation_name: None,
+        };
+        execute(data, request, true).unwrap()
+    }
+
+    #[test]
+    fn test_schema() {
+        let data = db();
+        let sdl = sdl(&data.data.read());
+        assert!(
+            sdl.contains("type Post {\n  id: ID!\n  title: String\n  views: Float\n  meta: JSON\n")
+        );
+        assert!(sdl.contains("  post: Post\n"));
+        assert!(sdl.contains("  comments(filter: CommentFilter, q: String, page: Int, perPage: Int, sortField: String, sortOrder: String): [Comment!]!\n"));
+        assert!(sdl.contains("  post(id: ID!): Post\n"));
+        assert!(sdl.contains("  deletePost(id: ID!): Post\n"));
+        assert!(!sdl.contains("Profile"));
+
+        let res = run(
+            &data,
+            "{ __schema { queryType { name } types { name kind } } }",
+            json!({}),
+        );
+        assert_eq!(
+            res["data"]["__schema"]["queryType"],
+            json!({"name": "Query"})
+        );
+        assert!(res["data"]["__schema"]["types"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({"name": "PostFilter", "kind": "INPUT_OBJECT"})));
+    }
+
+    #[test]
+    fn test_query() {
+        let data = db();
+        let res = run(
+            &data,
+            "query($min: Int) { all: posts(filter: {views_gte: $min}) { ...post } _postsMeta { count } }
+             fragment post on Post { title comments { body post { id } } hidden: id @skip(if: true) }",
+            json!({"min": 5}),
+        );
+        assert_eq!(
+            res,
+            json!({"data": {
+                "all": [{"title": "hello", "comments": [{"body": "c1", "post": {"id": 1}}]}],
+                "_postsMeta": {"count": 2}
+            }})
+        );
+
+        let res = run(
+            &data,
+            "{ post(id: 2) { __typename title } posts { nope } }",
+            json!({}),
+        );
+        assert_eq!(
+            res["data"]["post"],
+            json!({"__typename": "Post", "title": "world"})
+        );
+        assert_eq!(res["errors"].as_array().unwrap().len(), 1);
+
+        // GET 不能执行mutation，请求本身有误时没有data
+        let request = Request {
+            query: "mutation { deletePost(id: 1) { id } }".to_string(),
+            variables: None,
+            operation_name: None,
+        };
+        assert!(execute(&data, request, false).is_err());
+        let request = Request {
+            query: "{ posts {".to_string(),
+            variables: None,
+            operation_name: None,
+        };
+        assert!(execute(&data, request, true).is_err());
+    }
+
+    #[test]
+    fn test_fragment_limits() {
+        let data = db();
+        let error = |query: &str| {
+            let request = Request {
+                query: query.to_string(),
+                variables: None,
+                operation_name: None,
+            };
+            execute(&data, request, true).unwrap_err()["errors"][0]["message"].clone()
+        };
+        assert_eq!(
+            error("{ posts { ...A } } fragment A on Post { id ...A ...A }"),
+            "cannot spread fragment \"A\" within itself"
+        );
+        // 没有被用到的片段同样不能循环引用
+        assert_eq!(
+            error("{ posts { id } } fragment A on Post { ...B } fragment B on Post { ...A }"),
+            "cannot spread fragment \"A\" within itself"
+        );
+        // 每层重复展开两次，不循环也会指数增长
+        let mut query = "{ posts { ...F0 } }".to_string();
+        for i in 0..20 {
+            query += &format!(" fragment F{} on Post {{ ...F{} ...F{} }}", i, i + 1, i + 1);
+        }
+        query += " fragment F20 on Post { id }";
+        assert_eq!(
+            error(&query),
+            "the query selects more than 10000 fields after expanding fragments"
+        );
+    }
+
+    #[test]
+    fn test_mutation() {
+        let data = db();
+        let mut receiver = data.watchers.subscribe(vec![]);
+        let res = run(
+            &data,
+            r#"mutation { createComment(body: "c2", postId: 2) { id post { title } }
+                          updatePost(id: "2", meta: null) { id meta }
+                          deletePost(id: 1) { title } }"#,
+            json!({}),
+        );
+        assert_eq!(
+            res,
+            json!({"data": {
+                "createComment": {"id": 2, "post": {"title": "world"}},
+                "updatePost": {"id": 2, "meta": null},
+                "deletePost": {"title": "hello"}
+            }})
+        );
+        assert_eq!(
+            data.data.read()["posts"],
+            json!([{"id": 2, "title": "world", "views": 2.5}])
+        );
+        let mut ops = vec![];
+        while let Some(Some(event)) = receiver.next().now_or_never() {
+            ops.push(event.op);
+        }
+        assert_eq!(ops, vec!["insert", "merge_patch", "delete"]);
+
+        let res = run(
+            &data,
+            "mutation { updatePost(id: 9, title: \"x\") { id } }",
+            json!({}),
+        );
+        assert_eq!(res["data"]["updatePost"], Value::Null);
+        assert_eq!(res["errors"][0]["extensions"]["code"], "id_not_found");
+    }
+}
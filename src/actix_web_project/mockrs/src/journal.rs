@@ -0,0 +1,273 @@
+//! 预写日志(write-ahead log)模块
+//! 每次修改数据都会先以一行json追加到日志文件，启动时在db文件的基础上重放日志即可恢复数据。
+//! 日志第一行记录了生成该日志时db文件内容的哈希，
+//! 压缩(快照)时先原子替换db文件再重置日志，若两步之间崩溃，哈希不匹配的旧日志会被直接忽略。
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufReader};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api::QueryKeys;
+use crate::db::Database;
+use crate::error::Error;
+use crate::patch;
+
+/// 一次数据修改，path为json pointer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Entry {
+    Insert {
+        path: String,
+        value: Value,
+    },
+    Delete {
+        path: String,
+    },
+    Replace {
+        path: String,
+        value: Value,
+    },
+    JsonPatch {
+        path: String,
+        ops: Vec<patch::Operation>,
+    },
+    MergePatch {
+        path: String,
+        value: Value,
+    },
+    /// /_actions/batch 的一组修改，作为一行写入，重放时同样整体生效
+    Batch {
+        entries: Vec<Entry>,
+    },
+}
+
+impl Entry {
+    /// 操作名，与序列化后的op字段一致
+    pub fn op(&self) -> &'static str {
+        match self {
+            Entry::Insert { .. } => "insert",
+            Entry::Delete { .. } => "delete",
+            Entry::Replace { .. } => "replace",
+            Entry::JsonPatch { .. } => "json_patch",
+            Entry::MergePatch { .. } => "merge_patch",
+            Entry::Batch { .. } => "batch",
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            Entry::Insert { path, .. }
+            | Entry::Delete { path }
+            | Entry::Replace { path, .. }
+            | Entry::JsonPatch { path, .. }
+            | Entry::MergePatch { path, .. } => path,
+            Entry::Batch { .. } => "",
+        }
+    }
+
+    /// 在json上重新执行本次修改
+    pub fn apply(self, json_obj: &mut Value) -> Result<(), Error> {
+        match self {
+            Entry::Insert { path, value } => {
+                Database::insert(&mut QueryKeys::from_ptr(&path), json_obj, value)
+            }
+            Entry::Delete { path } => Database::delete(&mut QueryKeys::from_ptr(&path), json_obj),
+            Entry::Replace { path, value } => {
+                Database::replace(&mut QueryKeys::from_ptr(&path), json_obj, value)
+            }
+            Entry::JsonPatch { path, ops } => {
+                Database::json_patch(&mut QueryKeys::from_ptr(&path), json_obj, ops)
+            }
+            Entry::MergePatch { path, value } => {
+                Database::merge_patch(&mut QueryKeys::from_ptr(&path), json_obj, value)
+            }
+            Entry::Batch { entries } => {
+                let mut working = json_obj.clone();
+                for entry in entries {
+                    entry.apply(&mut working)?;
+                }
+                *json_obj = working;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    base: String,
+}
+
+pub struct Journal {
+    file: String,
+    writer: File,
+    // 自上次快照以来追加的条目数
+    pending: usize,
+}
+
+/// FNV-1a 64位哈希，结果在不同编译器版本间保持稳定
+pub fn checksum(content: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in content {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+impl Journal {
+    /// 打开日志并返回其中与db文件内容匹配的条目，日志不存在或已过期时会重新创建
+    pub fn open(file: &str, db_content: &[u8]) -> io::Result<(Journal, Vec<Entry>)> {
+        let base = checksum(db_content);
+        let entries = match File::open(file) {
+            Ok(f) => Self::read_entries(f, &base, file)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let journal = match entries {
+            Some((ref entries, true)) => Journal {
+                file: file.to_string(),
+                writer: OpenOptions::new().append(true).open(file)?,
+                pending: entries.len(),
+            },
+            // 末尾有残缺行时重写日志，避免之后追加的条目跟在残缺行后面无法读取
+            Some((ref entries, false)) => {
+                let mut journal = Self::create(file, &base)?;
+                for entry in entries {
+                    journal.append(entry)?;
+                }
+                journal
+            }
+            None => Self::create(file, &base)?,
+        };
+        Ok((
+            journal,
+            entries.map(|(entries, _)| entries).unwrap_or_default(),
+        ))
+    }
+
+    fn create(file: &str, base: &str) -> io::Result<Journal> {
+        let mut writer = File::create(file)?;
+        let header = Header {
+            base: base.to_string(),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&header)?)?;
+        writer.sync_data()?;
+        Ok(Journal {
+            file: file.to_string(),
+            writer,
+            pending: 0,
+        })
+    }
+
+    /// 返回日志条目以及日志是否完整
+    fn read_entries(f: File, base: &str, file: &str) -> io::Result<Option<(Vec<Entry>, bool)>> {
+        let mut lines = BufReader::new(f).lines();
+        match lines.next() {
+            Some(line) => match serde_json::from_str::<Header>(&line?) {
+                Ok(ref header) if header.base == base => {}
+                _ => {
+                    warn!("Journal {:?} does not match db file, discard it", file);
+                    return Ok(None);
+                }
+            },
+            None => return Ok(None),
+        }
+        let mut entries = vec![];
+        for (no, line) in lines.enumerate() {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    // 通常是崩溃时写了一半的最后一行
+                    warn!("Skip broken journal line {} of {:?}: {}", no + 2, file, e);
+                    return Ok(Some((entries, false)));
+                }
+            }
+        }
+        Ok(Some((entries, true)))
+    }
+
+    /// 追加一条修改记录
+    pub fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.sync_data()?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// db文件已替换为新快照后，以新内容为基准清空日志
+    pub fn reset(&mut self, db_content: &[u8]) -> io::Result<()> {
+        *self = Self::create(&self.file, &checksum(db_content))?;
+        Ok(())
+    }
+}
+
+/// 先写入临时文件再重命名，保证目标文件要么是旧内容要么是完整的新内容
+pub fn write_atomic(file: &str, content: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", file);
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(content)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let file = std::env::temp_dir().join(format!("mockrs_journal_{}.log", std::process::id()));
+        let file = file.to_str().unwrap();
+        let _ = fs::remove_file(file);
+        let db = br#"{"posts":[]}"#;
+
+        let (mut journal, entries) = Journal::open(file, db).unwrap();
+        assert!(entries.is_empty());
+        journal
+            .append(&Entry::Insert {
+                path: "/posts/0".to_string(),
+                value: json!({"name": "a"}),
+            })
+            .unwrap();
+        journal
+            .append(&Entry::MergePatch {
+                path: "/posts/0".to_string(),
+                value: json!({"id": 1}),
+            })
+            .unwrap();
+        // 模拟崩溃时写了一半的行
+        OpenOptions::new()
+            .append(true)
+            .open(file)
+            .unwrap()
+            .write_all(b"{\"op\":\"del")
+            .unwrap();
+
+        let (mut journal, entries) = Journal::open(file, db).unwrap();
+        let mut data: Value = serde_json::from_slice(db).unwrap();
+        for entry in entries {
+            entry.apply(&mut data).unwrap();
+        }
+        assert_eq!(data, json!({"posts": [{"name": "a", "id": 1}]}));
+        assert_eq!(journal.pending(), 2);
+
+        // 快照之后db文件内容变化，旧日志不应再被重放
+        journal.reset(b"{}").unwrap();
+        let (_, entries) = Journal::open(file, db).unwrap();
+        assert!(entries.is_empty());
+        fs::remove_file(file).unwrap();
+    }
+}
@@ -3,17 +3,47 @@
 extern crate log;
 
 use std::io::{Error, ErrorKind, prelude::*};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 
+use actix_rt::time;
+use actix_web::dev::Service;
+use actix_web::client::Client;
 use actix_web::{App, HttpServer, middleware, web};
-use jen::generator::Generator;
+use futures::future::Either;
 use structopt::StructOpt;
 
 use dotenv::dotenv;
-use opt::Config;
+use opt::{Config, ServeConfig};
 
 mod api;
+mod assets;
+mod auth;
+mod batch;
+mod cassette;
+mod chaos;
+mod databases;
 mod db;
+mod error;
+mod etag;
+mod fixture;
+mod format;
+mod graphql;
+mod journal;
+mod monitor;
+mod openapi;
 mod opt;
+mod patch;
+mod query;
+mod relation;
+mod reload;
+mod routes;
+mod schema;
+mod store;
+mod template;
+mod tls;
+mod watch;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
@@ -28,59 +58,322 @@ async fn main() -> std::io::Result<()> {
 
     // 判断输入参数
     match config {
-        Config::Serve {
-            db_file,
-            host,
-            port,
-        } => run_server(db_file, host, port).await,
+        Config::Serve(config) => run_server(*config).await,
         Config::Gen {
             template,
-            output
-        } => generate_by_template(template, output),
+            openapi,
+            output,
+            seed,
+            count,
+            ndjson,
+        } => match openapi {
+            Some(spec) => generate_by_openapi(spec, output),
+            None => generate_by_template(template.unwrap_or_default(), output, seed, count, ndjson),
+        },
+        Config::Validate { db_file, schema } => validate(db_file, schema),
+        Config::Record {
+            target,
+            cassette,
+            host,
+            port,
+        } => run_record(target, cassette, host, port).await,
+        Config::Replay {
+            cassette,
+            host,
+            port,
+            match_body,
+        } => run_replay(cassette, host, port, match_body).await,
     }
 }
 
 /// actix-web 配置
 /// 异步方法
-async fn run_server(db_file: String, host: String, port: usize) -> std::io::Result<()> {
-    // 创建Database
-    let db = db::Database::new(&db_file);
+async fn run_server(config: ServeConfig) -> std::io::Result<()> {
+    let ServeConfig {
+        db_file,
+        format,
+        host,
+        port,
+        mode,
+        routes,
+        static_dir,
+        api_prefix,
+        auth,
+        schema,
+        require_if_match,
+        journal,
+        snapshot_interval,
+        watch,
+        request_log,
+        tls,
+        chaos,
+    } = config;
+    // 加载路由规则
+    let routes = Arc::new(match &routes {
+        Some(file) => routes::Routes::load(file)?,
+        None => routes::Routes::default(),
+    });
+    // 加载认证配置，未指定时所有请求都无需认证
+    let web_auth = web::Data::new(match &auth {
+        Some(file) => auth::Auth::load(file)?,
+        None => auth::Auth::default(),
+    });
+    // 创建Database，指定了日志文件时开启持久化
+    let schema = match &schema {
+        Some(file) => Some(schema::Schema::load(file)?),
+        None => None,
+    };
+    // db_file为目录时每个json文件都是一个命名数据库，指定了日志时开启持久化
+    let mut databases = databases::Databases::load(&db_file, journal.as_deref(), schema, format)?;
+    let api_prefix = api_prefix.unwrap_or_default();
+    databases.set_api_prefix(api_prefix.clone());
     // 放入为共享数据 web_data为arc包装
-    let web_db = web::Data::new(db);
-    HttpServer::new(move || {
-        App::new()
+    let web_databases = web::Data::new(databases);
+    if journal.is_some() {
+        // 后台线程定期将日志压缩为db文件快照
+        let snapshot_databases = web_databases.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(snapshot_interval.max(1)));
+            for db in snapshot_databases.all() {
+                if let Err(e) = db.snapshot() {
+                    error!("{}", e);
+                }
+            }
+        });
+    }
+    if watch {
+        // 后台线程监视db文件，被外部修改时重新加载
+        reload::spawn(web_databases.clone());
+    }
+    let server_databases = web_databases.clone();
+    let web_mode = web::Data::new(mode);
+    let web_preconditions = web::Data::new(etag::Preconditions { require_if_match });
+    // 故障注入配置，中间件与 /_actions/chaos 共享
+    let web_chaos = web::Data::new(RwLock::new(chaos));
+    // 请求记录与指标，中间件与 /_actions/requests、/_actions/metrics 共享
+    let web_requests = web::Data::new(monitor::RequestLog::new(request_log));
+    // 静态文件目录，数据库路由只匹配api前缀下的路径，其余请求返回目录中的文件
+    let static_files = match &static_dir {
+        Some(_) if api_prefix.is_empty() => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--static-dir requires a non-empty --api-prefix",
+            ))
+        }
+        Some(dir) => Some(web::Data::new(assets::StaticFiles::new(dir)?)),
+        None => None,
+    };
+    let api_paths = if api_prefix.is_empty() {
+        vec!["/*".to_string()]
+    } else {
+        vec![api_prefix.clone(), format!("{}/*", api_prefix)]
+    };
+    // 开启HTTPS时加载或生成证书
+    let tls_config = tls.server_config(&host)?;
+    let server = HttpServer::new(move || {
+        let app = App::new()
             // 设置共享数据
-            .app_data(web_db.clone())
+            .app_data(server_databases.clone())
+            .app_data(web_mode.clone())
+            .app_data(web_preconditions.clone())
+            .app_data(web_chaos.clone())
+            .app_data(web_auth.clone())
+            .app_data(web_requests.clone())
+            .app_data(error::json_config())
+            // 静态规则与路径重写，先于所有路由生效
+            .wrap_fn({
+                let routes = routes.clone();
+                move |mut req, srv| match routes.rule_for(req.method(), req.path()) {
+                    Some(rule) => {
+                        let rule = rule.clone();
+                        Either::Left(async move {
+                            time::delay_for(rule.delay()).await;
+                            Ok(req.into_response(rule.response()))
+                        })
+                    }
+                    None => {
+                        routes.rewrite(&mut req);
+                        Either::Right(srv.call(req))
+                    }
+                }
+            })
             // 设置日志
             .wrap(middleware::Logger::default())
+            // 记录客户端发出的原始请求，在路径重写与静态规则之前
+            .wrap(monitor::Monitor::new(
+                web_requests.clone(),
+                server_databases.clone(),
+            ))
             .service(web::resource("/index").route(web::get().to(api::server_info)))
-            .service(web::scope("/_actions").route("/flush", web::post().to(api::flush)))
             .service(
-                web::resource("/*")
+                web::scope("/_actions")
+                    .route("/flush", web::post().to(api::flush))
+                    .service(
+                        // 认证规则在处理函数中按每个操作检查
+                        web::resource("/batch")
+                            .wrap(chaos::Chaos::new(web_chaos.clone()))
+                            .route(web::post().to(api::batch)),
+                    )
+                    .route("/openapi.json", web::get().to(api::openapi))
+                    .route("/watch", web::get().to(api::watch))
+                    .service(
+                        web::resource("/requests")
+                            .route(web::get().to(api::requests_list))
+                            .route(web::delete().to(api::requests_clear)),
+                    )
+                    .route("/metrics", web::get().to(api::metrics))
+                    .route("/login", web::post().to(api::login))
+                    .service(
+                        web::resource("/databases")
+                            .route(web::get().to(api::databases_list))
+                            .route(web::post().to(api::databases_create)),
+                    )
+                    .route("/databases/{name}/reset", web::post().to(api::databases_reset))
+                    .route("/databases/{name}", web::delete().to(api::databases_drop))
+                    .route("/snapshots", web::get().to(api::fixture_list))
+                    .route("/snapshot", web::post().to(api::fixture_save))
+                    .route("/snapshots/{name}", web::delete().to(api::fixture_remove))
+                    .route("/restore/{name}", web::post().to(api::fixture_restore))
+                    .route("/reset", web::post().to(api::fixture_reset))
+                    .service(
+                        web::resource("/chaos")
+                            .route(web::get().to(api::chaos_info))
+                            .route(web::post().to(api::chaos_update))
+                            .route(web::put().to(api::chaos_update))
+                            .route(web::delete().to(api::chaos_reset)),
+                    ),
+            )
+            .service(
+                web::resource("/graphql")
+                    .wrap(chaos::Chaos::new(web_chaos.clone()))
+                    .wrap(auth::Authentication::new(web_auth.clone()))
+                    .route(web::get().to(api::graphql))
+                    .route(web::post().to(api::graphql)),
+            )
+            .service(
+                web::resource(api_paths.clone())
+                    // 故障注入只影响数据库路由
+                    .wrap(chaos::Chaos::new(web_chaos.clone()))
+                    // 认证在故障注入之前，被拒绝的请求不会注入故障
+                    .wrap(auth::Authentication::new(web_auth.clone()))
                     .route(web::get().to(api::do_get))
                     .route(web::post().to(api::do_post))
                     .route(web::put().to(api::do_post))
+                    .route(web::patch().to(api::do_patch))
                     .route(web::delete().to(api::do_delete)),
-            )
+            );
+        match &static_files {
+            Some(files) => app
+                .app_data(files.clone())
+                .default_service(web::route().to(assets::serve)),
+            None => app,
+        }
+    });
+    let address = format!("{}:{}", host, port);
+    let server = match (tls_config, tls.tls_port) {
+        // HTTP与HTTPS同时监听
+        (Some(config), Some(tls_port)) => server
+            .bind(address)?
+            .bind_rustls(format!("{}:{}", host, tls_port), config)?,
+        (Some(config), None) => server.bind_rustls(address, config)?,
+        (None, _) => server.bind(address)?,
+    };
+    let res = server.run().await;
+    // 正常退出前再做一次快照
+    for db in web_databases.all() {
+        if let Err(e) = db.snapshot() {
+            error!("{}", e);
+        }
+    }
+    res
+}
+
+/// 按schema校验db文件，输出所有不符合的位置
+fn validate(db_file: String, schema: String) -> std::io::Result<()> {
+    let format = format::Format::from_path(&db_file).unwrap_or(format::Format::Json);
+    let (_, json_obj) = format::load(&db_file, format)?;
+    let schema = schema::Schema::load(&schema)?;
+    let violations = db::validate(Some(&schema), &json_obj, None);
+    if violations.is_empty() {
+        println!("{} is valid", db_file);
+        return Ok(());
+    }
+    for violation in violations.iter() {
+        println!("{}: {}", violation.pointer, violation.message);
+    }
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("{} violations found in {}", violations.len(), db_file),
+    ))
+}
+
+/// 录制模式：作为反向代理转发所有请求，并写入cassette文件
+async fn run_record(target: String, cassette: String, host: String, port: usize) -> std::io::Result<()> {
+    let recorder = web::Data::new(cassette::Recorder::new(&target, &cassette)?);
+    info!("recording {} into {}", target, cassette);
+    HttpServer::new(move || {
+        App::new()
+            // Client 不能跨线程共享，每个worker各自创建
+            .data(Client::default())
+            .app_data(recorder.clone())
+            .wrap(middleware::Logger::default())
+            .default_service(web::route().to(cassette::proxy))
     })
         .bind(format!("{}:{}", host, port))?
         .run()
         .await
 }
 
-/// 根据模板生成数据
-fn generate_by_template(template: String, output: Option<String>) -> std::io::Result<()> {
-    match Generator::new(template) {
-        Err(_) => Err(Error::new(ErrorKind::NotFound, "can not find template")),
-        Ok(mut gen) => {
-            match output {
-                None => println!("{}", gen.create()),
-                Some(output) => {
-                    let mut f = std::fs::File::create(output)?;
-                    f.write(gen.create().as_bytes())?;
-                }
-            }
-            Ok(())
+/// 回放模式：按录制的cassette文件返回响应
+async fn run_replay(cassette: String, host: String, port: usize, match_body: bool) -> std::io::Result<()> {
+    let player = web::Data::new(cassette::Player::new(
+        cassette::Cassette::load(&cassette)?,
+        match_body,
+    ));
+    HttpServer::new(move || {
+        App::new()
+            .app_data(player.clone())
+            .wrap(middleware::Logger::default())
+            .default_service(web::route().to(cassette::replay))
+    })
+        .bind(format!("{}:{}", host, port))?
+        .run()
+        .await
+}
+
+/// 根据模板生成数据，指定count或ndjson时渲染多次
+fn generate_by_template(
+    template: String,
+    output: Option<String>,
+    seed: Option<u64>,
+    count: Option<usize>,
+    ndjson: bool,
+) -> std::io::Result<()> {
+    if !std::path::Path::new(&template).is_file() {
+        return Err(Error::new(ErrorKind::NotFound, "can not find template"));
+    }
+    let mut gen = template::Template::new(&template, seed)?;
+    let content = match (count, ndjson) {
+        (None, false) => gen.render(),
+        (count, ndjson) => gen.render_many(count.unwrap_or(1), ndjson)?,
+    };
+    write_output(content, output)
+}
+
+/// 根据OpenAPI spec生成带示例数据的db文件
+fn generate_by_openapi(spec: String, output: Option<String>) -> std::io::Result<()> {
+    let spec = openapi::load_spec(&spec)?;
+    write_output(serde_json::to_string_pretty(&openapi::generate(&spec)).unwrap(), output)
+}
+
+/// 未指定输出文件时输出到标准输出
+fn write_output(content: String, output: Option<String>) -> std::io::Result<()> {
+    match output {
+        None => println!("{}", content),
+        Some(output) => {
+            let mut f = std::fs::File::create(output)?;
+            f.write_all(content.as_bytes())?;
         }
     }
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,512 @@
+//! 请求监控模块
+//! 包裹整个App，记录每个请求，便于在测试中断言客户端的调用：
+//!     GET     /_actions/requests      最近的请求(环形缓冲区，--request-log 指定容量)，可按以下参数过滤
+//!             method= path= path_prefix= status= since=<id> limit=<n>
+//!     DELETE  /_actions/requests      清空已记录的请求
+//!     GET     /_actions/metrics       Prometheus文本格式的指标：按路由与状态码的请求数、延迟直方图、db大小
+//! 路由标签的数量不随数据及请求路径增长：
+//!     /_actions 下的接口只保留前两段，如 /_actions/databases
+//!     数据库路由中每隔一段(集合中的id、数组下标)替换为 {id}，其余的段不是db的顶层key时替换为 {key}
+//!     404及数据库路由之外的请求(静态文件、扫描器探测的路径)都归入 {other}
+//! 这两个接口自身的请求不会被记录；请求体只记录处理函数读取过的部分(最多64KB)。
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{web, Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::databases::Databases;
+use crate::error;
+
+/// 每个请求最多记录的请求体字节数
+const BODY_LIMIT: usize = 64 * 1024;
+
+/// 延迟直方图的桶(秒)
+const BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// 不记录的路径
+const SKIP: &[&str] = &["/_actions/requests", "/_actions/metrics"];
+
+/// 无法归入具体路由的请求的标签
+const OTHER: &str = "{other}";
+
+/// 一个已完成的请求
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    pub id: u64,
+    /// 请求到达的时间，unix毫秒
+    pub time: u64,
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub status: u16,
+    pub latency_ms: f64,
+    /// json请求体按json保存，其余按字符串保存，没有请求体时为null
+    pub body: Value,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// GET /_actions/requests 的过滤参数
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub path_prefix: Option<String>,
+    pub status: Option<u16>,
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    pub fn parse(query_string: &str) -> Result<Filter, error::Error> {
+        web::Query::<Filter>::from_query(query_string)
+            .map(web::Query::into_inner)
+            .map_err(|e| error::Error::InvalidQuery {
+                detail: format!("invalid filter: {}", e),
+            })
+    }
+
+    fn matches(&self, record: &Record) -> bool {
+        self.method
+            .as_ref()
+            .is_none_or(|method| method.eq_ignore_ascii_case(&record.method))
+            && self.path.as_ref().is_none_or(|path| *path == record.path)
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| record.path.starts_with(prefix.as_str()))
+            && self.status.is_none_or(|status| status == record.status)
+            && self.since.is_none_or(|since| record.id > since)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; BUCKETS.len()];
+        }
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= *le {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    // (method, route, status) -> 请求数
+    requests: BTreeMap<(String, String, u16), u64>,
+    // (method, route) -> 延迟
+    latency: BTreeMap<(String, String), Histogram>,
+}
+
+/// 请求记录与指标，在所有worker间共享
+pub struct RequestLog {
+    capacity: usize,
+    next_id: AtomicU64,
+    records: Mutex<VecDeque<Record>>,
+    metrics: Mutex<Metrics>,
+}
+
+/// 请求在指标中的路由标签
+pub fn route(path: &str, status: u16, databases: &Databases) -> String {
+    if status == 404 {
+        return OTHER.to_string();
+    }
+    if path.starts_with("/_actions/") {
+        return path.split('/').take(3).collect::<Vec<&str>>().join("/");
+    }
+    if path == "/graphql" || path == "/index" {
+        return path.to_string();
+    }
+    let (mut route, db, rest) = match databases.route_of(path) {
+        Some(found) => found,
+        None => return OTHER.to_string(),
+    };
+    let data = db.data.read();
+    for (idx, segment) in rest.split('/').filter(|s| !s.is_empty()).enumerate() {
+        route.push('/');
+        route.push_str(match segment {
+            _ if idx % 2 == 1 => "{id}",
+            key if data.get(key).is_some() => key,
+            _ => "{key}",
+        });
+    }
+    if route.is_empty() {
+        route.push('/');
+    }
+    route
+}
+
+/// 请求体按content-type转为json值
+fn encode_body(content_type: &str, body: &[u8], truncated: bool) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    if content_type.contains("json") && !truncated {
+        if let Ok(value) = serde_json::from_slice(body) {
+            return value;
+        }
+    }
+    Value::String(String::from_utf8_lossy(body).into_owned())
+}
+
+/// Prometheus标签值转义
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl RequestLog {
+    /// capacity为0时不记录请求，指标仍然统计
+    pub fn new(capacity: usize) -> RequestLog {
+        RequestLog {
+            capacity,
+            next_id: AtomicU64::new(1),
+            records: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            metrics: Mutex::new(Metrics::default()),
+        }
+    }
+
+    fn push(&self, mut record: Record, route: String) {
+        let seconds = record.latency_ms / 1000.0;
+        {
+            let mut metrics = self.metrics.lock().unwrap();
+            *metrics
+                .requests
+                .entry((record.method.clone(), route.clone(), record.status))
+                .or_default() += 1;
+            metrics
+                .latency
+                .entry((record.method.clone(), route))
+                .or_default()
+                .observe(seconds);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        // 在锁内分配id，保证缓冲区中的id递增
+        record.id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// 按过滤条件返回记录(由旧到新)及匹配的总数，limit只保留最新的若干条
+    pub fn query(&self, filter: &Filter) -> (Vec<Record>, usize) {
+        let records = self.records.lock().unwrap();
+        let matched: Vec<&Record> = records.iter().filter(|r| filter.matches(r)).collect();
+        let total = matched.len();
+        let skip = filter.limit.map_or(0, |limit| total.saturating_sub(limit));
+        (matched.into_iter().skip(skip).cloned().collect(), total)
+    }
+
+    pub fn clear(&self) {
+        self.records.lock().unwrap().clear();
+    }
+
+    /// Prometheus文本格式的指标
+    pub fn metrics(&self, databases: &Databases) -> String {
+        let mut out = String::new();
+        {
+            let metrics = self.metrics.lock().unwrap();
+            out.push_str(
+                "# HELP mockrs_requests_total Requests handled, by method, route and status.\n",
+            );
+            out.push_str("# TYPE mockrs_requests_total counter\n");
+            for ((method, route, status), count) in metrics.requests.iter() {
+                out.push_str(&format!(
+                    "mockrs_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}\n",
+                    method,
+                    label(route),
+                    status,
+                    count
+                ));
+            }
+            out.push_str("# HELP mockrs_request_duration_seconds Time until the response head is ready, by method and route.\n");
+            out.push_str("# TYPE mockrs_request_duration_seconds histogram\n");
+            for ((method, route), histogram) in metrics.latency.iter() {
+                let labels = format!("method=\"{}\",route=\"{}\"", method, label(route));
+                for (le, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                    out.push_str(&format!(
+                        "mockrs_request_duration_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                        labels, le, count
+                    ));
+                }
+                out.push_str(&format!(
+                    "mockrs_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n",
+                    labels, histogram.count
+                ));
+                out.push_str(&format!(
+                    "mockrs_request_duration_seconds_sum{{{}}} {}\n",
+                    labels, histogram.sum
+                ));
+                out.push_str(&format!(
+                    "mockrs_request_duration_seconds_count{{{}}} {}\n",
+                    labels, histogram.count
+                ));
+            }
+        }
+        let mounted = databases.mounted();
+        out.push_str(
+            "# HELP mockrs_db_bytes Size of the data of each database serialized as json.\n",
+        );
+        out.push_str("# TYPE mockrs_db_bytes gauge\n");
+        let mut items = vec![];
+        for (path, db) in mounted.iter() {
+            let data = db.data.read();
+            let size = serde_json::to_vec(&*data).map_or(0, |bytes| bytes.len());
+            out.push_str(&format!(
+                "mockrs_db_bytes{{database=\"{}\"}} {}\n",
+                label(path),
+                size
+            ));
+            if let Value::Object(map) = &*data {
+                for (key, value) in map {
+                    if let Value::Array(array) = value {
+                        items.push((path.clone(), key.clone(), array.len()));
+                    }
+                }
+            }
+        }
+        out.push_str("# HELP mockrs_db_items Number of elements of each top-level array.\n");
+        out.push_str("# TYPE mockrs_db_items gauge\n");
+        for (path, key, len) in items {
+            out.push_str(&format!(
+                "mockrs_db_items{{database=\"{}\",key=\"{}\"}} {}\n",
+                label(&path),
+                label(&key),
+                len
+            ));
+        }
+        out
+    }
+}
+
+/// 请求监控中间件
+pub struct Monitor {
+    log: web::Data<RequestLog>,
+    databases: web::Data<Databases>,
+}
+
+impl Monitor {
+    pub fn new(log: web::Data<RequestLog>, databases: web::Data<Databases>) -> Monitor {
+        Monitor { log, databases }
+    }
+}
+
+impl<S, B> Transform<S> for Monitor
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MonitorMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MonitorMiddleware {
+            service,
+            log: self.log.clone(),
+            databases: self.databases.clone(),
+        })
+    }
+}
+
+pub struct MonitorMiddleware<S> {
+    service: S,
+    log: web::Data<RequestLog>,
+    databases: web::Data<Databases>,
+}
+
+impl<S, B> Service for MonitorMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        if SKIP.contains(&req.path()) {
+            return Box::pin(self.service.call(req));
+        }
+        let start = Instant::now();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        let query = req.query_string().to_string();
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        // 处理函数读取请求体时顺便保留一份，不影响其读取方式
+        let captured = Rc::new(RefCell::new((Vec::new(), false)));
+        let tee = captured.clone();
+        let payload = req.take_payload().map(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                let (body, truncated) = &mut *tee.borrow_mut();
+                let room = BODY_LIMIT - body.len();
+                *truncated |= bytes.len() > room;
+                body.extend_from_slice(&bytes[..bytes.len().min(room)]);
+            }
+            chunk
+        });
+        req.set_payload(Payload::Stream(Box::pin(payload)));
+        let log = self.log.clone();
+        let databases = self.databases.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => res.status().as_u16(),
+                Err(e) => e.as_response_error().status_code().as_u16(),
+            };
+            let (body, truncated) = &*captured.borrow();
+            let route = route(&path, status, &databases);
+            log.push(
+                Record {
+                    id: 0,
+                    time,
+                    method,
+                    path,
+                    query,
+                    status,
+                    latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+                    body: encode_body(&content_type, body, *truncated),
+                    truncated: *truncated,
+                },
+                route,
+            );
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(method: &str, path: &str, status: u16) -> Record {
+        Record {
+            id: 0,
+            time: 0,
+            method: method.to_string(),
+            path: path.to_string(),
+            query: String::new(),
+            status,
+            latency_ms: 2.0,
+            body: Value::Null,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn test_request_log() {
+        let log = RequestLog::new(3);
+        log.push(record("GET", "/users", 200), "/users".to_string());
+        log.push(record("PUT", "/users/3", 200), "/users".to_string());
+        log.push(record("PUT", "/users/4", 404), "/users".to_string());
+        log.push(record("PUT", "/users/3", 200), "/users".to_string());
+        // 容量为3，最早的请求被丢弃
+        let (records, total) = log.query(&Filter::default());
+        assert_eq!(total, 3);
+        assert_eq!(records[0].id, 2);
+
+        let filter = Filter::parse("method=put&path=/users/3").unwrap();
+        assert_eq!(log.query(&filter).1, 2);
+        let filter = Filter::parse("path_prefix=/users&status=404").unwrap();
+        assert_eq!(log.query(&filter).0[0].path, "/users/4");
+        let filter = Filter::parse("since=2&limit=1").unwrap();
+        let (records, total) = log.query(&filter);
+        assert_eq!((records[0].id, total), (4, 2));
+        assert!(Filter::parse("status=abc").is_err());
+
+        log.clear();
+        assert_eq!(log.query(&Filter::default()).1, 0);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let databases = Databases::load("db.json", None, None, None).unwrap();
+        let log = RequestLog::new(0);
+        for (method, path, status) in [
+            ("GET", "/posts/1", 200),
+            ("GET", "/posts/alice", 200),
+            ("DELETE", "/posts/2", 404),
+        ] {
+            let route = route(path, status, &databases);
+            log.push(record(method, path, status), route);
+        }
+        assert_eq!(log.query(&Filter::default()).1, 0);
+        // 标签不随路径增长
+        assert_eq!(
+            route("/posts/1/comments", 200, &databases),
+            "/posts/{id}/{key}"
+        );
+        assert_eq!(route("/wp-login.php", 401, &databases), "/{key}");
+        assert_eq!(route("/", 200, &databases), "/");
+        assert_eq!(route("/.env", 404, &databases), "{other}");
+        assert_eq!(
+            route("/_actions/databases/a/reset", 204, &databases),
+            "/_actions/databases"
+        );
+        assert_eq!(route("/db/a/posts", 200, &databases), "{other}");
+
+        let metrics = log.metrics(&databases);
+        assert!(metrics.contains(
+            "mockrs_requests_total{method=\"GET\",route=\"/posts/{id}\",status=\"200\"} 2\n"
+        ));
+        assert!(metrics.contains(
+            "mockrs_request_duration_seconds_bucket{method=\"GET\",route=\"/posts/{id}\",le=\"0.005\"} 2\n"
+        ));
+        assert!(metrics.contains(
+            "mockrs_request_duration_seconds_bucket{method=\"DELETE\",route=\"{other}\",le=\"0.001\"} 0\n"
+        ));
+        assert!(metrics.contains("mockrs_db_bytes{database=\"/\"}"));
+        assert!(metrics.contains("mockrs_db_items{database=\"/\",key=\"posts\"}"));
+    }
+}
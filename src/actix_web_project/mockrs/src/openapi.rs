@@ -0,0 +1,679 @@
+//! OpenAPI 3 模块
+//!     导出  GET /_actions/openapi.json 按当前数据推断所有可访问的路径，并由数据推断各路径的schema
+//!     导入  mockrs gen --openapi spec.yaml 按spec中GET接口的响应schema生成带示例数据的db文件
+//! 导出时顶层集合的元素schema放在 components/schemas 中，以集合名的单数形式命名，例如 posts -> Post。
+//! 导入时不带路径参数的路径直接生成对应的数据，/posts/{id} 这类路径在 /posts 不存在时生成一个集合。
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use log::debug;
+use serde_json::{json, Map, Value};
+
+use crate::api::RouteMode;
+use crate::db;
+use crate::relation;
+
+/// 导入时每个集合生成的元素数
+const EXAMPLE_COUNT: usize = 3;
+/// 解析 $ref 的最大深度，避免循环引用
+const MAX_DEPTH: usize = 16;
+
+/// 由json值推断schema，数组的元素schema由所有元素合并得到
+pub fn infer_schema(value: &Value) -> Value {
+    match value {
+        Value::Null => json!({"nullable": true}),
+        Value::Bool(_) => json!({"type": "boolean"}),
+        Value::Number(n) if n.is_f64() => json!({"type": "number"}),
+        Value::Number(_) => json!({"type": "integer"}),
+        Value::String(_) => json!({"type": "string"}),
+        Value::Array(array) => {
+            let items = array
+                .iter()
+                .map(infer_schema)
+                .reduce(merge_schema)
+                .unwrap_or_else(|| json!({}));
+            json!({"type": "array", "items": items})
+        }
+        Value::Object(map) => {
+            let properties: Map<String, Value> = map
+                .iter()
+                .map(|(key, value)| (key.clone(), infer_schema(value)))
+                .collect();
+            let required: Vec<&String> = map.keys().collect();
+            json!({"type": "object", "properties": properties, "required": required})
+        }
+    }
+}
+
+fn type_of(schema: &Value) -> Option<&str> {
+    schema.get("type").and_then(Value::as_str)
+}
+
+/// 合并两个推断出的schema：对象合并属性且只保留都必需的属性，类型不同时用oneOf
+fn merge_schema(a: Value, b: Value) -> Value {
+    if a == b {
+        return a;
+    }
+    match (type_of(&a), type_of(&b)) {
+        (Some("object"), Some("object")) => {
+            let mut properties = a["properties"].as_object().cloned().unwrap_or_default();
+            for (key, schema) in b["properties"].as_object().cloned().unwrap_or_default() {
+                let merged = match properties.remove(&key) {
+                    Some(old) => merge_schema(old, schema),
+                    None => schema,
+                };
+                properties.insert(key, merged);
+            }
+            let required: Vec<Value> = a["required"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|key| b["required"].as_array().is_some_and(|r| r.contains(key)))
+                .collect();
+            json!({"type": "object", "properties": properties, "required": required})
+        }
+        (Some("array"), Some("array")) => {
+            json!({"type": "array", "items": merge_schema(a["items"].clone(), b["items"].clone())})
+        }
+        (Some("integer"), Some("number")) | (Some("number"), Some("integer")) => {
+            json!({"type": "number"})
+        }
+        // null与其它类型合并为可空
+        (None, Some(_)) if a == json!({"nullable": true}) => with_nullable(b),
+        (Some(_), None) if b == json!({"nullable": true}) => with_nullable(a),
+        _ => {
+            let mut options = match a {
+                Value::Object(ref map) if map.contains_key("oneOf") => {
+                    map["oneOf"].as_array().cloned().unwrap_or_default()
+                }
+                other => vec![other],
+            };
+            if !options.contains(&b) {
+                options.push(b);
+            }
+            json!({ "oneOf": options })
+        }
+    }
+}
+
+fn with_nullable(mut schema: Value) -> Value {
+    schema["nullable"] = json!(true);
+    schema
+}
+
+/// 集合名转为components中的schema名：posts -> Post
+fn component_name(collection: &str) -> String {
+    let name = relation::singular(collection);
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => name,
+    }
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({"application/json": {"schema": schema}})
+}
+
+fn ok_response(description: &str, schema: Value) -> Value {
+    json!({"description": description, "content": json_content(schema)})
+}
+
+fn query_param(name: &str, ty: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "schema": {"type": ty},
+        "description": description
+    })
+}
+
+/// 数组GET支持的列表查询参数
+fn list_parameters() -> Vec<Value> {
+    vec![
+        query_param("q", "string", "Full-text search"),
+        query_param("_sort", "string", "Comma separated fields to sort by"),
+        query_param("_order", "string", "Comma separated asc or desc"),
+        query_param(
+            "_page",
+            "integer",
+            "Page number, responds with X-Total-Count and Link headers",
+        ),
+        query_param("_limit", "integer", "Page size or slice length"),
+        query_param("_start", "integer", "Slice start"),
+        query_param("_end", "integer", "Slice end"),
+    ]
+}
+
+struct Exporter {
+    mode: RouteMode,
+    paths: Map<String, Value>,
+    schemas: Map<String, Value>,
+}
+
+impl Exporter {
+    /// 为path生成各操作，然后递归子节点
+    /// params为path中已有的路径参数，schema为该节点的schema(可能是$ref)
+    fn walk(&mut self, path: &str, params: &[Value], name: &str, value: &Value, schema: Value) {
+        let parameters = Value::Array(params.to_vec());
+        let error = json!({"$ref": "#/components/responses/Error"});
+        let mut item = Map::new();
+        let mut get = json!({
+            "summary": format!("Get {}", if path == "/" { "the whole db" } else { path }),
+            "parameters": parameters,
+            "responses": {"200": ok_response("OK", schema.clone()), "default": error}
+        });
+        if value.is_array() {
+            get["parameters"]
+                .as_array_mut()
+                .unwrap()
+                .extend(list_parameters());
+        }
+        item.insert("get".to_string(), get);
+        if path != "/" {
+            item.insert(
+                "put".to_string(),
+                json!({
+                    "summary": format!("Replace {}", path),
+                    "parameters": parameters,
+                    "requestBody": {"required": true, "content": json_content(schema.clone())},
+                    "responses": {
+                        "200": ok_response("Replaced", schema.clone()),
+                        "201": {"description": "Created"},
+                        "default": error
+                    }
+                }),
+            );
+            item.insert(
+                "patch".to_string(),
+                json!({
+                    "summary": format!("Partially update {}", path),
+                    "parameters": parameters,
+                    "requestBody": {"required": true, "content": {
+                        "application/merge-patch+json": {"schema": {"type": "object"}},
+                        "application/json-patch+json": {
+                            "schema": {"type": "array", "items": {"type": "object"}}
+                        }
+                    }},
+                    "responses": {"204": {"description": "Updated"}, "default": error}
+                }),
+            );
+            item.insert(
+                "delete".to_string(),
+                json!({
+                    "summary": format!("Delete {}", path),
+                    "parameters": parameters,
+                    "responses": {"204": {"description": "Deleted"}, "default": error}
+                }),
+            );
+        }
+
+        match value {
+            Value::Object(map) => {
+                for (key, child) in map {
+                    let child_path = format!("{}/{}", path.trim_end_matches('/'), key);
+                    self.walk(&child_path, params, key, child, infer_schema(child));
+                }
+            }
+            Value::Array(array) => {
+                let collection = self.mode == RouteMode::Collection && db::is_collection(array);
+                let item_schema = match &schema {
+                    Value::Object(map) if map.contains_key("items") => map["items"].clone(),
+                    _ => json!({}),
+                };
+                if collection {
+                    item.insert(
+                        "post".to_string(),
+                        json!({
+                            "summary": format!("Append to {}, id is generated when missing", path),
+                            "parameters": parameters,
+                            "requestBody": {"required": true, "content": json_content(item_schema.clone())},
+                            "responses": {"201": ok_response("Created", item_schema.clone()), "default": error}
+                        }),
+                    );
+                }
+                let (param, param_schema) = if collection {
+                    (
+                        format!("{}Id", relation::singular(name)),
+                        json!({"type": "string"}),
+                    )
+                } else {
+                    (
+                        format!("{}Index", relation::singular(name)),
+                        json!({"type": "integer", "minimum": 0}),
+                    )
+                };
+                // 路径参数不能重名
+                let mut param_name = param.clone();
+                let mut n = 1;
+                while params.iter().any(|p| p["name"] == param_name.as_str()) {
+                    n += 1;
+                    param_name = format!("{}{}", param, n);
+                }
+                let mut child_params = params.to_vec();
+                child_params.push(json!({"name": param_name, "in": "path", "required": true, "schema": param_schema}));
+                let child_path = format!("{}/{{{}}}", path.trim_end_matches('/'), param_name);
+                // 以第一个元素代表所有元素继续展开子路径
+                let sample = array.first().cloned().unwrap_or(Value::Null);
+                self.walk(&child_path, &child_params, name, &sample, item_schema);
+            }
+            _ => {}
+        }
+        self.paths.insert(path.to_string(), Value::Object(item));
+    }
+}
+
+/// 导出描述当前数据的OpenAPI 3文档
+pub fn export(json_obj: &Value, mode: RouteMode) -> Value {
+    let mut exporter = Exporter {
+        mode,
+        paths: Map::new(),
+        schemas: Map::new(),
+    };
+    let mut root_schema = infer_schema(json_obj);
+    // 顶层集合的元素schema放入components
+    if let Value::Object(map) = json_obj {
+        for (key, value) in map {
+            if let Value::Array(array) = value {
+                if array.is_empty() || !db::is_collection(array) {
+                    continue;
+                }
+                let name = component_name(key);
+                let items = root_schema["properties"][key]["items"].take();
+                exporter.schemas.insert(name.clone(), items);
+                root_schema["properties"][key]["items"] =
+                    json!({ "$ref": format!("#/components/schemas/{}", name) });
+            }
+        }
+    }
+    exporter.walk("/", &[], "", json_obj, root_schema.clone());
+    // 顶层路径的schema使用带$ref的版本
+    if let Value::Object(map) = json_obj {
+        for key in map.keys() {
+            let path = format!("/{}", key);
+            let schema = root_schema["properties"][key].clone();
+            if let Some(item) = exporter.paths.get_mut(&path) {
+                item["get"]["responses"]["200"]["content"]["application/json"]["schema"] =
+                    schema.clone();
+                item["put"]["requestBody"]["content"]["application/json"]["schema"] =
+                    schema.clone();
+                item["put"]["responses"]["200"]["content"]["application/json"]["schema"] =
+                    schema.clone();
+                if item.get("post").is_some() {
+                    item["post"]["requestBody"]["content"]["application/json"]["schema"] =
+                        schema["items"].clone();
+                    item["post"]["responses"]["201"]["content"]["application/json"]["schema"] =
+                        schema["items"].clone();
+                }
+            }
+        }
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": exporter.paths,
+        "components": {
+            "schemas": exporter.schemas,
+            "responses": {
+                "Error": {
+                    "description": "Error",
+                    "content": {"application/problem+json": {"schema": {
+                        "type": "object",
+                        "properties": {
+                            "type": {"type": "string"},
+                            "title": {"type": "string"},
+                            "status": {"type": "integer"},
+                            "detail": {"type": "string"},
+                            "code": {"type": "string"}
+                        },
+                        "required": ["type", "title", "status", "code"]
+                    }}}
+                }
+            }
+        }
+    })
+}
+
+/// 读取spec文件，.json按json解析，其余按yaml解析
+pub fn load_spec(file: &str) -> std::io::Result<Value> {
+    let content = fs::read_to_string(file)?;
+    let spec = if file.ends_with(".json") {
+        serde_json::from_str(&content).map_err(|e| e.to_string())
+    } else {
+        serde_yaml::from_str(&content).map_err(|e| e.to_string())
+    };
+    spec.map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid openapi spec {}: {}", file, e),
+        )
+    })
+}
+
+struct Generator<'a> {
+    spec: &'a Value,
+}
+
+impl<'a> Generator<'a> {
+    fn resolve(&self, schema: &'a Value) -> &'a Value {
+        let mut schema = schema;
+        for _ in 0..MAX_DEPTH {
+            match schema
+                .get("$ref")
+                .and_then(Value::as_str)
+                .and_then(|r| r.strip_prefix('#'))
+                .and_then(|p| self.spec.pointer(p))
+            {
+                Some(target) => schema = target,
+                None => break,
+            }
+        }
+        schema
+    }
+
+    /// GET接口成功响应的json schema
+    fn response_schema(&self, item: &'a Value) -> Option<&'a Value> {
+        let responses = self
+            .resolve(item.get("get")?)
+            .get("responses")?
+            .as_object()?;
+        let response = ["200", "201", "2XX", "default"]
+            .iter()
+            .find_map(|code| responses.get(*code))?;
+        let content = self.resolve(response).get("content")?.as_object()?;
+        content
+            .iter()
+            .find(|(media, _)| media.contains("json"))
+            .and_then(|(_, media)| media.get("schema"))
+    }
+
+    /// 按schema生成示例值，n为元素序号(从1开始)，name为所在的属性名
+    fn example(&self, schema: &'a Value, name: &str, n: usize, depth: usize) -> Value {
+        let schema = self.resolve(schema);
+        if depth > MAX_DEPTH {
+            return Value::Null;
+        }
+        if let Some(example) = schema.get("example") {
+            return example.clone();
+        }
+        if let Some(example) = schema.get("examples").and_then(|e| e.get(0)) {
+            return example.clone();
+        }
+        if let Some(default) = schema.get("default") {
+            return default.clone();
+        }
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            if !options.is_empty() {
+                return options[(n - 1) % options.len()].clone();
+            }
+        }
+        if let Some(Value::Array(schemas)) = schema.get("allOf") {
+            let mut merged = Map::new();
+            for sub in schemas {
+                if let Value::Object(map) = self.example(sub, name, n, depth + 1) {
+                    merged.extend(map);
+                }
+            }
+            return Value::Object(merged);
+        }
+        for key in ["oneOf", "anyOf"].iter() {
+            if let Some(sub) = schema.get(*key).and_then(|s| s.get(0)) {
+                return self.example(sub, name, n, depth + 1);
+            }
+        }
+        let ty = match type_of(schema) {
+            Some(ty) => ty,
+            None if schema.get("properties").is_some() => "object",
+            None if schema.get("items").is_some() => "array",
+            None => return Value::Null,
+        };
+        let format = schema.get("format").and_then(Value::as_str).unwrap_or("");
+        match ty {
+            "object" => {
+                let properties = schema.get("properties").and_then(Value::as_object);
+                Value::Object(
+                    properties
+                        .into_iter()
+                        .flatten()
+                        .map(|(key, sub)| (key.clone(), self.example(sub, key, n, depth + 1)))
+                        .collect(),
+                )
+            }
+            "array" => {
+                let count = schema
+                    .get("minItems")
+                    .and_then(Value::as_u64)
+                    .map_or(1, |min| min.max(1) as usize);
+                match schema.get("items") {
+                    Some(items) => Value::Array(
+                        (0..count)
+                            .map(|i| self.example(items, name, n + i, depth + 1))
+                            .collect(),
+                    ),
+                    None => json!([]),
+                }
+            }
+            "integer" => {
+                let min = schema.get("minimum").and_then(Value::as_i64).unwrap_or(1);
+                let max = schema
+                    .get("maximum")
+                    .and_then(Value::as_i64)
+                    .unwrap_or(i64::MAX);
+                json!((min + n as i64 - 1).min(max))
+            }
+            "number" => {
+                let min = schema.get("minimum").and_then(Value::as_f64).unwrap_or(0.0);
+                json!(min + n as f64 + 0.5)
+            }
+            "boolean" => json!(n % 2 == 1),
+            "string" => json!(match format {
+                "date-time" => format!("2020-01-{:02}T00:00:00Z", (n - 1) % 28 + 1),
+                "date" => format!("2020-01-{:02}", (n - 1) % 28 + 1),
+                "email" => format!("user{}@example.com", n),
+                "uuid" => format!("00000000-0000-4000-8000-{:012}", n),
+                "uri" | "url" => format!("https://example.com/{}/{}", name, n),
+                _ => format!("{} {}", name, n),
+            }),
+            _ => Value::Null,
+        }
+    }
+
+    /// 生成集合：元素有id属性时按序号赋值
+    fn collection(&self, schema: &'a Value, name: &str) -> Value {
+        Value::Array(
+            (1..=EXAMPLE_COUNT)
+                .map(|n| {
+                    let mut item = self.example(schema, name, n, 0);
+                    if let Some(id) = item.get_mut("id") {
+                        if !id.is_string() {
+                            *id = json!(n);
+                        }
+                    }
+                    item
+                })
+                .collect(),
+        )
+    }
+}
+
+/// 在pointer处写入value，途经的对象不存在时创建，已有数据时不覆盖
+fn put(root: &mut Value, segments: &[&str], value: Value) {
+    let mut cur = root;
+    for (idx, seg) in segments.iter().enumerate() {
+        let map = match cur {
+            Value::Object(map) => map,
+            _ => return,
+        };
+        if idx == segments.len() - 1 {
+            map.entry(seg.to_string()).or_insert(value);
+            return;
+        }
+        cur = map.entry(seg.to_string()).or_insert_with(|| json!({}));
+    }
+}
+
+/// 按OpenAPI spec生成db数据
+pub fn generate(spec: &Value) -> Value {
+    let gen = Generator { spec };
+    let mut db = json!({});
+    let paths = match spec.get("paths").and_then(Value::as_object) {
+        Some(paths) => paths,
+        None => return db,
+    };
+    let is_param = |seg: &&str| seg.starts_with('{') && seg.ends_with('}');
+    // 先处理不带参数的路径，避免被 /xxx/{id} 生成的集合占位
+    let mut items: Vec<(Vec<&str>, &Value)> = paths
+        .iter()
+        .map(|(path, item)| (path.split('/').filter(|s| !s.is_empty()).collect(), item))
+        .collect();
+    items.sort_by_key(|(segments, _)| segments.iter().any(is_param));
+    for (segments, item) in items {
+        let schema = match gen.response_schema(item) {
+            Some(schema) => schema,
+            None => continue,
+        };
+        let name = segments.last().copied().unwrap_or("");
+        match segments.iter().position(is_param) {
+            None if segments.is_empty() => {
+                if let Value::Object(map) = gen.example(schema, "", 1, 0) {
+                    for (key, value) in map {
+                        put(&mut db, &[key.as_str()], value);
+                    }
+                }
+            }
+            None => {
+                let value = match type_of(gen.resolve(schema)) {
+                    Some("array") => match gen.resolve(schema).get("items") {
+                        Some(items) => gen.collection(items, name),
+                        None => json!([]),
+                    },
+                    _ => gen.example(schema, name, 1, 0),
+                };
+                put(&mut db, &segments, value);
+            }
+            // 只有最后一段是参数：/posts/{id}
+            Some(idx) if idx == segments.len() - 1 && idx > 0 => {
+                let prefix = &segments[..idx];
+                put(&mut db, prefix, gen.collection(schema, prefix[idx - 1]));
+            }
+            Some(_) => debug!("Skip path with inner parameters: /{}", segments.join("/")),
+        }
+    }
+    db
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export() {
+        let data = json!({
+            "posts": [{"id": 1, "title": "a", "tags": ["x"]}, {"id": 2, "title": null}],
+            "profile": {"name": "a"}
+        });
+        let doc = export(&data, RouteMode::Collection);
+        let paths = doc["paths"].as_object().unwrap();
+        let mut keys: Vec<&String> = paths.keys().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "/",
+                "/posts",
+                "/posts/{postId}",
+                "/posts/{postId}/id",
+                "/posts/{postId}/tags",
+                "/posts/{postId}/tags/{tagIndex}",
+                "/posts/{postId}/title",
+                "/profile",
+                "/profile/name"
+            ]
+        );
+        assert!(paths["/posts"].get("post").is_some());
+        assert!(paths["/profile"].get("post").is_none());
+        assert_eq!(
+            doc["components"]["schemas"]["Post"],
+            json!({
+                "type": "object",
+                "properties": {
+                    "id": {"type": "integer"},
+                    "tags": {"type": "array", "items": {"type": "string"}},
+                    "title": {"type": "string", "nullable": true}
+                },
+                "required": ["id", "title"]
+            })
+        );
+        assert_eq!(
+            paths["/posts"]["get"]["responses"]["200"]["content"]["application/json"]["schema"],
+            json!({"type": "array", "items": {"$ref": "#/components/schemas/Post"}})
+        );
+    }
+
+    #[test]
+    fn test_generate() {
+        let spec: Value = serde_yaml::from_str(
+            r##"
+openapi: 3.0.0
+paths:
+  /users/{id}:
+    get:
+      responses:
+        "200":
+          content:
+            application/json:
+              schema:
+                $ref: "#/components/schemas/User"
+  /config:
+    get:
+      responses:
+        "200":
+          content:
+            application/json:
+              schema:
+                type: object
+                properties:
+                  debug: {type: boolean, default: false}
+  /api/posts:
+    get:
+      responses:
+        "200":
+          content:
+            application/json:
+              schema:
+                type: array
+                items:
+                  type: object
+                  properties:
+                    id: {type: integer}
+                    status: {type: string, enum: [draft, published]}
+components:
+  schemas:
+    User:
+      type: object
+      properties:
+        id: {type: string, format: uuid}
+        email: {type: string, format: email}
+        age: {type: integer, minimum: 18}
+"##,
+        )
+        .unwrap();
+        let db = generate(&spec);
+        assert_eq!(db["config"], json!({"debug": false}));
+        assert_eq!(db["api"]["posts"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            db["api"]["posts"][1],
+            json!({"id": 2, "status": "published"})
+        );
+        assert_eq!(
+            db["users"][0],
+            json!({"id": "00000000-0000-4000-8000-000000000001", "email": "user1@example.com", "age": 18})
+        );
+    }
+}
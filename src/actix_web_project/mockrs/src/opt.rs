@@ -15,6 +15,11 @@
 //!     Option类型        表示该命令可选
 //!     bool类型          表示该命令为flag模式，无需给value，输入则为true，无输入则为false
 //!
+use crate::api::RouteMode;
+use crate::assets;
+use crate::chaos::ChaosConfig;
+use crate::format::Format;
+use crate::tls::TlsConfig;
 use crate::StructOpt;
 
 /// 要在 RUST 程序中获得Cargo中的一些值，请执行以下操作:
@@ -30,11 +35,56 @@ about = env ! ("CARGO_PKG_DESCRIPTION")
 )]
 pub enum Config {
     /// Run http json server
-    Serve {
-        /// Json file as database
-        #[structopt(required = true, env = "MOCKRS_DB_FILE")]
+    Serve(Box<ServeConfig>),
+
+    /// Generate fake data based on template or OpenAPI spec
+    Gen {
+        /// Template file to generate json file
+        #[structopt(required_unless = "openapi")]
+        template: Option<String>,
+
+        /// OpenAPI 3 spec (yaml or json) to generate a db file with example data from
+        #[structopt(long, conflicts_with = "template")]
+        openapi: Option<String>,
+
+        /// Output json file
+        #[structopt(long)]
+        output: Option<String>,
+
+        /// Seed of the random generator, the same seed always produces the same output
+        #[structopt(long, conflicts_with = "openapi")]
+        seed: Option<u64>,
+
+        /// Render the template this many times and output a json array of the documents
+        #[structopt(long, conflicts_with = "openapi")]
+        count: Option<usize>,
+
+        /// Output one compact document per line instead of a json array
+        #[structopt(long, conflicts_with = "openapi")]
+        ndjson: bool,
+    },
+
+    /// Validate a db file against a JSON Schema
+    Validate {
+        /// Json file to validate
+        #[structopt(required = true)]
         db_file: String,
 
+        /// JSON Schema file describing the whole db file
+        #[structopt(required = true)]
+        schema: String,
+    },
+
+    /// Proxy requests to a target server and record every exchange to a cassette file
+    Record {
+        /// Base url of the proxied server, e.g. http://127.0.0.1:8080
+        #[structopt(required = true)]
+        target: String,
+
+        /// Cassette file to write, interactions of an existing file are kept
+        #[structopt(long, default_value = "cassette.json")]
+        cassette: String,
+
         /// Listen ip
         #[structopt(long, default_value = "127.0.0.1", env = "MOCKRS_HOST")]
         host: String,
@@ -44,14 +94,92 @@ pub enum Config {
         port: usize,
     },
 
-    /// Generate fake data based on template
-    Gen {
-        /// Template file to generate json file
+    /// Serve the responses recorded in a cassette file
+    Replay {
+        /// Cassette file written by the record subcommand
         #[structopt(required = true)]
-        template: String,
+        cassette: String,
 
-        /// Output json file
+        /// Listen ip
+        #[structopt(long, default_value = "127.0.0.1", env = "MOCKRS_HOST")]
+        host: String,
+
+        /// Listen port
+        #[structopt(short, long, default_value = "9000", env = "MOCKRS_PORT")]
+        port: usize,
+
+        /// Also require the request body to match the recorded one
         #[structopt(long)]
-        output: Option<String>,
+        match_body: bool,
     },
-}
\ No newline at end of file
+}
+
+/// serve 子命令的参数
+#[derive(StructOpt, Debug, Clone)]
+pub struct ServeConfig {
+    /// Json, yaml, toml or json5 file as database, or a directory whose db files are served as separate databases under /db/<name>
+    #[structopt(required = true, env = "MOCKRS_DB_FILE")]
+    pub db_file: String,
+
+    /// Format of the db file, detected by its extension when omitted
+    #[structopt(long, env = "MOCKRS_FORMAT", possible_values = &["json", "json5", "yaml", "toml"])]
+    pub format: Option<Format>,
+
+    /// Listen ip
+    #[structopt(long, default_value = "127.0.0.1", env = "MOCKRS_HOST")]
+    pub host: String,
+
+    /// Listen port
+    #[structopt(short, long, default_value = "9000", env = "MOCKRS_PORT")]
+    pub port: usize,
+
+    /// Route mode: "pointer" addresses arrays by index, "collection" addresses arrays of objects by their id field
+    #[structopt(long, default_value = "pointer", env = "MOCKRS_MODE", possible_values = &["pointer", "collection"])]
+    pub mode: RouteMode,
+
+    /// Json file of rewrite and static response rules applied before the database routes
+    #[structopt(long, env = "MOCKRS_ROUTES")]
+    pub routes: Option<String>,
+
+    /// Directory of static files served on paths outside --api-prefix, unknown paths accepting html get its index.html
+    #[structopt(long, env = "MOCKRS_STATIC_DIR", requires = "api-prefix")]
+    pub static_dir: Option<String>,
+
+    /// Path prefix of the database routes, e.g. /api, they are served at / when omitted
+    #[structopt(long, env = "MOCKRS_API_PREFIX", parse(from_str = assets::api_prefix))]
+    pub api_prefix: Option<String>,
+
+    /// Json file of api keys, users, jwt settings and per-path rules guarding the database routes
+    #[structopt(long, env = "MOCKRS_AUTH")]
+    pub auth: Option<String>,
+
+    /// JSON Schema file describing the whole db file, writes violating it are rejected with 422
+    #[structopt(long, env = "MOCKRS_SCHEMA")]
+    pub schema: Option<String>,
+
+    /// Reject changes to existing data that come without an If-Match header
+    #[structopt(long)]
+    pub require_if_match: bool,
+
+    /// Journal file, every change is appended to it and replayed on startup; a directory when db_file is a directory
+    #[structopt(long, env = "MOCKRS_JOURNAL")]
+    pub journal: Option<String>,
+
+    /// Seconds between compacting the journal into a snapshot of the db file
+    #[structopt(long, default_value = "60", env = "MOCKRS_SNAPSHOT_INTERVAL")]
+    pub snapshot_interval: u64,
+
+    /// Reload the db file when it is changed by another program, invalid content is logged and ignored
+    #[structopt(long)]
+    pub watch: bool,
+
+    /// Number of recent requests kept for /_actions/requests, 0 disables the request log
+    #[structopt(long, default_value = "1000", env = "MOCKRS_REQUEST_LOG")]
+    pub request_log: usize,
+
+    #[structopt(flatten)]
+    pub tls: TlsConfig,
+
+    #[structopt(flatten)]
+    pub chaos: ChaosConfig,
+}
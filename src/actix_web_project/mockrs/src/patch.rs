@@ -0,0 +1,274 @@
+//! 局部更新模块
+//! 支持两种PATCH格式：
+//!     application/json-patch+json     RFC 6902 JSON Patch，由一组有序操作构成
+//!     application/merge-patch+json    RFC 7396 JSON Merge Patch，直接给出要合并的json
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::Error;
+
+pub const JSON_PATCH: &str = "application/json-patch+json";
+pub const MERGE_PATCH: &str = "application/merge-patch+json";
+
+/// JSON Patch 的单个操作
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// 将json pointer拆分为各级key，并还原转义字符 ~1 -> /  ~0 -> ~
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, Error> {
+    if pointer.is_empty() {
+        return Ok(vec![]);
+    }
+    if !pointer.starts_with('/') {
+        return Err(Error::InvalidPointer {
+            pointer: pointer.to_string(),
+        });
+    }
+    Ok(pointer[1..]
+        .split('/')
+        .map(|seg| seg.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+/// 将各级key重新拼接为json pointer
+fn to_pointer(tokens: &[String]) -> String {
+    tokens
+        .iter()
+        .map(|seg| format!("/{}", seg.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// 解析数组下标，不允许前导0
+fn parse_index(token: &str, pointer: &str) -> Result<usize, Error> {
+    let invalid = || Error::InvalidIndex {
+        pointer: pointer.to_string(),
+    };
+    if token.len() > 1 && token.starts_with('0') {
+        return Err(invalid());
+    }
+    token.parse::<usize>().map_err(|_| invalid())
+}
+
+fn not_found(pointer: &str) -> Error {
+    Error::PathNotFound {
+        pointer: pointer.to_string(),
+    }
+}
+
+fn out_of_bounds(pointer: &str) -> Error {
+    Error::IndexOutOfBounds {
+        pointer: pointer.to_string(),
+    }
+}
+
+fn not_a_container(pointer: &str) -> Error {
+    Error::TypeConflict {
+        pointer: pointer.to_string(),
+        detail: format!("parent of {:?} is not an object or array", pointer),
+    }
+}
+
+fn get_parent<'a>(
+    doc: &'a mut Value,
+    tokens: &[String],
+    pointer: &str,
+) -> Result<&'a mut Value, Error> {
+    doc.pointer_mut(&to_pointer(&tokens[..tokens.len() - 1]))
+        .ok_or_else(|| not_found(pointer))
+}
+
+fn add(doc: &mut Value, pointer: &str, value: Value) -> Result<(), Error> {
+    let tokens = parse_pointer(pointer)?;
+    if tokens.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let target_key = &tokens[tokens.len() - 1];
+    match get_parent(doc, &tokens, pointer)? {
+        Value::Object(map) => {
+            map.insert(target_key.clone(), value);
+            Ok(())
+        }
+        Value::Array(array) => {
+            if target_key == "-" {
+                array.push(value);
+                return Ok(());
+            }
+            let idx = parse_index(target_key, pointer)?;
+            if idx > array.len() {
+                return Err(out_of_bounds(pointer));
+            }
+            array.insert(idx, value);
+            Ok(())
+        }
+        _ => Err(not_a_container(pointer)),
+    }
+}
+
+fn remove(doc: &mut Value, pointer: &str) -> Result<Value, Error> {
+    let tokens = parse_pointer(pointer)?;
+    if tokens.is_empty() {
+        return Ok(std::mem::replace(doc, Value::Null));
+    }
+    let target_key = &tokens[tokens.len() - 1];
+    match get_parent(doc, &tokens, pointer)? {
+        Value::Object(map) => map
+            .remove(target_key)
+            .ok_or_else(|| not_found(pointer)),
+        Value::Array(array) => {
+            let idx = parse_index(target_key, pointer)?;
+            if idx >= array.len() {
+                return Err(out_of_bounds(pointer));
+            }
+            Ok(array.remove(idx))
+        }
+        _ => Err(not_a_container(pointer)),
+    }
+}
+
+fn get<'a>(doc: &'a mut Value, pointer: &str) -> Result<&'a mut Value, Error> {
+    // 先校验格式，避免 pointer_mut 对非法pointer直接返回None而丢失原因
+    parse_pointer(pointer)?;
+    doc.pointer_mut(pointer).ok_or_else(|| not_found(pointer))
+}
+
+fn apply_operation(doc: &mut Value, op: Operation) -> Result<(), Error> {
+    match op {
+        Operation::Add { path, value } => add(doc, &path, value),
+        Operation::Remove { path } => remove(doc, &path).map(|_| ()),
+        Operation::Replace { path, value } => {
+            *get(doc, &path)? = value;
+            Ok(())
+        }
+        Operation::Move { from, path } => {
+            if path != from && path.starts_with(&format!("{}/", from)) {
+                return Err(Error::InvalidMove { from, pointer: path });
+            }
+            let value = remove(doc, &from)?;
+            add(doc, &path, value)
+        }
+        Operation::Copy { from, path } => {
+            let value = get(doc, &from)?.clone();
+            add(doc, &path, value)
+        }
+        Operation::Test { path, value } => {
+            let actual = get(doc, &path)?;
+            if *actual == value {
+                Ok(())
+            } else {
+                Err(Error::TestFailed {
+                    pointer: path,
+                    expected: value,
+                    actual: actual.clone(),
+                })
+            }
+        }
+    }
+}
+
+/// 依次执行JSON Patch中的所有操作
+/// 在副本上执行，全部成功后才写回，任一操作失败则原json保持不变。
+pub fn json_patch(doc: &mut Value, ops: Vec<Operation>) -> Result<(), Error> {
+    let mut working = doc.clone();
+    for (index, op) in ops.into_iter().enumerate() {
+        if let Err(e) = apply_operation(&mut working, op) {
+            return Err(Error::PatchFailed {
+                index,
+                cause: Box::new(e),
+            });
+        }
+    }
+    *doc = working;
+    Ok(())
+}
+
+/// 按RFC 7396合并：patch中的null表示删除，对象递归合并，其余类型直接替换
+pub fn merge_patch(doc: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch_map) => {
+            if !doc.is_object() {
+                *doc = json!({});
+            }
+            let map = doc.as_object_mut().unwrap();
+            for (key, value) in patch_map {
+                if value.is_null() {
+                    map.remove(&key);
+                } else {
+                    merge_patch(map.entry(key).or_insert(Value::Null), value);
+                }
+            }
+        }
+        other => *doc = other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ops(value: Value) -> Vec<Operation> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let mut doc = json!({"posts": [{"name": "a"}, {"name": "b"}], "user": {"a/b": 1}});
+        let patch = ops(json!([
+            {"op": "add", "path": "/posts/-", "value": {"name": "c"}},
+            {"op": "replace", "path": "/posts/0/name", "value": "x"},
+            {"op": "remove", "path": "/posts/1"},
+            {"op": "copy", "from": "/posts/0", "path": "/first"},
+            {"op": "move", "from": "/user/a~1b", "path": "/count"},
+            {"op": "test", "path": "/count", "value": 1}
+        ]));
+        json_patch(&mut doc, patch).unwrap();
+        assert_eq!(
+            doc,
+            json!({
+                "posts": [{"name": "x"}, {"name": "c"}],
+                "user": {},
+                "first": {"name": "x"},
+                "count": 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_patch_atomic() {
+        let mut doc = json!({"a": 1});
+        let patch = ops(json!([
+            {"op": "replace", "path": "/a", "value": 2},
+            {"op": "test", "path": "/a", "value": 3}
+        ]));
+        let err = json_patch(&mut doc, patch).unwrap_err();
+        let problem = err.problem();
+        assert_eq!(problem["index"], 1);
+        assert_eq!(problem["cause"]["code"], "test_failed");
+        assert_eq!(err.status().as_u16(), 409);
+        assert_eq!(doc, json!({"a": 1}));
+
+        let err = json_patch(&mut doc, ops(json!([{"op": "remove", "path": "a"}]))).unwrap_err();
+        assert_eq!(err.problem()["cause"]["code"], "invalid_pointer");
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut doc = json!({"title": "Goodbye!", "author": {"givenName": "John", "familyName": "Doe"}, "tags": ["a", "b"]});
+        merge_patch(
+            &mut doc,
+            json!({"title": "Hello!", "author": {"familyName": null}, "tags": ["c"], "phone": "123"}),
+        );
+        assert_eq!(
+            doc,
+            json!({"title": "Hello!", "author": {"givenName": "John"}, "tags": ["c"], "phone": "123"})
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! 列表查询模块
+//! GET 的目标为数组时，支持json-server风格的查询参数：
+//!     field=value                 相等过滤，同一字段出现多次表示"或"，field可用 a.b 访问嵌套字段
+//!     field_gte= field_lte=       大于等于/小于等于，两边都能解析为数字时按数字比较
+//!     field_ne=  field_like=      不等于/正则匹配(忽略大小写)
+//!     q=                          全文搜索，任意字符串字段包含该值即匹配
+//!     _sort=a,b  _order=asc,desc  排序
+//!     _page= _limit=              分页，未给_limit时每页10条
+//!     _start= _end= _limit=       切片
+use std::cmp::Ordering;
+
+use actix_web::web;
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+
+use crate::error::Error;
+
+const DEFAULT_PAGE_LIMIT: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Gte,
+    Lte,
+    Like,
+}
+
+#[derive(Debug, Clone)]
+struct Filter {
+    field: String,
+    op: Operator,
+    value: String,
+    // _like 的正则，解析时编译
+    regex: Option<Regex>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListQuery {
+    filters: Vec<Filter>,
+    q: Option<String>,
+    sort: Vec<(String, bool)>,
+    page: Option<usize>,
+    limit: Option<usize>,
+    start: Option<usize>,
+    end: Option<usize>,
+    // 原始参数(未解码)，用于生成分页链接
+    raw_params: Vec<String>,
+}
+
+/// 列表查询结果，total为过滤后、分页前的数量
+pub struct ListResult {
+    pub items: Vec<Value>,
+    pub total: usize,
+}
+
+fn parse_usize(key: &str, value: &str) -> Result<usize, Error> {
+    value.parse::<usize>().map_err(|_| Error::InvalidQuery {
+        detail: format!("{} must be a non-negative integer, got {:?}", key, value),
+    })
+}
+
+/// 按 a.b.c 取嵌套字段
+fn field<'a>(item: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(item, |cur, key| match cur {
+        Value::Object(map) => map.get(key),
+        Value::Array(array) => key.parse::<usize>().ok().and_then(|idx| array.get(idx)),
+        _ => None,
+    })
+}
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// 字段值与参数比较，两边都是数字时按数字比较，否则按字符串比较
+fn compare(value: &Value, param: &str) -> Option<Ordering> {
+    match (value.as_f64(), param.parse::<f64>()) {
+        (Some(a), Ok(b)) => a.partial_cmp(&b),
+        _ => Some(as_text(value).as_str().cmp(param)),
+    }
+}
+
+/// 排序比较，缺失字段排在最后
+fn compare_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => match (a.as_f64(), b.as_f64()) {
+            (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+            _ => as_text(a).cmp(&as_text(b)),
+        },
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// 递归查找任意字符串字段是否包含关键字
+fn contains_text(value: &Value, keyword: &str) -> bool {
+    match value {
+        Value::String(s) => s.to_lowercase().contains(keyword),
+        Value::Number(n) => n.to_string().contains(keyword),
+        Value::Array(array) => array.iter().any(|v| contains_text(v, keyword)),
+        Value::Object(map) => map.values().any(|v| contains_text(v, keyword)),
+        _ => false,
+    }
+}
+
+impl Filter {
+    fn parse(key: &str, value: &str) -> Result<Filter, Error> {
+        let suffixes = [
+            ("_gte", Operator::Gte),
+            ("_lte", Operator::Lte),
+            ("_ne", Operator::Ne),
+            ("_like", Operator::Like),
+        ];
+        let (field, op) = suffixes
+            .iter()
+            .find(|(suffix, _)| key.len() > suffix.len() && key.ends_with(suffix))
+            .map_or((key, Operator::Eq), |(suffix, op)| {
+                (&key[..key.len() - suffix.len()], op.clone())
+            });
+        let regex = if op == Operator::Like {
+            let re = RegexBuilder::new(value)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| Error::InvalidQuery {
+                    detail: format!("invalid _like pattern: {}", e),
+                })?;
+            Some(re)
+        } else {
+            None
+        };
+        Ok(Filter {
+            field: field.to_string(),
+            op,
+            value: value.to_string(),
+            regex,
+        })
+    }
+
+    fn matches(&self, item: &Value) -> bool {
+        let value = field(item, &self.field);
+        match self.op {
+            Operator::Eq => value.is_some_and(|v| as_text(v) == self.value),
+            Operator::Ne => value.is_none_or(|v| as_text(v) != self.value),
+            Operator::Gte => value
+                .and_then(|v| compare(v, &self.value))
+                .is_some_and(|o| o != Ordering::Less),
+            Operator::Lte => value
+                .and_then(|v| compare(v, &self.value))
+                .is_some_and(|o| o != Ordering::Greater),
+            Operator::Like => match &self.regex {
+                Some(re) => value.is_some_and(|v| re.is_match(&as_text(v))),
+                None => false,
+            },
+        }
+    }
+}
+
+impl ListQuery {
+    /// 解析查询字符串，未知的以 _ 开头的参数会被忽略
+    pub fn parse(query_string: &str) -> Result<ListQuery, Error> {
+        let params = web::Query::<Vec<(String, String)>>::from_query(query_string)
+            .map_err(|e| Error::InvalidQuery {
+                detail: format!("invalid query string: {}", e),
+            })?
+            .into_inner();
+        let mut query = ListQuery::from_params(&params)?;
+        query.raw_params = query_string
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_string())
+            .collect();
+        Ok(query)
+    }
+
+    /// 由已解码的参数构造，参数含义与查询字符串相同
+    pub fn from_params(params: &[(String, String)]) -> Result<ListQuery, Error> {
+        let mut query = ListQuery::default();
+        let mut order: Vec<String> = vec![];
+        for (key, value) in params.iter() {
+            match key.as_str() {
+                "q" => query.q = Some(value.to_lowercase()),
+                "_sort" => {
+                    query.sort = value
+                        .split(',')
+                        .map(|f| (f.trim().to_string(), true))
+                        .collect()
+                }
+                "_order" => order = value.split(',').map(|o| o.trim().to_lowercase()).collect(),
+                "_page" => query.page = Some(parse_usize(key, value)?.max(1)),
+                "_limit" => query.limit = Some(parse_usize(key, value)?),
+                "_start" => query.start = Some(parse_usize(key, value)?),
+                "_end" => query.end = Some(parse_usize(key, value)?),
+                _ if key.starts_with('_') => {}
+                _ => query.filters.push(Filter::parse(key, value)?),
+            }
+        }
+        for (idx, (_, asc)) in query.sort.iter_mut().enumerate() {
+            *asc = order.get(idx).is_none_or(|o| o != "desc");
+        }
+        Ok(query)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+            && self.q.is_none()
+            && self.sort.is_empty()
+            && self.page.is_none()
+            && self.limit.is_none()
+            && self.start.is_none()
+            && self.end.is_none()
+    }
+
+    fn matches(&self, item: &Value) -> bool {
+        // 同一字段的相等条件之间为"或"，其余条件之间为"与"
+        let mut eq_fields: Vec<&str> = vec![];
+        for filter in self.filters.iter() {
+            if filter.op == Operator::Eq {
+                if !eq_fields.contains(&filter.field.as_str()) {
+                    eq_fields.push(&filter.field);
+                }
+            } else if !filter.matches(item) {
+                return false;
+            }
+        }
+        for f in eq_fields {
+            let any = self
+                .filters
+                .iter()
+                .filter(|x| x.op == Operator::Eq && x.field == f)
+                .any(|filter| filter.matches(item));
+            if !any {
+                return false;
+            }
+        }
+        match &self.q {
+            Some(q) => contains_text(item, q),
+            None => true,
+        }
+    }
+
+    /// 依次执行过滤、搜索、排序、分页/切片
+    pub fn apply(&self, items: &[Value]) -> ListResult {
+        let mut result: Vec<Value> = items
+            .iter()
+            .filter(|item| self.matches(item))
+            .cloned()
+            .collect();
+        if !self.sort.is_empty() {
+            result.sort_by(|a, b| {
+                self.sort
+                    .iter()
+                    .map(|(f, asc)| {
+                        let ord = compare_values(field(a, f), field(b, f));
+                        if *asc {
+                            ord
+                        } else {
+                            ord.reverse()
+                        }
+                    })
+                    .find(|ord| *ord != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+        let total = result.len();
+        let (start, end) = if let Some(page) = self.page {
+            let limit = self.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+            // 页码与数量来自客户端，避免溢出
+            ((page - 1).saturating_mul(limit), page.saturating_mul(limit))
+        } else {
+            let start = self.start.unwrap_or(0);
+            let end = match (self.end, self.limit) {
+                (Some(end), _) => end,
+                (None, Some(limit)) => start.saturating_add(limit),
+                (None, None) => total,
+            };
+            (start, end)
+        };
+        let start = start.min(total);
+        let end = end.min(total).max(start);
+        ListResult {
+            items: result.drain(start..end).collect(),
+            total,
+        }
+    }
+
+    /// 生成分页的Link响应头，格式同 RFC 5988
+    pub fn links(&self, base: &str, total: usize) -> Option<String> {
+        let page = self.page?;
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_LIMIT).max(1);
+        let last = total.div_ceil(limit).max(1);
+        let url = |p: usize| {
+            let query: Vec<String> = self
+                .raw_params
+                .iter()
+                .filter(|p| !p.starts_with("_page="))
+                .cloned()
+                .chain(std::iter::once(format!("_page={}", p)))
+                .collect();
+            format!("{}?{}", base, query.join("&"))
+        };
+        let mut links = vec![format!("<{}>; rel=\"first\"", url(1))];
+        if page > 1 {
+            links.push(format!("<{}>; rel=\"prev\"", url((page - 1).min(last))));
+        }
+        if page < last {
+            links.push(format!("<{}>; rel=\"next\"", url(page + 1)));
+        }
+        links.push(format!("<{}>; rel=\"last\"", url(last)));
+        Some(links.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn posts() -> Vec<Value> {
+        vec![
+            json!({"id": 1, "title": "Hello Rust", "views": 100, "author": {"name": "a"}}),
+            json!({"id": 2, "title": "hello actix", "views": 20, "author": {"name": "b"}}),
+            json!({"id": 3, "title": "serde", "views": 300, "author": {"name": "a"}}),
+        ]
+    }
+
+    fn ids(query: &str) -> Vec<Value> {
+        let query = ListQuery::parse(query).unwrap();
+        query
+            .apply(&posts())
+            .items
+            .iter()
+            .map(|item| item["id"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(ids("author.name=a"), vec![json!(1), json!(3)]);
+        assert_eq!(ids("id=1&id=2"), vec![json!(1), json!(2)]);
+        assert_eq!(ids("views_gte=100&views_lte=200"), vec![json!(1)]);
+        assert_eq!(ids("id_ne=2"), vec![json!(1), json!(3)]);
+        assert_eq!(ids("title_like=^hello"), vec![json!(1), json!(2)]);
+        assert_eq!(ids("q=ACTIX"), vec![json!(2)]);
+        // 无效的正则在解析时报错，与数组是否为空无关
+        assert_eq!(
+            ListQuery::parse("title_like=(").unwrap_err().code(),
+            "invalid_query"
+        );
+    }
+
+    #[test]
+    fn test_sort_and_page() {
+        assert_eq!(
+            ids("_sort=views&_order=desc"),
+            vec![json!(3), json!(1), json!(2)]
+        );
+        assert_eq!(
+            ids("_sort=author.name,views&_order=asc,desc"),
+            vec![json!(3), json!(1), json!(2)]
+        );
+        assert_eq!(ids("_page=2&_limit=2"), vec![json!(3)]);
+        assert_eq!(ids("_start=1&_end=2"), vec![json!(2)]);
+        assert_eq!(ids("_start=1&_limit=5"), vec![json!(2), json!(3)]);
+
+        let query = ListQuery::parse("_page=2&_limit=1&id_ne=9").unwrap();
+        assert_eq!(
+            query.links("/posts", 3).unwrap(),
+            "</posts?_limit=1&id_ne=9&_page=1>; rel=\"first\", \
+             </posts?_limit=1&id_ne=9&_page=1>; rel=\"prev\", \
+             </posts?_limit=1&id_ne=9&_page=3>; rel=\"next\", \
+             </posts?_limit=1&id_ne=9&_page=3>; rel=\"last\""
+        );
+        assert!(ListQuery::parse("_page=x").is_err());
+
+        let max = usize::MAX;
+        assert!(ids(&format!("_page={}&_limit=2", max)).is_empty());
+        assert!(ids(&format!("_page=2&_limit={}", max)).is_empty());
+        assert_eq!(
+            ids(&format!("_start=1&_limit={}", max)),
+            vec![json!(2), json!(3)]
+        );
+        assert!(ids(&format!("_start={}&_limit={}", max, max)).is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+//! 关联查询模块
+//! 顶层集合之间通过外键关联，外键名为单数形式的集合名加 Id，例如 comments[].postId 指向 posts 中的元素。
+//!     _embed=comments     GET /posts/1 时内嵌所有 postId 为 1 的 comments
+//!     _expand=post        GET /comments/3 时按 postId 内嵌所属的 post
+//!     /posts/1/comments   等价于 /comments?postId=1
+use actix_web::web;
+use serde_json::Value;
+
+use crate::db;
+use crate::error::Error;
+
+/// 集合名转单数：posts -> post，categories -> category
+pub fn singular(name: &str) -> String {
+    if let Some(stem) = name.strip_suffix("ies") {
+        format!("{}y", stem)
+    } else if let Some(stem) = name.strip_suffix('s') {
+        stem.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// 单数转可能的集合名，按顺序尝试
+fn plurals(name: &str) -> Vec<String> {
+    let mut names = vec![format!("{}s", name), format!("{}es", name)];
+    if let Some(stem) = name.strip_suffix('y') {
+        names.push(format!("{}ies", stem));
+    }
+    names.push(name.to_string());
+    names
+}
+
+pub fn foreign_key(collection: &str) -> String {
+    format!("{}Id", singular(collection))
+}
+
+fn collection<'a>(root: &'a Value, name: &str) -> Option<&'a Vec<Value>> {
+    match root.get(name) {
+        Some(Value::Array(array)) if db::is_collection(array) => Some(array),
+        _ => None,
+    }
+}
+
+/// 查找child集合中外键指向parent_id的所有元素
+pub fn children(root: &Value, child: &str, parent: &str, parent_id: &Value) -> Option<Vec<Value>> {
+    let fk = foreign_key(parent);
+    let parent_id = db::id_string(parent_id);
+    collection(root, child).map(|array| {
+        array
+            .iter()
+            .filter(|item| item.get(&fk).map(db::id_string).as_deref() == Some(parent_id.as_str()))
+            .cloned()
+            .collect()
+    })
+}
+
+/// 在name对应的集合中查找外键指向的父元素
+fn parent(root: &Value, name: &str, id: &Value) -> Option<Value> {
+    let id = db::id_string(id);
+    plurals(name)
+        .iter()
+        .filter_map(|plural| collection(root, plural))
+        .next()
+        .and_then(|array| db::find_by_id(array, &id).map(|idx| array[idx].clone()))
+}
+
+/// _embed 与 _expand 参数
+#[derive(Debug, Clone, Default)]
+pub struct Relations {
+    embed: Vec<String>,
+    expand: Vec<String>,
+}
+
+impl Relations {
+    pub fn parse(query_string: &str) -> Result<Relations, Error> {
+        let params = web::Query::<Vec<(String, String)>>::from_query(query_string)
+            .map_err(|e| Error::InvalidQuery {
+                detail: format!("invalid query string: {}", e),
+            })?
+            .into_inner();
+        let mut relations = Relations::default();
+        for (key, value) in params {
+            let names = value.split(',').map(|name| name.trim().to_string());
+            match key.as_str() {
+                "_embed" => relations.embed.extend(names),
+                "_expand" => relations.expand.extend(names),
+                _ => {}
+            }
+        }
+        Ok(relations)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.embed.is_empty() && self.expand.is_empty()
+    }
+
+    fn apply_item(&self, root: &Value, collection: &str, item: &mut Value) {
+        let id = match item.get("id") {
+            Some(id) => id.clone(),
+            None => Value::Null,
+        };
+        let map = match item.as_object_mut() {
+            Some(map) => map,
+            None => return,
+        };
+        for child in self.embed.iter() {
+            if let Some(children) = children(root, child, collection, &id) {
+                map.insert(child.clone(), Value::Array(children));
+            }
+        }
+        for name in self.expand.iter() {
+            let fk = format!("{}Id", name);
+            if let Some(parent) = map.get(&fk).and_then(|fk| parent(root, name, fk)) {
+                map.insert(name.clone(), parent);
+            }
+        }
+    }
+
+    /// 对集合collection中的单个元素或元素数组执行内嵌
+    pub fn apply(&self, root: &Value, collection: &str, value: &mut Value) {
+        match value {
+            Value::Array(items) => {
+                for item in items.iter_mut() {
+                    self.apply_item(root, collection, item);
+                }
+            }
+            item => self.apply_item(root, collection, item),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_relations() {
+        let root = json!({
+            "posts": [{"id": 1, "title": "a"}, {"id": 2, "title": "b"}],
+            "comments": [{"id": 1, "postId": 1}, {"id": 2, "postId": "2"}, {"id": 3, "postId": 1}],
+            "categories": [{"id": 1}]
+        });
+        assert_eq!(singular("categories"), "category");
+        assert_eq!(
+            children(&root, "comments", "posts", &json!(1))
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let relations = Relations::parse("_embed=comments").unwrap();
+        let mut post = root["posts"][1].clone();
+        relations.apply(&root, "posts", &mut post);
+        assert_eq!(post["comments"], json!([{"id": 2, "postId": "2"}]));
+
+        let relations = Relations::parse("_expand=post").unwrap();
+        let mut comments = root["comments"].clone();
+        relations.apply(&root, "comments", &mut comments);
+        assert_eq!(comments[0]["post"], json!({"id": 1, "title": "a"}));
+        assert_eq!(comments[1]["post"]["title"], "b");
+    }
+}
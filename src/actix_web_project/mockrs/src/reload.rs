@@ -0,0 +1,119 @@
+//! 热加载模块
+//! 开启 --watch 后后台线程定期检查db文件的修改时间，文件被外部修改时重新解析并整体替换数据，
+//! 解析失败或不符合schema时记录错误并继续使用原数据。
+//! 替换后向 /_actions/watch 的订阅者推送 {"op": "reload", "path": "", ...} 事件。
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use actix_web::web;
+use log::{error, info};
+
+use crate::databases::Databases;
+use crate::db::Database;
+
+/// 检查文件修改的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 记录各db文件上一次的修改时间
+#[derive(Default)]
+pub struct FileWatcher {
+    modified: HashMap<String, SystemTime>,
+}
+
+impl FileWatcher {
+    /// 检查一遍所有数据库，返回重新加载了的db文件
+    pub fn poll(&mut self, databases: &[Arc<Database>]) -> Vec<String> {
+        let mut reloaded = vec![];
+        for db in databases {
+            // 运行时创建的数据库没有db文件
+            if db.file().is_empty() {
+                continue;
+            }
+            let modified = match fs::metadata(db.file()).and_then(|meta| meta.modified()) {
+                Ok(modified) => modified,
+                Err(_) => continue,
+            };
+            let previous = self.modified.insert(db.file().to_string(), modified);
+            if previous.is_none_or(|previous| previous == modified) {
+                continue;
+            }
+            match db.reload() {
+                Ok(true) => {
+                    info!("Reloaded {}", db.file());
+                    reloaded.push(db.file().to_string());
+                }
+                Ok(false) => {}
+                Err(e) => error!("Reload failed, keep serving the old data: {}", e),
+            }
+        }
+        reloaded
+    }
+}
+
+/// 启动后台线程监视所有db文件
+pub fn spawn(databases: web::Data<Databases>) {
+    let mut watcher = FileWatcher::default();
+    watcher.poll(&databases.all());
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        watcher.poll(&databases.all());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+    use serde_json::json;
+
+    use super::*;
+    use crate::format::Format;
+
+    #[test]
+    fn test_reload() {
+        let file = std::env::temp_dir().join(format!("mockrs-reload-{}.json", std::process::id()));
+        let file = file.to_string_lossy().to_string();
+        fs::write(&file, r#"{"posts": [1]}"#).unwrap();
+        let db = Arc::new(Database::new(&file, Format::Json).unwrap());
+        let mut receiver = db.watchers.subscribe(vec![]);
+        let dbs = vec![db.clone()];
+        let mut watcher = FileWatcher::default();
+        assert!(watcher.poll(&dbs).is_empty());
+
+        let mut seconds = 0;
+        let mut touch = |content: Option<&str>| {
+            if let Some(content) = content {
+                fs::write(&file, content).unwrap();
+            }
+            // 直接设置修改时间，不依赖文件系统时间戳的精度
+            seconds += 1;
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+            fs::File::options()
+                .write(true)
+                .open(&file)
+                .and_then(|f| f.set_modified(modified))
+                .unwrap();
+            watcher.poll(&dbs)
+        };
+        assert_eq!(touch(Some(r#"{"posts": [1, 2]}"#)), vec![file.clone()]);
+        assert_eq!(*db.data.read(), json!({"posts": [1, 2]}));
+        assert_eq!(db.fixtures.initial(), json!({"posts": [1, 2]}));
+        let event = receiver.next().now_or_never().unwrap().unwrap();
+        assert_eq!(event.op, "reload");
+        assert_eq!(event.old, Some(json!({"posts": [1]})));
+
+        // 无法解析时保留原数据，内容未变化时不替换
+        assert!(touch(Some(r#"{"posts": ["#)).is_empty());
+        assert!(touch(Some(r#"{"posts": [1, 2]}"#)).is_empty());
+        assert_eq!(*db.data.read(), json!({"posts": [1, 2]}));
+
+        // 服务自身写回的文件不会覆盖之后接受的修改
+        db.flush(&file, Format::Json).unwrap();
+        db.reset(json!({"posts": [1, 2, 3]}));
+        assert!(touch(None).is_empty());
+        assert_eq!(*db.data.read(), json!({"posts": [1, 2, 3]}));
+        fs::remove_file(&file).unwrap();
+    }
+}
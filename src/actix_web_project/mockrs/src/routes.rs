@@ -0,0 +1,273 @@
+//! 路由规则模块
+//! 通过 --routes 指定一个json文件，在请求进入通用的 /* 资源之前生效：
+//!     rules       静态规则，按 method + path 直接返回固定的状态码、响应头、响应体，可设置延迟
+//!     rewrites    路径重写，将请求路径映射为数据库中的路径
+//! 路径模式中 :name 匹配一段路径，末尾的 * 匹配剩余所有路径。
+//! ```json
+//! {
+//!   "rules": [
+//!     {"method": "GET", "path": "/health", "status": 200, "headers": {"X-Mock": "1"}, "body": {"ok": true}, "delay": 100}
+//!   ],
+//!   "rewrites": [
+//!     {"from": "/api/v1/users/:id", "to": "/users/:id"},
+//!     {"from": "/blog/:author/posts", "to": "/posts?author=:author"},
+//!     {"from": "/api/*", "to": "/*"}
+//!   ]
+//! }
+//! ```
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{Method, StatusCode, Uri};
+use actix_web::HttpResponse;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// 路径模式，auth 的路径规则同样使用
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    segments: Vec<String>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        Pattern {
+            segments: pattern
+                .trim_start_matches('/')
+                .split('/')
+                .map(|seg| seg.to_string())
+                .collect(),
+        }
+    }
+
+    /// 匹配成功时返回捕获的参数，* 捕获的剩余路径以 "*" 为名
+    pub fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let mut params = HashMap::new();
+        for (idx, seg) in self.segments.iter().enumerate() {
+            if seg == "*" && idx == self.segments.len() - 1 {
+                params.insert("*".to_string(), parts.get(idx..)?.join("/"));
+                return Some(params);
+            }
+            let part = parts.get(idx)?;
+            if let Some(name) = seg.strip_prefix(':') {
+                params.insert(name.to_string(), part.to_string());
+            } else if seg != part {
+                return None;
+            }
+        }
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RewriteConfig {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Clone)]
+struct Rewrite {
+    from: Pattern,
+    to: String,
+}
+
+impl Rewrite {
+    fn apply(&self, path: &str) -> Option<String> {
+        let params = self.from.matches(path)?;
+        // 参数名较长的先替换，避免 :id 替换掉 :idx 的前缀
+        let mut names: Vec<&String> = params.keys().collect();
+        names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+        let mut target = self.to.clone();
+        for name in names {
+            let placeholder = if name == "*" {
+                "*".to_string()
+            } else {
+                format!(":{}", name)
+            };
+            target = target.replace(&placeholder, &params[name]);
+        }
+        Some(target)
+    }
+}
+
+/// 静态响应规则
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    /// 为空时匹配任意方法
+    method: Option<String>,
+    path: String,
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// 字符串按原样返回，其余按json返回
+    body: Option<Value>,
+    /// 延迟返回的毫秒数
+    #[serde(default)]
+    delay: u64,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Rule {
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay)
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let mut resp = HttpResponse::build(status);
+        for (name, value) in self.headers.iter() {
+            resp.header(name.as_str(), value.as_str());
+        }
+        match &self.body {
+            None => resp.finish(),
+            Some(Value::String(body)) => resp.body(body.clone()),
+            Some(body) => resp.json(body),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct RoutesConfig {
+    #[serde(default)]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    rewrites: Vec<RewriteConfig>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    rules: Vec<(Pattern, Rule)>,
+    rewrites: Vec<Rewrite>,
+}
+
+impl Routes {
+    pub fn load(file: &str) -> std::io::Result<Routes> {
+        let content = fs::read_to_string(file)?;
+        let config: RoutesConfig = serde_json::from_str(&content).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid routes file {}: {}", file, e),
+            )
+        })?;
+        Ok(Routes::from_config(config))
+    }
+
+    fn from_config(config: RoutesConfig) -> Routes {
+        Routes {
+            rules: config
+                .rules
+                .into_iter()
+                .map(|rule| (Pattern::new(&rule.path), rule))
+                .collect(),
+            rewrites: config
+                .rewrites
+                .into_iter()
+                .map(|rewrite| Rewrite {
+                    from: Pattern::new(&rewrite.from),
+                    to: rewrite.to,
+                })
+                .collect(),
+        }
+    }
+
+    /// 查找第一条匹配的静态规则
+    pub fn rule_for(&self, method: &Method, path: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|(pattern, rule)| {
+                rule.method
+                    .as_ref()
+                    .is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+                    && pattern.matches(path).is_some()
+            })
+            .map(|(_, rule)| rule)
+    }
+
+    /// 按第一条匹配的规则重写路径，重写目标中的查询参数会与原查询参数合并
+    fn rewrite_uri(&self, path: &str, query: &str) -> Option<String> {
+        let target = self.rewrites.iter().find_map(|rw| rw.apply(path))?;
+        let target = if target.starts_with('/') {
+            target
+        } else {
+            format!("/{}", target)
+        };
+        Some(match (target.contains('?'), query.is_empty()) {
+            (_, true) => target,
+            (true, false) => format!("{}&{}", target, query),
+            (false, false) => format!("{}?{}", target, query),
+        })
+    }
+
+    /// 重写请求，同时更新路由匹配使用的路径
+    pub fn rewrite(&self, req: &mut ServiceRequest) {
+        let uri = match self.rewrite_uri(req.path(), req.query_string()) {
+            Some(uri) => uri,
+            None => return,
+        };
+        match uri.parse::<Uri>() {
+            Ok(uri) => {
+                debug!("Rewrite {} -> {}", req.path(), uri);
+                req.match_info_mut().get_mut().update(&uri);
+                req.head_mut().uri = uri;
+            }
+            Err(e) => warn!("Invalid rewrite target {}: {}", uri, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes() -> Routes {
+        let config: RoutesConfig = serde_json::from_value(serde_json::json!({
+            "rules": [{"method": "post", "path": "/login", "status": 201, "body": "ok"}],
+            "rewrites": [
+                {"from": "/api/v1/users/:id", "to": "/users/:id"},
+                {"from": "/blog/:author/posts", "to": "/posts?author=:author"},
+                {"from": "/api/*", "to": "/*"}
+            ]
+        }))
+        .unwrap();
+        Routes::from_config(config)
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let routes = routes();
+        assert_eq!(
+            routes.rewrite_uri("/api/v1/users/3", ""),
+            Some("/users/3".to_string())
+        );
+        assert_eq!(
+            routes.rewrite_uri("/blog/tom/posts", "_page=2"),
+            Some("/posts?author=tom&_page=2".to_string())
+        );
+        assert_eq!(
+            routes.rewrite_uri("/api/posts/1", "q=a"),
+            Some("/posts/1?q=a".to_string())
+        );
+        assert_eq!(routes.rewrite_uri("/users/3", ""), None);
+    }
+
+    #[test]
+    fn test_rule() {
+        let routes = routes();
+        assert!(routes.rule_for(&Method::POST, "/login").is_some());
+        assert!(routes.rule_for(&Method::GET, "/login").is_none());
+        assert!(routes.rule_for(&Method::POST, "/login/x").is_none());
+    }
+}
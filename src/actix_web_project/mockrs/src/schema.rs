@@ -0,0 +1,528 @@
+//! JSON Schema 校验模块
+//! 写入前按schema校验数据，schema有两种来源：
+//!     --schema <file>     描述整个db文件的schema
+//!     db文件中的 $schema    按顶层key给出各集合的schema，例如 {"$schema": {"posts": {"type": "array", "items": {...}}}}
+//! 支持draft-07中与数据结构相关的常用关键字：
+//!     type enum const $ref(仅限本文档内 #/...) allOf anyOf oneOf not
+//!     minimum maximum exclusiveMinimum exclusiveMaximum multipleOf
+//!     minLength maxLength pattern
+//!     items additionalItems minItems maxItems uniqueItems
+//!     properties patternProperties additionalProperties required minProperties maxProperties
+//! 其余关键字(format等)会被忽略。
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+
+/// 顶层key，存放各集合的schema
+pub const SCHEMA_KEY: &str = "$schema";
+
+/// 一条校验错误，pointer为出错值在db中的位置
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Violation {
+    pub pointer: String,
+    pub message: String,
+}
+
+pub fn escape(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "number" => value.is_number(),
+        name => type_name(value) == name,
+    }
+}
+
+fn property_schemas<'a>(map: &'a serde_json::Map<String, Value>, key: &str) -> Vec<&'a Value> {
+    let mut schemas = vec![];
+    if let Some(schema) = map.get("properties").and_then(|p| p.get(key)) {
+        schemas.push(schema);
+    }
+    if let Some(Value::Object(patterns)) = map.get("patternProperties") {
+        for (pattern, schema) in patterns {
+            if Regex::new(pattern).is_ok_and(|re| re.is_match(key)) {
+                schemas.push(schema);
+            }
+        }
+    }
+    if schemas.is_empty() {
+        if let Some(schema) = map.get("additionalProperties") {
+            schemas.push(schema);
+        }
+    }
+    schemas
+}
+
+/// 以schema为根校验value，$ref在schema内解析
+pub fn validate(schema: &Value, value: &Value, pointer: &str) -> Vec<Violation> {
+    let mut validator = Validator {
+        root: schema,
+        errors: vec![],
+    };
+    validator.check(schema, value, pointer);
+    validator.errors
+}
+
+#[derive(Debug, Clone)]
+pub struct Schema {
+    root: Value,
+}
+
+impl Schema {
+    pub fn new(root: Value) -> Schema {
+        Schema { root }
+    }
+
+    pub fn load(file: &str) -> std::io::Result<Schema> {
+        let content = fs::read_to_string(file)?;
+        let root = serde_json::from_str(&content).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid schema file {}: {}", file, e),
+            )
+        })?;
+        Ok(Schema::new(root))
+    }
+
+    /// 校验value，pointer为value在db中的位置，用于拼接错误位置
+    pub fn validate(&self, value: &Value, pointer: &str) -> Vec<Violation> {
+        validate(&self.root, value, pointer)
+    }
+
+    /// 校验顶层key对应的值，只使用schema中描述该key的部分
+    pub fn validate_key(&self, key: &str, value: Option<&Value>) -> Vec<Violation> {
+        let pointer = format!("/{}", escape(key));
+        let mut validator = Validator {
+            root: &self.root,
+            errors: vec![],
+        };
+        match value {
+            Some(value) => {
+                for schema in validator.property_schemas(&self.root, key) {
+                    validator.check(schema, value, &pointer);
+                }
+            }
+            None => {
+                if let Some(required) = self.root.get("required").and_then(Value::as_array) {
+                    if required.iter().any(|name| name == key) {
+                        validator.fail("", format!("missing required property {:?}", key));
+                    }
+                }
+            }
+        }
+        validator.errors
+    }
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+    errors: Vec<Violation>,
+}
+
+impl<'a> Validator<'a> {
+    fn fail(&mut self, pointer: &str, message: String) {
+        self.errors.push(Violation {
+            pointer: pointer.to_string(),
+            message,
+        });
+    }
+
+    /// 只判断是否通过，不记录错误
+    fn is_valid(&self, schema: &'a Value, value: &Value) -> bool {
+        let mut sub = Validator {
+            root: self.root,
+            errors: vec![],
+        };
+        sub.check(schema, value, "");
+        sub.errors.is_empty()
+    }
+
+    fn resolve(&self, reference: &str) -> Option<&'a Value> {
+        self.root.pointer(reference.strip_prefix('#')?)
+    }
+
+    /// 对象属性key适用的schema：properties、匹配的patternProperties，都没有时为additionalProperties
+    fn property_schemas(&self, schema: &'a Value, key: &str) -> Vec<&'a Value> {
+        let schema = match schema.get("$ref").and_then(Value::as_str) {
+            Some(reference) => self.resolve(reference).unwrap_or(schema),
+            None => schema,
+        };
+        match schema {
+            Value::Object(map) => property_schemas(map, key),
+            _ => vec![],
+        }
+    }
+
+    fn check(&mut self, schema: &'a Value, value: &Value, pointer: &str) {
+        let map = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                return self.fail(pointer, "no value is allowed here".to_string())
+            }
+            Value::Object(map) => map,
+            _ => return,
+        };
+        if let Some(reference) = map.get("$ref").and_then(Value::as_str) {
+            match self.resolve(reference) {
+                Some(target) => self.check(target, value, pointer),
+                None => self.fail(pointer, format!("unresolvable $ref {:?}", reference)),
+            }
+            // draft-07中$ref会覆盖同级的其它关键字
+            return;
+        }
+        self.check_generic(map, value, pointer);
+        self.check_combinators(map, value, pointer);
+        match value {
+            Value::Number(_) => self.check_number(map, value, pointer),
+            Value::String(s) => self.check_string(map, s, pointer),
+            Value::Array(array) => self.check_array(map, array, pointer),
+            Value::Object(object) => self.check_object(map, object, pointer),
+            _ => {}
+        }
+    }
+
+    fn check_generic(
+        &mut self,
+        map: &serde_json::Map<String, Value>,
+        value: &Value,
+        pointer: &str,
+    ) {
+        match map.get("type") {
+            Some(Value::String(name)) if !is_type(value, name) => self.fail(
+                pointer,
+                format!("expected {}, got {}", name, type_name(value)),
+            ),
+            Some(Value::Array(names))
+                if !names
+                    .iter()
+                    .any(|name| name.as_str().is_some_and(|name| is_type(value, name))) =>
+            {
+                self.fail(
+                    pointer,
+                    format!(
+                        "expected one of {}, got {}",
+                        Value::Array(names.clone()),
+                        type_name(value)
+                    ),
+                )
+            }
+            _ => {}
+        }
+        if let Some(Value::Array(options)) = map.get("enum") {
+            if !options.contains(value) {
+                self.fail(
+                    pointer,
+                    format!("value must be one of {}", Value::Array(options.clone())),
+                );
+            }
+        }
+        if let Some(expected) = map.get("const") {
+            if expected != value {
+                self.fail(pointer, format!("value must be {}", expected));
+            }
+        }
+    }
+
+    fn check_combinators(
+        &mut self,
+        map: &'a serde_json::Map<String, Value>,
+        value: &Value,
+        pointer: &str,
+    ) {
+        if let Some(Value::Array(schemas)) = map.get("allOf") {
+            for schema in schemas {
+                self.check(schema, value, pointer);
+            }
+        }
+        if let Some(Value::Array(schemas)) = map.get("anyOf") {
+            if !schemas.iter().any(|schema| self.is_valid(schema, value)) {
+                self.fail(
+                    pointer,
+                    "value does not match any schema of anyOf".to_string(),
+                );
+            }
+        }
+        if let Some(Value::Array(schemas)) = map.get("oneOf") {
+            let count = schemas
+                .iter()
+                .filter(|schema| self.is_valid(schema, value))
+                .count();
+            if count != 1 {
+                self.fail(
+                    pointer,
+                    format!(
+                        "value must match exactly one schema of oneOf, matched {}",
+                        count
+                    ),
+                );
+            }
+        }
+        if let Some(schema) = map.get("not") {
+            if self.is_valid(schema, value) {
+                self.fail(
+                    pointer,
+                    "value must not match the schema of not".to_string(),
+                );
+            }
+        }
+    }
+
+    fn check_number(&mut self, map: &serde_json::Map<String, Value>, value: &Value, pointer: &str) {
+        let n = match value.as_f64() {
+            Some(n) => n,
+            None => return,
+        };
+        let limit = |key: &str| map.get(key).and_then(Value::as_f64);
+        if let Some(min) = limit("minimum") {
+            if n < min {
+                self.fail(pointer, format!("value must be >= {}", min));
+            }
+        }
+        if let Some(max) = limit("maximum") {
+            if n > max {
+                self.fail(pointer, format!("value must be <= {}", max));
+            }
+        }
+        if let Some(min) = limit("exclusiveMinimum") {
+            if n <= min {
+                self.fail(pointer, format!("value must be > {}", min));
+            }
+        }
+        if let Some(max) = limit("exclusiveMaximum") {
+            if n >= max {
+                self.fail(pointer, format!("value must be < {}", max));
+            }
+        }
+        if let Some(step) = limit("multipleOf") {
+            if step > 0.0 && (n / step).fract() != 0.0 {
+                self.fail(pointer, format!("value must be a multiple of {}", step));
+            }
+        }
+    }
+
+    fn check_string(&mut self, map: &serde_json::Map<String, Value>, s: &str, pointer: &str) {
+        let len = s.chars().count() as u64;
+        if let Some(min) = map.get("minLength").and_then(Value::as_u64) {
+            if len < min {
+                self.fail(
+                    pointer,
+                    format!("string must have at least {} characters", min),
+                );
+            }
+        }
+        if let Some(max) = map.get("maxLength").and_then(Value::as_u64) {
+            if len > max {
+                self.fail(
+                    pointer,
+                    format!("string must have at most {} characters", max),
+                );
+            }
+        }
+        if let Some(pattern) = map.get("pattern").and_then(Value::as_str) {
+            match Regex::new(pattern) {
+                Ok(re) if !re.is_match(s) => self.fail(
+                    pointer,
+                    format!("string does not match pattern {:?}", pattern),
+                ),
+                Ok(_) => {}
+                Err(_) => self.fail(pointer, format!("invalid pattern {:?} in schema", pattern)),
+            }
+        }
+    }
+
+    fn check_array(
+        &mut self,
+        map: &'a serde_json::Map<String, Value>,
+        array: &[Value],
+        pointer: &str,
+    ) {
+        let len = array.len() as u64;
+        if let Some(min) = map.get("minItems").and_then(Value::as_u64) {
+            if len < min {
+                self.fail(pointer, format!("array must have at least {} items", min));
+            }
+        }
+        if let Some(max) = map.get("maxItems").and_then(Value::as_u64) {
+            if len > max {
+                self.fail(pointer, format!("array must have at most {} items", max));
+            }
+        }
+        if map.get("uniqueItems") == Some(&Value::Bool(true)) {
+            for (idx, item) in array.iter().enumerate() {
+                if array[..idx].contains(item) {
+                    self.fail(
+                        &format!("{}/{}", pointer, idx),
+                        "duplicate array item".to_string(),
+                    );
+                }
+            }
+        }
+        match map.get("items") {
+            // 元组形式：按位置校验，多出的元素按additionalItems校验
+            Some(Value::Array(schemas)) => {
+                for (idx, item) in array.iter().enumerate() {
+                    let schema = match schemas.get(idx) {
+                        Some(schema) => schema,
+                        None => match map.get("additionalItems") {
+                            Some(schema) => schema,
+                            None => break,
+                        },
+                    };
+                    self.check(schema, item, &format!("{}/{}", pointer, idx));
+                }
+            }
+            Some(schema) => {
+                for (idx, item) in array.iter().enumerate() {
+                    self.check(schema, item, &format!("{}/{}", pointer, idx));
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn check_object(
+        &mut self,
+        map: &'a serde_json::Map<String, Value>,
+        object: &serde_json::Map<String, Value>,
+        pointer: &str,
+    ) {
+        let len = object.len() as u64;
+        if let Some(min) = map.get("minProperties").and_then(Value::as_u64) {
+            if len < min {
+                self.fail(
+                    pointer,
+                    format!("object must have at least {} properties", min),
+                );
+            }
+        }
+        if let Some(max) = map.get("maxProperties").and_then(Value::as_u64) {
+            if len > max {
+                self.fail(
+                    pointer,
+                    format!("object must have at most {} properties", max),
+                );
+            }
+        }
+        if let Some(Value::Array(required)) = map.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    self.fail(pointer, format!("missing required property {:?}", name));
+                }
+            }
+        }
+        for (key, value) in object {
+            let child = format!("{}/{}", pointer, escape(key));
+            let schemas = property_schemas(map, key);
+            if schemas == [&Value::Bool(false)] {
+                self.fail(&child, format!("unexpected property {:?}", key));
+                continue;
+            }
+            for schema in schemas {
+                self.check(schema, value, &child);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn pointers(violations: Vec<Violation>) -> Vec<String> {
+        violations.into_iter().map(|v| v.pointer).collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = Schema::new(json!({
+            "type": "object",
+            "properties": {
+                "posts": {"type": "array", "items": {"$ref": "#/definitions/post"}, "uniqueItems": true}
+            },
+            "required": ["posts"],
+            "definitions": {
+                "post": {
+                    "type": "object",
+                    "properties": {
+                        "id": {"type": "integer", "minimum": 1},
+                        "title": {"type": "string", "minLength": 1, "pattern": "^[a-z]"},
+                        "tags": {"type": "array", "items": {"enum": ["a", "b"]}, "maxItems": 2},
+                        "a/b": {"oneOf": [{"type": "string"}, {"type": "null"}]}
+                    },
+                    "required": ["id", "title"],
+                    "additionalProperties": false
+                }
+            }
+        }));
+        let valid = json!({"posts": [{"id": 1, "title": "a", "tags": ["a"]}, {"id": 2, "title": "b", "a/b": null}]});
+        assert!(schema.validate(&valid, "").is_empty());
+
+        let invalid = json!({"posts": [
+            {"id": 0, "title": "A"},
+            {"id": 1.5, "tags": ["c", "a", "b"], "a/b": 1, "extra": true}
+        ]});
+        assert_eq!(
+            pointers(schema.validate(&invalid, "")),
+            vec![
+                "/posts/0/id",
+                "/posts/0/title",
+                "/posts/1",
+                "/posts/1/a~1b",
+                "/posts/1/extra",
+                "/posts/1/id",
+                "/posts/1/tags",
+                "/posts/1/tags/0",
+            ]
+        );
+        assert_eq!(pointers(schema.validate(&json!({}), "")), vec![""]);
+        assert_eq!(pointers(schema.validate_key("posts", None)), vec![""]);
+        assert_eq!(
+            pointers(schema.validate_key("posts", Some(&json!([{"id": 1}])))),
+            vec!["/posts/0"]
+        );
+    }
+
+    #[test]
+    fn test_db_schema() {
+        let data = json!({
+            "$schema": {"users": {"type": "array", "items": {"required": ["name"]}}},
+            "users": [{"name": "a"}, {}],
+            "posts": [{}]
+        });
+        let violations = crate::db::validate(None, &data, None);
+        assert_eq!(violations[0].pointer, "/users/1");
+        assert!(crate::db::validate(None, &data, Some("posts")).is_empty());
+
+        let schema = Schema::new(json!({"properties": {"posts": {"maxItems": 0}}}));
+        assert_eq!(
+            pointers(crate::db::validate(Some(&schema), &data, Some("posts"))),
+            vec!["/posts"]
+        );
+        let err = crate::error::Error::SchemaViolation { errors: violations }.problem();
+        assert_eq!(err["status"], 422);
+        assert_eq!(
+            err["errors"][0]["message"],
+            "missing required property \"name\""
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! 数据存储模块
+//! 整个json文档由读写锁保护，GET等只读请求可以并发执行，修改时独占。
+//! 处理函数panic会使锁中毒，此时继续使用锁中的数据并清除中毒状态，
+//! 而不是让之后的每个请求都失败；修改都经过 Database::checked 等函数，panic时最多留下未完成的一次修改。
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use log::warn;
+use serde_json::Value;
+
+pub struct Store {
+    value: RwLock<Value>,
+}
+
+impl Store {
+    pub fn new(value: Value) -> Store {
+        Store {
+            value: RwLock::new(value),
+        }
+    }
+
+    /// 共享读，可与其它读者并发
+    pub fn read(&self) -> RwLockReadGuard<'_, Value> {
+        self.value.read().unwrap_or_else(|e| self.recover(e))
+    }
+
+    /// 独占写
+    pub fn write(&self) -> RwLockWriteGuard<'_, Value> {
+        self.value.write().unwrap_or_else(|e| self.recover(e))
+    }
+
+    fn recover<G>(&self, e: PoisonError<G>) -> G {
+        warn!("A handler panicked while holding the data lock, recovering");
+        self.value.clear_poison();
+        e.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_poison() {
+        let store = Arc::new(Store::new(json!({"posts": []})));
+        let poisoner = store.clone();
+        let res = thread::spawn(move || {
+            let _guard = poisoner.write();
+            panic!("handler panicked");
+        })
+        .join();
+        assert!(res.is_err());
+        store.write()["posts"] = json!([1]);
+        assert_eq!(store.read()["posts"], json!([1]));
+        assert!(!store.value.is_poisoned());
+    }
+
+    fn data() -> Value {
+        let posts: Vec<Value> = (0..1000)
+            .map(|id| json!({"id": id, "title": format!("post {}", id), "tags": ["a", "b"]}))
+            .collect();
+        json!({ "posts": posts })
+    }
+
+    /// 多个线程在固定时长内重复读取集合中的元素，返回每秒读取次数
+    fn throughput(workers: usize, read: Arc<dyn Fn(usize) -> Value + Send + Sync>) -> u64 {
+        let duration = Duration::from_millis(500);
+        let handles: Vec<_> = (0..workers)
+            .map(|worker| {
+                let read = read.clone();
+                thread::spawn(move || {
+                    let start = Instant::now();
+                    let mut count = 0u64;
+                    while start.elapsed() < duration {
+                        read((worker * 31 + count as usize) % 1000);
+                        count += 1;
+                    }
+                    count
+                })
+            })
+            .collect();
+        let total: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        total * 1000 / duration.as_millis() as u64
+    }
+
+    /// cargo test -p mockrs --release bench_concurrent_get -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_concurrent_get() {
+        let mutex = Arc::new(Mutex::new(data()));
+        let store = Arc::new(Store::new(data()));
+        println!(
+            "{:>8} {:>14} {:>14}",
+            "workers", "Mutex get/s", "Store get/s"
+        );
+        for &workers in [1, 2, 4, 8, 16].iter() {
+            let m = mutex.clone();
+            let locked = throughput(
+                workers,
+                Arc::new(move |i| m.lock().unwrap()["posts"][i].clone()),
+            );
+            let s = store.clone();
+            let shared = throughput(workers, Arc::new(move |i| s.read()["posts"][i].clone()));
+            println!("{:>8} {:>14} {:>14}", workers, locked, shared);
+        }
+    }
+}
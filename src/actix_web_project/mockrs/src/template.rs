@@ -0,0 +1,447 @@
+//! 模板生成模块
+//! 在jen的基础上为 gen 子命令提供可重复的假数据：
+//!     --seed      jen的helper使用全局随机数，这里以同名helper覆盖，统一使用可指定种子的随机数
+//!     --count     渲染多次，输出数组或每行一个文档的NDJSON
+//!     id ref      集合间的引用，id(collection="posts") 生成该集合递增的id，
+//!                 ref(collection="posts") 从已生成的id中随机取一个，模板按从上到下的顺序渲染
+//! ```text
+//! {
+//!   "posts": [{% for i in range(end=3) %}{"id": {{ id(collection="posts") }}, "title": "{{ sentence() }}"}{% if not loop.last %},{% endif %}{% endfor %}],
+//!   "comments": [{% for i in range(end=5) %}{"postId": {{ ref(collection="posts") }}, "author": "{{ name() }}"}{% if not loop.last %},{% endif %}{% endfor %}]
+//! }
+//! ```
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jen::generator::Generator;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use tera::GlobalFn;
+
+/// 指定种子时timestamp的上限固定为该时间(2020-01-01)，保证输出不随运行时间变化
+const SEEDED_NOW: u64 = 1_577_836_800;
+
+const FIRST_NAMES: &[&str] = &[
+    "James", "Mary", "John", "Linda", "Robert", "Susan", "Michael", "Karen", "Wei", "Yuki", "Omar",
+    "Sofia", "Lucas", "Emma", "Ivan", "Priya",
+];
+const LAST_NAMES: &[&str] = &[
+    "Smith", "Johnson", "Brown", "Garcia", "Miller", "Davis", "Wang", "Tanaka", "Haddad", "Rossi",
+    "Silva", "Müller", "Petrov", "Patel",
+];
+const TITLES: &[&str] = &["Mr.", "Mrs.", "Ms.", "Miss", "Dr."];
+const WORDS: &[&str] = &[
+    "alias",
+    "consequatur",
+    "aut",
+    "perferendis",
+    "sit",
+    "voluptatem",
+    "accusantium",
+    "doloremque",
+    "aperiam",
+    "eaque",
+    "ipsa",
+    "quae",
+    "ab",
+    "illo",
+    "inventore",
+    "veritatis",
+    "et",
+    "quasi",
+    "architecto",
+    "beatae",
+    "vitae",
+    "dicta",
+    "sunt",
+    "explicabo",
+    "nemo",
+    "enim",
+    "ipsam",
+];
+const CITIES: &[&str] = &[
+    "Springfield",
+    "Riverside",
+    "Franklin",
+    "Greenville",
+    "Bristol",
+    "Clinton",
+    "Fairview",
+    "Salem",
+    "Madison",
+    "Georgetown",
+];
+const STATES: &[(&str, &str)] = &[
+    ("California", "CA"),
+    ("Texas", "TX"),
+    ("New York", "NY"),
+    ("Florida", "FL"),
+    ("Illinois", "IL"),
+    ("Ohio", "OH"),
+    ("Washington", "WA"),
+    ("Oregon", "OR"),
+];
+const STREET_SUFFIXES: &[&str] = &["Street", "Avenue", "Road", "Lane", "Boulevard", "Way"];
+const COMPANY_SUFFIXES: &[&str] = &["Inc", "LLC", "Group", "and Sons", "Ltd"];
+const INDUSTRIES: &[&str] = &[
+    "Banking",
+    "Computer Software",
+    "Education",
+    "Hospitality",
+    "Logistics",
+    "Retail",
+    "Telecommunications",
+];
+const PROFESSIONS: &[&str] = &[
+    "engineer",
+    "teacher",
+    "designer",
+    "accountant",
+    "nurse",
+    "lawyer",
+    "chef",
+    "architect",
+];
+const DOMAIN_SUFFIXES: &[&str] = &["com", "net", "org", "io", "info"];
+const USER_AGENTS: &[&str] = &[
+    "Mozilla/5.0 (X11; Linux x86_64; rv:72.0) Gecko/20100101 Firefox/72.0",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/79.0.3945.130 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_2) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/13.0.4 Safari/605.1.15",
+];
+
+/// 所有helper共享的状态
+struct State {
+    seed: Option<u64>,
+    rng: StdRng,
+    index: u64,
+    now: u64,
+    // 各集合已生成的id
+    ids: HashMap<String, Vec<Value>>,
+}
+
+impl State {
+    fn new(seed: Option<u64>) -> State {
+        let (rng, now) = match seed {
+            Some(seed) => (StdRng::seed_from_u64(seed), SEEDED_NOW),
+            None => (
+                StdRng::from_entropy(),
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+            ),
+        };
+        State {
+            seed,
+            rng,
+            index: 0,
+            now,
+            ids: HashMap::new(),
+        }
+    }
+
+    fn pick(&mut self, values: &[&str]) -> String {
+        values.choose(&mut self.rng).unwrap().to_string()
+    }
+
+    fn words(&mut self, count: usize) -> Vec<String> {
+        (0..count).map(|_| self.pick(WORDS)).collect()
+    }
+
+    fn sentence(&mut self) -> String {
+        let count = self.rng.gen_range(4, 10);
+        let mut sentence = self.words(count).join(" ");
+        sentence[..1].make_ascii_uppercase();
+        sentence + "."
+    }
+
+    fn hex(&mut self, len: usize) -> String {
+        (0..len)
+            .map(|_| std::char::from_digit(self.rng.gen_range(0, 16), 16).unwrap())
+            .collect()
+    }
+}
+
+type Args = HashMap<String, Value>;
+
+fn collection(args: &Args) -> tera::Result<String> {
+    args.get("collection")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| "collection argument is required".into())
+}
+
+/// 以name注册一个使用共享状态的helper
+fn helper<F>(state: &Arc<Mutex<State>>, name: &'static str, f: F) -> (&'static str, GlobalFn)
+where
+    F: Fn(&mut State, &Args) -> tera::Result<Value> + Send + Sync + 'static,
+{
+    let state = state.clone();
+    (
+        name,
+        Box::new(move |args: Args| f(&mut state.lock().unwrap(), &args)),
+    )
+}
+
+/// 与jen同名的helper，以及id ref
+fn helpers(state: &Arc<Mutex<State>>) -> Vec<(&'static str, GlobalFn)> {
+    let pick = |name: &'static str, values: &'static [&'static str]| {
+        helper(state, name, move |s, _| Ok(Value::from(s.pick(values))))
+    };
+    vec![
+        pick("city", CITIES),
+        pick("firstName", FIRST_NAMES),
+        pick("lastName", LAST_NAMES),
+        pick("title", TITLES),
+        pick("word", WORDS),
+        pick("industry", INDUSTRIES),
+        pick("profession", PROFESSIONS),
+        pick("domain", DOMAIN_SUFFIXES),
+        pick("userAgent", USER_AGENTS),
+        helper(state, "bool", |s, _| Ok(Value::from(s.rng.gen::<bool>()))),
+        helper(state, "name", |s, _| {
+            Ok(Value::from(format!(
+                "{} {}",
+                s.pick(FIRST_NAMES),
+                s.pick(LAST_NAMES)
+            )))
+        }),
+        helper(state, "username", |s, _| {
+            let name = s.pick(FIRST_NAMES).to_lowercase();
+            Ok(Value::from(format!("{}{}", name, s.rng.gen_range(1, 1000))))
+        }),
+        helper(state, "email", |s, _| {
+            let name = s.pick(FIRST_NAMES).to_lowercase();
+            let host = s.pick(WORDS);
+            let suffix = s.pick(DOMAIN_SUFFIXES);
+            Ok(Value::from(format!("{}@{}.{}", name, host, suffix)))
+        }),
+        helper(state, "company", |s, _| {
+            Ok(Value::from(format!(
+                "{} {}",
+                s.pick(LAST_NAMES),
+                s.pick(COMPANY_SUFFIXES)
+            )))
+        }),
+        helper(state, "phone", |s, _| {
+            let (a, b, c) = (
+                s.rng.gen_range(200, 1000),
+                s.rng.gen_range(100, 1000),
+                s.rng.gen_range(0, 10000),
+            );
+            Ok(Value::from(format!("({}) {}-{:04}", a, b, c)))
+        }),
+        helper(state, "street", |s, _| {
+            let number = s.rng.gen_range(1, 10000);
+            let name = s.pick(LAST_NAMES);
+            Ok(Value::from(format!(
+                "{} {} {}",
+                number,
+                name,
+                s.pick(STREET_SUFFIXES)
+            )))
+        }),
+        helper(state, "state", |s, _| {
+            Ok(Value::from(STATES.choose(&mut s.rng).unwrap().0))
+        }),
+        helper(state, "stateCode", |s, _| {
+            Ok(Value::from(STATES.choose(&mut s.rng).unwrap().1))
+        }),
+        helper(state, "zip", |s, _| {
+            Ok(Value::from(format!("{:05}", s.rng.gen_range(0, 100_000))))
+        }),
+        helper(state, "postcode", |s, _| {
+            Ok(Value::from(format!("{:05}", s.rng.gen_range(0, 100_000))))
+        }),
+        helper(state, "latitude", |s, _| {
+            Ok(Value::from(s.rng.gen_range(-90.0, 90.0)))
+        }),
+        helper(state, "longitude", |s, _| {
+            Ok(Value::from(s.rng.gen_range(-180.0, 180.0)))
+        }),
+        helper(state, "sentence", |s, _| Ok(Value::from(s.sentence()))),
+        helper(state, "paragraph", |s, _| {
+            let sentences: Vec<String> = (0..7).map(|_| s.sentence()).collect();
+            Ok(Value::from(sentences.join(" ")))
+        }),
+        helper(state, "integer", |s, args| {
+            let start = args.get("start").and_then(Value::as_i64).unwrap_or(0);
+            let end = args.get("end").and_then(Value::as_i64).unwrap_or(i64::MAX);
+            if start >= end {
+                return Ok(Value::from(start));
+            }
+            Ok(Value::from(s.rng.gen_range(start, end)))
+        }),
+        helper(state, "float", |s, args| {
+            let start = args.get("start").and_then(Value::as_f64).unwrap_or(0.0);
+            let end = args.get("end").and_then(Value::as_f64).unwrap_or(1.0);
+            if start >= end {
+                return Ok(Value::from(start));
+            }
+            Ok(Value::from(s.rng.gen_range(start, end)))
+        }),
+        helper(state, "timestamp", |s, _| {
+            let now = s.now;
+            Ok(Value::from(s.rng.gen_range(0, now)))
+        }),
+        helper(state, "index", |s, _| {
+            let index = s.index;
+            s.index += 1;
+            Ok(Value::from(index))
+        }),
+        helper(state, "random", |s, args| {
+            match args.get("values").and_then(Value::as_array) {
+                Some(values) if !values.is_empty() => {
+                    Ok(values.choose(&mut s.rng).unwrap().clone())
+                }
+                _ => Err("random requires a non-empty values argument".into()),
+            }
+        }),
+        helper(state, "uuid", |s, _| {
+            // 随机数生成的v4 uuid
+            let hex = s.hex(32);
+            let variant = ["8", "9", "a", "b"].choose(&mut s.rng).unwrap();
+            Ok(Value::from(format!(
+                "{}-{}-4{}-{}{}-{}",
+                &hex[..8],
+                &hex[8..12],
+                &hex[13..16],
+                variant,
+                &hex[17..20],
+                &hex[20..]
+            )))
+        }),
+        helper(state, "objectId", |s, _| Ok(Value::from(s.hex(24)))),
+        helper(state, "id", |s, args| {
+            let ids = s.ids.entry(collection(args)?).or_default();
+            let id = Value::from(ids.len() + 1);
+            ids.push(id.clone());
+            Ok(id)
+        }),
+        helper(state, "ref", |s, args| {
+            let collection = collection(args)?;
+            let ids = s
+                .ids
+                .get(&collection)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            match ids.choose(&mut s.rng) {
+                Some(id) => Ok(id.clone()),
+                None => Err(format!(
+                    "no ids generated for collection {} yet, call id(collection=\"{}\") before ref",
+                    collection, collection
+                )
+                .into()),
+            }
+        }),
+    ]
+}
+
+/// 加载了模板的生成器
+pub struct Template {
+    generator: Generator,
+}
+
+impl Template {
+    pub fn new(template: &str, seed: Option<u64>) -> std::io::Result<Template> {
+        let state = Arc::new(Mutex::new(State::new(seed)));
+        let mut all = jen::helper::builtin();
+        all.extend(helpers(&state));
+        let generator = Generator::new_with_helpers(template, all).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid template {}: {}", template, e),
+            )
+        })?;
+        // jen创建时会试渲染一次，重新开始以保证相同种子的输出相同
+        let seed = state.lock().unwrap().seed;
+        *state.lock().unwrap() = State::new(seed);
+        Ok(Template { generator })
+    }
+
+    /// 原样输出渲染结果
+    pub fn render(&mut self) -> String {
+        self.generator.create()
+    }
+
+    /// 渲染count个文档，ndjson时每行一个文档，否则为json数组
+    pub fn render_many(&mut self, count: usize, ndjson: bool) -> std::io::Result<String> {
+        let mut docs = Vec::with_capacity(count);
+        for _ in 0..count {
+            let content = self.generator.create();
+            let doc: Value = serde_json::from_str(&content).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("template output is not json: {}", e),
+                )
+            })?;
+            docs.push(doc);
+        }
+        if ndjson {
+            let lines: Vec<String> = docs.iter().map(|doc| doc.to_string()).collect();
+            Ok(lines.join("\n"))
+        } else {
+            Ok(serde_json::to_string_pretty(&docs).unwrap())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const TEMPLATE: &str = r#"{
+  "posts": [{% for i in range(end=3) %}{"id": {{ id(collection="posts") }}, "title": "{{ sentence() }}", "uuid": "{{ uuid() }}"}{% if not loop.last %},{% endif %}{% endfor %}],
+  "comments": [{% for i in range(end=5) %}{"postId": {{ ref(collection="posts") }}, "author": "{{ name() }}", "at": {{ timestamp() }}}{% if not loop.last %},{% endif %}{% endfor %}]
+}"#;
+
+    fn template(name: &str, content: &str) -> String {
+        let file =
+            std::env::temp_dir().join(format!("mockrs-{}-{}.tera", name, std::process::id()));
+        fs::write(&file, content).unwrap();
+        file.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_seeded_references() {
+        let file = template("db", TEMPLATE);
+        let render = |seed| Template::new(&file, seed).unwrap().render();
+        assert_eq!(render(Some(7)), render(Some(7)));
+        assert_ne!(render(Some(7)), render(Some(8)));
+
+        let mut gen = Template::new(&file, Some(7)).unwrap();
+        let db: Value = serde_json::from_str(&gen.render()).unwrap();
+        let ids: Vec<Value> = db["posts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|post| post["id"].clone())
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        for comment in db["comments"].as_array().unwrap() {
+            assert!(ids.contains(&comment["postId"]));
+        }
+
+        let ndjson = gen.render_many(2, true).unwrap();
+        let docs: Vec<Value> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // 多次渲染之间id继续递增
+        assert_eq!(docs[1]["posts"][0]["id"], 7);
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_missing_reference() {
+        let file = template("ref", r#"{"postId": {{ ref(collection="posts") }}}"#);
+        assert!(Template::new(&file, Some(1)).is_err());
+        fs::remove_file(&file).unwrap();
+    }
+}
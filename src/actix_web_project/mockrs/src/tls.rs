@@ -0,0 +1,168 @@
+//! TLS模块
+//! 指定 --tls-cert/--tls-key 或 --self-signed 后以HTTPS提供服务，ALPN协商HTTP/2与HTTP/1.1。
+//! --self-signed 启动时为 localhost 与 --host 生成一次性的自签名证书，客户端需信任该证书或跳过校验。
+//! 指定 --tls-port 时HTTPS监听该端口，--port 继续提供HTTP；否则HTTPS替代 --port 上的HTTP。
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use structopt::StructOpt;
+
+/// HTTPS配置
+#[derive(StructOpt, Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain, serve HTTPS with it
+    #[structopt(
+        long,
+        env = "MOCKRS_TLS_CERT",
+        requires = "tls-key",
+        conflicts_with = "self-signed"
+    )]
+    pub tls_cert: Option<String>,
+
+    /// PEM file of the private key (PKCS#8 or RSA) of --tls-cert
+    #[structopt(long, env = "MOCKRS_TLS_KEY", requires = "tls-cert")]
+    pub tls_key: Option<String>,
+
+    /// Serve HTTPS with a throwaway certificate for localhost and --host generated at startup
+    #[structopt(long)]
+    pub self_signed: bool,
+
+    /// Serve HTTPS on this port and keep plain HTTP on --port, otherwise HTTPS replaces HTTP
+    #[structopt(long, env = "MOCKRS_TLS_PORT")]
+    pub tls_port: Option<usize>,
+}
+
+impl TlsConfig {
+    /// 生成rustls的服务端配置，未开启HTTPS时为None
+    pub fn server_config(&self, host: &str) -> std::io::Result<Option<ServerConfig>> {
+        let (chain, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (load_certs(cert)?, load_key(key)?),
+            _ if self.self_signed => self_signed(host)?,
+            _ if self.tls_port.is_some() => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "--tls-port requires --tls-cert or --self-signed",
+                ))
+            }
+            _ => return Ok(None),
+        };
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(chain, key).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid certificate: {}", e),
+            )
+        })?;
+        Ok(Some(config))
+    }
+}
+
+fn open(file: &str) -> std::io::Result<BufReader<File>> {
+    File::open(file)
+        .map(BufReader::new)
+        .map_err(|e| Error::new(e.kind(), format!("unable to read {}: {}", file, e)))
+}
+
+/// 读取PEM格式的证书链
+fn load_certs(file: &str) -> std::io::Result<Vec<Certificate>> {
+    match certs(&mut open(file)?) {
+        Ok(chain) if !chain.is_empty() => Ok(chain),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificate found in {}", file),
+        )),
+    }
+}
+
+/// 读取PEM格式的私钥，依次尝试PKCS#8与RSA格式
+fn load_key(file: &str) -> std::io::Result<PrivateKey> {
+    let mut keys = pkcs8_private_keys(&mut open(file)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(file)?).unwrap_or_default();
+    }
+    keys.into_iter().next().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("no private key found in {}", file),
+        )
+    })
+}
+
+/// 为 localhost 及监听地址生成自签名证书
+fn self_signed(host: &str) -> std::io::Result<(Vec<Certificate>, PrivateKey)> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    if !names.iter().any(|name| name == host) {
+        names.push(host.to_string());
+    }
+    let cert = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| Error::other(e.to_string()))?;
+    let der = cert
+        .serialize_der()
+        .map_err(|e| Error::other(e.to_string()))?;
+    Ok((
+        vec![Certificate(der)],
+        PrivateKey(cert.serialize_private_key_der()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_server_config() {
+        assert!(TlsConfig::default()
+            .server_config("127.0.0.1")
+            .unwrap()
+            .is_none());
+        let config = TlsConfig {
+            tls_port: Some(9443),
+            ..TlsConfig::default()
+        };
+        assert!(config.server_config("127.0.0.1").is_err());
+        let config = TlsConfig {
+            self_signed: true,
+            ..TlsConfig::default()
+        };
+        assert!(config.server_config("0.0.0.0").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_load_pem() {
+        let dir = std::env::temp_dir();
+        let cert_file = dir.join(format!("mockrs-tls-{}.crt", std::process::id()));
+        let key_file = dir.join(format!("mockrs-tls-{}.key", std::process::id()));
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        fs::write(&cert_file, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_file, cert.serialize_private_key_pem()).unwrap();
+        let config = TlsConfig {
+            tls_cert: Some(cert_file.to_string_lossy().to_string()),
+            tls_key: Some(key_file.to_string_lossy().to_string()),
+            ..TlsConfig::default()
+        };
+        assert!(config.server_config("127.0.0.1").unwrap().is_some());
+
+        // 证书与私钥文件弄反时给出文件名
+        let swapped = TlsConfig {
+            tls_cert: config.tls_key.clone(),
+            tls_key: config.tls_cert.clone(),
+            ..TlsConfig::default()
+        };
+        let e = swapped.server_config("127.0.0.1").err().unwrap();
+        assert!(e.to_string().contains("no certificate found"));
+        fs::remove_file(&cert_file).unwrap();
+        fs::remove_file(&key_file).unwrap();
+
+        let missing = TlsConfig {
+            tls_cert: Some("no-such-cert.pem".to_string()),
+            tls_key: Some("no-such-key.pem".to_string()),
+            ..TlsConfig::default()
+        };
+        let e = missing.server_config("127.0.0.1").err().unwrap();
+        assert!(e.to_string().contains("no-such-cert.pem"));
+    }
+}
@@ -0,0 +1,205 @@
+//! 数据变更推送模块
+//! /_actions/watch 以WebSocket(请求带 Upgrade: websocket 时)或Server-Sent Events推送每一次数据修改，
+//! 事件内容为 {"op": "replace", "path": "/users/0", "old": {...}, "new": {...}}。
+//! 可以用 ?path=/users&path=/posts 只订阅这些路径，路径本身、其子路径及其上级路径的修改都会推送。
+use std::sync::Mutex;
+
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error;
+
+/// 一次数据变更，path为json pointer，插入时old为null，删除时new为null
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub op: &'static str,
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+struct Subscriber {
+    prefixes: Vec<String>,
+    sender: UnboundedSender<Event>,
+}
+
+impl Subscriber {
+    fn wants(&self, path: &str) -> bool {
+        self.prefixes.is_empty()
+            || self.prefixes.iter().any(|prefix| {
+                path == prefix
+                    || path.starts_with(&format!("{}/", prefix))
+                    || prefix.starts_with(&format!("{}/", path))
+            })
+    }
+}
+
+/// 订阅者列表，断开的订阅者在下一次推送时移除
+#[derive(Default)]
+pub struct Hub {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl Hub {
+    pub fn subscribe(&self, prefixes: Vec<String>) -> UnboundedReceiver<Event> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber { prefixes, sender });
+        receiver
+    }
+
+    /// 没有订阅者时写入方无需准备事件内容
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.lock().unwrap().is_empty()
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            if subscriber.sender.is_closed() {
+                return false;
+            }
+            if subscriber.wants(&event.path) {
+                return subscriber.sender.unbounded_send(event.clone()).is_ok();
+            }
+            true
+        });
+    }
+}
+
+/// 解析 ?path= 参数，可重复出现或以逗号分隔
+pub fn parse_prefixes(query_string: &str) -> Result<Vec<String>, error::Error> {
+    let params = web::Query::<Vec<(String, String)>>::from_query(query_string)
+        .map_err(|e| error::Error::InvalidQuery {
+            detail: format!("invalid query string: {}", e),
+        })?
+        .into_inner();
+    Ok(params
+        .into_iter()
+        .filter(|(key, _)| key == "path")
+        .flat_map(|(_, value)| {
+            value
+                .split(',')
+                .map(|path| format!("/{}", path.trim().trim_matches('/')))
+                .collect::<Vec<String>>()
+        })
+        .map(|path| if path == "/" { String::new() } else { path })
+        .collect())
+}
+
+/// 以Server-Sent Events推送，先发送一条注释使客户端立即收到响应头
+pub fn sse(receiver: UnboundedReceiver<Event>) -> HttpResponse {
+    let events = receiver.map(|event| {
+        Ok::<_, Error>(web::Bytes::from(format!(
+            "event: {}\ndata: {}\n\n",
+            event.op,
+            serde_json::to_string(&event).unwrap()
+        )))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(stream::once(async { Ok(web::Bytes::from(": watching\n\n")) }).chain(events))
+}
+
+/// WebSocket会话，每个事件以一条文本消息发送
+pub struct WatchSession {
+    receiver: Option<UnboundedReceiver<Event>>,
+}
+
+impl WatchSession {
+    pub fn new(receiver: UnboundedReceiver<Event>) -> WatchSession {
+        WatchSession {
+            receiver: Some(receiver),
+        }
+    }
+}
+
+impl Actor for WatchSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(receiver) = self.receiver.take() {
+            ctx.add_stream(receiver);
+        }
+    }
+}
+
+impl StreamHandler<Event> for WatchSession {
+    fn handle(&mut self, event: Event, ctx: &mut Self::Context) {
+        ctx.text(serde_json::to_string(&event).unwrap());
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WatchSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            // 只推送，不处理客户端发来的其它消息
+            _ => {}
+        }
+    }
+}
+
+/// 请求带 Upgrade: websocket 时建立WebSocket，否则使用SSE
+pub fn is_websocket(req: &HttpRequest) -> bool {
+    req.headers()
+        .get("upgrade")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(path: &str) -> Event {
+        Event {
+            op: "delete",
+            path: path.to_string(),
+            old: Some(json!(1)),
+            new: None,
+        }
+    }
+
+    #[test]
+    fn test_hub() {
+        assert_eq!(
+            parse_prefixes("path=/users/&path=posts,/&x=1").unwrap(),
+            vec!["/users", "/posts", ""]
+        );
+        let hub = Hub::default();
+        let mut users = hub.subscribe(vec!["/users".to_string()]);
+        let mut all = hub.subscribe(vec![]);
+        let dropped = hub.subscribe(vec![]);
+        drop(dropped);
+
+        for path in ["/users/1", "/users2", "", "/posts/0"].iter() {
+            hub.publish(event(path));
+        }
+        assert_eq!(hub.subscribers.lock().unwrap().len(), 2);
+        let paths = |receiver: &mut UnboundedReceiver<Event>| {
+            let mut paths = vec![];
+            while let Some(Some(event)) = receiver.next().now_or_never() {
+                paths.push(event.path);
+            }
+            paths
+        };
+        assert_eq!(paths(&mut users), vec!["/users/1", ""]);
+        assert_eq!(paths(&mut all), vec!["/users/1", "/users2", "", "/posts/0"]);
+    }
+}